@@ -0,0 +1,201 @@
+//! User-controlled list of trusted sites, on which nothing is blocked regardless of the filter
+//! lists in use. It is held by the `Engine` and can be edited at any time without rebuilding the
+//! filters.
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+
+#[derive(Debug, PartialEq)]
+pub enum AllowlistError {
+    EmptyEntry,
+    /// The entry is not a hostname, possibly with `*` wildcards. URLs, paths and ports are not
+    /// supported.
+    InvalidEntry,
+}
+
+/// A set of trusted sites. Each entry is either:
+/// - a hostname, e.g. `example.com`, which also trusts all of its subdomains
+/// - a hostname pattern with `*` wildcards, e.g. `*.example.com` or `example.*`, which trusts any
+///   hostname that it matches entirely
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Allowlist {
+    hostnames: HashSet<String>,
+    patterns: Vec<String>,
+}
+
+impl Allowlist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an entry to the allowlist. Returns `false` if it was already present.
+    pub fn add(&mut self, entry: &str) -> Result<bool, AllowlistError> {
+        let entry = normalize_entry(entry)?;
+        if entry.contains('*') {
+            if self.patterns.contains(&entry) {
+                return Ok(false);
+            }
+            self.patterns.push(entry);
+            Ok(true)
+        } else {
+            Ok(self.hostnames.insert(entry))
+        }
+    }
+
+    /// Removes an entry from the allowlist. Returns `false` if it was not present.
+    pub fn remove(&mut self, entry: &str) -> bool {
+        let entry = match normalize_entry(entry) {
+            Ok(entry) => entry,
+            Err(_) => return false,
+        };
+        if entry.contains('*') {
+            let count = self.patterns.len();
+            self.patterns.retain(|pattern| *pattern != entry);
+            self.patterns.len() != count
+        } else {
+            self.hostnames.remove(&entry)
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.hostnames.clear();
+        self.patterns.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hostnames.is_empty() && self.patterns.is_empty()
+    }
+
+    /// All entries of the allowlist, in normalized form.
+    pub fn entries(&self) -> impl Iterator<Item = &str> {
+        self.hostnames.iter().chain(self.patterns.iter()).map(|entry| entry.as_str())
+    }
+
+    /// Returns the entry that trusts the given hostname, if any.
+    pub fn matching_entry(&self, hostname: &str) -> Option<&str> {
+        if self.is_empty() || hostname.is_empty() {
+            return None;
+        }
+        let hostname = hostname.to_ascii_lowercase();
+
+        let parent_domains = std::iter::once(0)
+            .chain(hostname.match_indices('.').map(|(i, _)| i + 1))
+            .map(|start| &hostname[start..]);
+        for domain in parent_domains {
+            if let Some(entry) = self.hostnames.get(domain) {
+                return Some(entry);
+            }
+        }
+
+        self.patterns.iter()
+            .find(|pattern| wildcard_match(pattern.as_bytes(), hostname.as_bytes()))
+            .map(|pattern| pattern.as_str())
+    }
+
+    pub fn is_trusted(&self, hostname: &str) -> bool {
+        self.matching_entry(hostname).is_some()
+    }
+}
+
+fn normalize_entry(entry: &str) -> Result<String, AllowlistError> {
+    let entry = entry.trim().trim_end_matches('.').to_lowercase();
+    if entry.is_empty() {
+        return Err(AllowlistError::EmptyEntry);
+    }
+    if entry.chars().any(|c| c.is_whitespace() || "/:?#@[]\\".contains(c)) {
+        return Err(AllowlistError::InvalidEntry);
+    }
+    if entry.is_ascii() {
+        Ok(entry)
+    } else {
+        idna::domain_to_ascii(&entry).map_err(|_| AllowlistError::InvalidEntry)
+    }
+}
+
+/// Whether `text` matches `pattern` entirely, where `*` in the pattern matches any sequence of
+/// characters.
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern, and of the text when it was reached
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, star_t)) = backtrack {
+            // Let the last `*` consume one more character
+            p = star + 1;
+            t = star_t + 1;
+            backtrack = Some((star, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostnames() {
+        let mut allowlist = Allowlist::new();
+        assert_eq!(allowlist.add("Example.com"), Ok(true));
+        assert_eq!(allowlist.add("example.com."), Ok(false));
+
+        assert_eq!(allowlist.matching_entry("example.com"), Some("example.com"));
+        assert_eq!(allowlist.matching_entry("sub.example.com"), Some("example.com"));
+        assert_eq!(allowlist.matching_entry("notexample.com"), None);
+        assert_eq!(allowlist.matching_entry("example.com.evil.net"), None);
+
+        assert!(allowlist.remove("EXAMPLE.COM"));
+        assert!(!allowlist.remove("example.com"));
+        assert!(allowlist.is_empty());
+    }
+
+    #[test]
+    fn patterns() {
+        let mut allowlist = Allowlist::new();
+        allowlist.add("*.example.com").unwrap();
+        allowlist.add("shop.*").unwrap();
+        allowlist.add("cdn*.test.net").unwrap();
+
+        assert!(allowlist.is_trusted("www.example.com"));
+        assert!(!allowlist.is_trusted("example.com"));
+        assert!(allowlist.is_trusted("shop.example.co.uk"));
+        assert!(!allowlist.is_trusted("myshop.org"));
+        assert!(allowlist.is_trusted("cdn.test.net"));
+        assert!(allowlist.is_trusted("cdn12.test.net"));
+        assert!(!allowlist.is_trusted("cdn.test.net.org"));
+
+        assert_eq!(allowlist.entries().count(), 3);
+        assert!(allowlist.remove("shop.*"));
+        assert!(!allowlist.is_trusted("shop.example.co.uk"));
+    }
+
+    #[test]
+    fn invalid_entries() {
+        let mut allowlist = Allowlist::new();
+        assert_eq!(allowlist.add(" "), Err(AllowlistError::EmptyEntry));
+        assert_eq!(allowlist.add("https://example.com"), Err(AllowlistError::InvalidEntry));
+        assert_eq!(allowlist.add("example.com/path"), Err(AllowlistError::InvalidEntry));
+        assert_eq!(allowlist.add("example.com:8080"), Err(AllowlistError::InvalidEntry));
+        assert_eq!(allowlist.add("ex ample.com"), Err(AllowlistError::InvalidEntry));
+        assert_eq!(allowlist.add("пример.рф"), Ok(true));
+        assert!(allowlist.is_trusted("xn--e1afmkfd.xn--p1ai"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match(b"*", b""));
+        assert!(wildcard_match(b"a*b*c", b"aXbYbc"));
+        assert!(wildcard_match(b"**.com", b"a.com"));
+        assert!(!wildcard_match(b"a*b", b"aXbc"));
+        assert!(!wildcard_match(b"abc", b"ab"));
+    }
+}
@@ -9,7 +9,7 @@ use std::iter::FromIterator;
 #[cfg(feature = "object-pooling")]
 use lifeguard::Pool;
 
-use crate::filters::network::{NetworkFilter, NetworkMatchable};
+use crate::filters::network::{CompiledRegex, NetworkFilter, NetworkMatchable};
 use crate::request::Request;
 use crate::utils::{fast_hash, Hash};
 use crate::optimizer;
@@ -31,14 +31,24 @@ pub struct BlockerResult {
     /// Huhi Browser keeps seperate instances of [`Blocker`] for default
     /// lists and regional ones, so `important` here is used to correct
     /// behaviour between them: checking should stop instead of moving to the
-    /// next instance iff an `important` rule matched.
+    /// next instance iff an `important` rule matched. [`crate::engine_group::EngineGroup`]
+    /// implements this for several [`crate::engine::Engine`]s.
     pub important: bool,
     /// Iff the blocker matches a rule which has the `redirect` option, as per
     /// [uBlock Origin's redirect syntax][1], the `redirect` is `Some`. The
     /// `redirect` field contains the body of the redirect to be injected.
+    /// Rules with the `redirect-rule` option only produce a redirect if the
+    /// request is blocked by another rule.
     ///
     /// [1]: https://github.com/gorhill/uBlock/wiki/Static-filter-syntax#redirect
     pub redirect: Option<String>,
+    /// Iff the blocker matches a rule which has the `removeparam` option, as
+    /// per [uBlock Origin's removeparam syntax][1], and the request is not
+    /// blocked, `rewritten_url` contains the request URL with the matching
+    /// query parameters removed. It is `None` if no parameters were removed.
+    ///
+    /// [1]: https://github.com/gorhill/uBlock/wiki/Static-filter-syntax#removeparam
+    pub rewritten_url: Option<String>,
     /// Exception is `Some` when the blocker matched on an exception rule.
     /// Effectively this means that there was a match, but the request should
     /// not be blocked. It is a non-empty string if the blocker was initialized
@@ -61,6 +71,7 @@ impl Default for BlockerResult {
             matched: false,
             important: false,
             redirect: None,
+            rewritten_url: None,
             exception: None,
             filter: None,
             error: None,
@@ -68,6 +79,110 @@ impl Default for BlockerResult {
     }
 }
 
+impl BlockerResult {
+    /// Result for a request whose URLs could not be parsed.
+    pub(crate) fn request_parse_error() -> BlockerResult {
+        BlockerResult {
+            error: Some("Error parsing request".to_owned()),
+            ..BlockerResult::default()
+        }
+    }
+}
+
+/// The lists of network filters held by a [`Blocker`], as reported by [`Blocker::explain`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum FilterListKind {
+    Importants,
+    Redirects,
+    Tagged,
+    Filters,
+    Exceptions,
+    RemoveParam,
+    Csp,
+    GenericHide,
+    Headers,
+    GenericBlock,
+}
+
+/// A network filter that matched a request. Apart from `raw_line`, which is only available if the
+/// blocker was created from a list of rules with debugging enabled, all fields are always set.
+#[derive(Debug, Clone, Serialize)]
+pub struct MatchedFilter {
+    pub raw_line: Option<String>,
+    pub id: Hash,
+    pub hostname: Option<String>,
+    pub pattern: Option<String>,
+    pub exception: bool,
+    pub important: bool,
+    pub redirect: Option<String>,
+    pub tag: Option<String>,
+}
+
+impl From<&NetworkFilter> for MatchedFilter {
+    fn from(filter: &NetworkFilter) -> Self {
+        MatchedFilter {
+            raw_line: filter.raw_line.clone(),
+            id: filter.id,
+            hostname: filter.hostname.clone(),
+            pattern: filter.filter.string_view(),
+            exception: filter.is_exception(),
+            important: filter.is_important(),
+            redirect: filter.redirect.clone(),
+            tag: filter.tag.clone(),
+        }
+    }
+}
+
+/// A single list checked while evaluating a request, along with every filter from it that matched.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExplainStep {
+    pub list: FilterListKind,
+    pub matches: Vec<MatchedFilter>,
+}
+
+impl ExplainStep {
+    fn new(list: FilterListKind, matches: &[&NetworkFilter]) -> Self {
+        ExplainStep {
+            list,
+            matches: matches.iter().map(|f| MatchedFilter::from(*f)).collect(),
+        }
+    }
+}
+
+/// Why a request was or wasn't blocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum BlockerDecision {
+    /// The request could not be checked, e.g. because it uses an unsupported scheme.
+    Unsupported,
+    /// No blocking filter matched the request.
+    NoMatch,
+    /// An `$important` filter matched, so exceptions were not checked.
+    Important,
+    /// A filter matched and no exception applied.
+    Blocked,
+    /// A filter matched, but an exception overrode it.
+    Excepted,
+    /// The request's source is trusted by the allowlist, so no filter was checked.
+    Allowlisted,
+}
+
+/// Trace of how a [`Blocker`] evaluated a request, as returned by [`Blocker::explain`].
+#[derive(Debug, Serialize)]
+pub struct BlockerTrace {
+    /// The lists that were checked, in order of evaluation.
+    pub steps: Vec<ExplainStep>,
+    pub decision: BlockerDecision,
+    /// The filter that caused the request to be blocked, if any.
+    pub filter: Option<MatchedFilter>,
+    /// The exception that overrode `filter`, if any.
+    pub exception: Option<MatchedFilter>,
+    /// The same result that `Blocker::check` returns for the request.
+    pub result: BlockerResult,
+    /// The allowlist entry that trusts the request's source, if any. Only set by
+    /// `Engine::explain`, since a `Blocker` has no allowlist.
+    pub allowlisted_by: Option<String>,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum BlockerError {
     SerializationError,
@@ -75,6 +190,10 @@ pub enum BlockerError {
     OptimizedFilterExistence,
     BadFilterAddUnsupported,
     FilterExists,
+    BadFilterRemoveUnsupported,
+    FilterNotFound,
+    FusedFilterRemovalUnsupported,
+    UnparseableFilter,
 }
 
 #[cfg(feature = "object-pooling")]
@@ -119,6 +238,9 @@ pub struct Blocker {
     pub(crate) pool: TokenPool,
 
     pub(crate) generic_hide: NetworkFilterList,
+    pub(crate) removeparam: NetworkFilterList,
+    pub(crate) headers: NetworkFilterList,
+    pub(crate) generic_block: NetworkFilterList,
 }
 
 impl Blocker {
@@ -128,7 +250,21 @@ impl Blocker {
         self.check_parameterised(request, false, false)
     }
 
+    /// Whether generic cosmetic rules are disabled on the given page, by a `$generichide` or
+    /// `$elemhide` exception.
     pub fn check_generic_hide(&self, hostname_request: &Request) -> bool {
+        self.check_cosmetic_hide(hostname_request).0
+    }
+
+    /// Whether hostname-specific cosmetic rules are disabled on the given page, by a
+    /// `$specifichide` or `$elemhide` exception.
+    pub fn check_specific_hide(&self, hostname_request: &Request) -> bool {
+        self.check_cosmetic_hide(hostname_request).1
+    }
+
+    /// Returns both `check_generic_hide` and `check_specific_hide` for the given page, in that
+    /// order, evaluating the exceptions only once.
+    pub fn check_cosmetic_hide(&self, hostname_request: &Request) -> (bool, bool) {
         let mut request_tokens;
         #[cfg(feature = "object-pooling")]
         {
@@ -140,7 +276,100 @@ impl Blocker {
         }
         hostname_request.get_tokens(&mut request_tokens);
 
-        self.generic_hide.check(hostname_request, &request_tokens, &HashSet::new()).is_some()
+        // `$generichide`, `$elemhide` and `$specifichide` exceptions share a list, so the first
+        // match is not enough. Exceptions that also have `$genericblock` are stored with the
+        // other `$genericblock` exceptions.
+        let no_tags = HashSet::new();
+        let exceptions = self.generic_hide.check_all(hostname_request, &request_tokens, &no_tags)
+            .into_iter()
+            .chain(self.generic_block.check_all(hostname_request, &request_tokens, &no_tags));
+
+        let (mut generichide, mut specifichide) = (false, false);
+        for filter in exceptions {
+            generichide |= filter.is_generic_hide() || filter.is_elem_hide();
+            specifichide |= filter.is_specific_hide() || filter.is_elem_hide();
+            if generichide && specifichide {
+                break;
+            }
+        }
+        (generichide, specifichide)
+    }
+
+    /// Returns the `$document` exception matching the given document request, if any. Such an
+    /// exception allowlists the whole page, i.e. every request it makes and all cosmetic filtering
+    /// on it.
+    pub fn check_document_allowlist(&self, document_request: &Request) -> Option<&NetworkFilter> {
+        let mut request_tokens;
+        #[cfg(feature = "object-pooling")]
+        {
+            request_tokens = self.pool.pool.new();
+        }
+        #[cfg(not(feature = "object-pooling"))]
+        {
+            request_tokens = Vec::with_capacity(utils::TOKENS_BUFFER_SIZE);
+        }
+        document_request.get_tokens(&mut request_tokens);
+
+        // Any exception matches document requests, but only those explicitly using `$document`
+        // apply to the rest of the page
+        self.exceptions.check_all(document_request, &request_tokens, &self.tags_enabled)
+            .into_iter()
+            .find(|filter| filter.is_document())
+    }
+
+    /// `$genericblock` exceptions matching the source document of the given request. If there are
+    /// any, generic blocking filters should not be applied to the request.
+    ///
+    /// Only the hostname of the source document is known, so the exceptions are looked up by its
+    /// tokens and matched against `source_hostname_hashes` with `NetworkFilter::matches_document`.
+    pub(crate) fn check_generic_block(&self, request: &Request, record_statistics: bool) -> Vec<&NetworkFilter> {
+        if self.generic_block.filter_map.is_empty() || request.source_hostname_hashes.is_none() {
+            return vec![];
+        }
+
+        let mut source_tokens;
+        #[cfg(feature = "object-pooling")]
+        {
+            source_tokens = self.pool.pool.new();
+        }
+        #[cfg(not(feature = "object-pooling"))]
+        {
+            source_tokens = Vec::with_capacity(utils::TOKENS_BUFFER_SIZE);
+        }
+        source_tokens.clear();
+        utils::tokenize_pooled(&request.source_hostname, &mut source_tokens);
+        // Add zero token as a fallback to wildcard rule bucket
+        source_tokens.push(0);
+
+        self.generic_block.check_all_documents(request, &source_tokens, record_statistics)
+    }
+
+    /// All `$redirect` and `$redirect-rule` filters and exceptions matching the request. Generic
+    /// filters are skipped if `generic_block` is set.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn redirect_filters(&self, request: &Request, request_tokens: &[Hash], generic_block: bool) -> Vec<&NetworkFilter> {
+        // Most requests match no redirect at all, so only collect every match once one is found
+        let first = match self.redirects.check(request, request_tokens, &HashSet::new()) {
+            Some(first) => first,
+            None => return vec![],
+        };
+        // The check and its first match were already recorded
+        let mut redirect_filters = self.redirects.explain_all(request, request_tokens, &HashSet::new());
+        #[cfg(feature = "metrics")]
+        self.redirects.statistics.record_hits(redirect_filters.iter().copied().filter(|f| f.id != first.id));
+        if generic_block {
+            redirect_filters.retain(|f| f.is_exception() || !f.is_generic());
+        }
+        redirect_filters
+    }
+
+    /// Like `NetworkFilterList::check`, but skips generic filters if `generic_block` is set.
+    fn check_blocking_list<'a>(list: &'a NetworkFilterList, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>, generic_block: bool) -> Option<&'a NetworkFilter> {
+        if generic_block {
+            list.check_all(request, request_tokens, active_tags).into_iter().find(|f| !f.is_generic())
+        } else {
+            list.check(request, request_tokens, active_tags)
+        }
     }
 
     pub fn check_parameterised(&self, request: &Request, matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
@@ -148,10 +377,6 @@ impl Blocker {
             return BlockerResult::default();
         }
 
-        // only check for tags in tagged and exception rule buckets,
-        // pass empty set for the rest
-        static NO_TAGS: Lazy<HashSet<String>> = Lazy::new(HashSet::new);
-
         let mut request_tokens;
         #[cfg(feature = "object-pooling")]
         {
@@ -163,31 +388,76 @@ impl Blocker {
         }
         request.get_tokens(&mut request_tokens);
 
+        let generic_block = !self.check_generic_block(request, true).is_empty();
+        let redirect_filters = self.redirect_filters(request, &request_tokens, generic_block);
+        self.check_tokenized(request, &request_tokens, generic_block, &redirect_filters, matched_rule, force_check_exceptions)
+    }
+
+    /// Like `check`, for many requests at once. A single token buffer is reused for all of them,
+    /// and `$genericblock` exceptions are only evaluated again when the source hostname changes
+    /// from one request to the next.
+    pub fn check_batch<'a, I: IntoIterator<Item = &'a Request>>(&self, requests: I) -> Vec<BlockerResult> {
+        let mut request_tokens;
+        #[cfg(feature = "object-pooling")]
+        {
+            request_tokens = self.pool.pool.new();
+        }
+        #[cfg(not(feature = "object-pooling"))]
+        {
+            request_tokens = Vec::with_capacity(utils::TOKENS_BUFFER_SIZE);
+        }
+
+        let mut generic_block: Option<(&str, bool)> = None;
+        requests.into_iter().map(|request| {
+            if !request.is_supported {
+                return BlockerResult::default();
+            }
+            request.get_tokens(&mut request_tokens);
+            let request_generic_block = match generic_block {
+                Some((source_hostname, value)) if source_hostname == request.source_hostname => value,
+                _ => {
+                    let value = !self.check_generic_block(request, true).is_empty();
+                    generic_block = Some((&request.source_hostname, value));
+                    value
+                }
+            };
+            let redirect_filters = self.redirect_filters(request, &request_tokens, request_generic_block);
+            self.check_tokenized(request, &request_tokens, request_generic_block, &redirect_filters, false, false)
+        }).collect()
+    }
+
+    /// Implementation of `check_parameterised`, for a supported request that has already been
+    /// tokenized, knowing whether its source document has a `$genericblock` exception and given
+    /// the `redirect_filters` matching the request.
+    pub(crate) fn check_tokenized(&self, request: &Request, request_tokens: &[Hash], generic_block: bool, redirect_filters: &[&NetworkFilter], matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
+        // only check for tags in tagged and exception rule buckets,
+        // pass empty set for the rest
+        static NO_TAGS: Lazy<HashSet<String>> = Lazy::new(HashSet::new);
+
         // Check the filters in the following order:
         // 1. $important (not subject to exceptions)
-        // 2. redirection ($redirect=resource)
+        // 2. redirection ($redirect=resource and $redirect-rule=resource)
         // 3. normal filters - if no match by then
         // 4. exceptions - if any non-important match of forced
+        //
+        // If the source document has a `$genericblock` exception, filters without `$domain` are
+        // skipped in steps 1 to 3.
 
-        #[cfg(feature = "metrics")]
-        print!("importants\t");
         // Always check important filters
-        let important_filter = self
-            .importants
-            .check(request, &request_tokens, &NO_TAGS);
+        let important_filter = Self::check_blocking_list(&self.importants, request, request_tokens, &NO_TAGS, generic_block);
 
-        let redirect_filter = self.redirects.check(request, &request_tokens, &NO_TAGS);
+        // All redirects are collected, so that exceptions and priorities can be applied later.
+        // `$redirect` filters also block the request, while `$redirect-rule` filters only
+        // redirect requests that are blocked by some other filter
+        let redirect_filter = redirect_filters
+            .iter()
+            .copied()
+            .find(|f| !f.is_exception() && !f.is_redirect_rule());
 
         // only check the rest of the rules if not previously matched
         let filter = if important_filter.is_none() && !matched_rule {
-            #[cfg(feature = "metrics")]
-            print!("tagged\t");
-            self.filters_tagged.check(request, &request_tokens, &self.tags_enabled)
-                .or_else(|| {
-                    #[cfg(feature = "metrics")]
-                    print!("filters\t");
-                    self.filters.check(request, &request_tokens, &NO_TAGS)
-                })
+            Self::check_blocking_list(&self.filters_tagged, request, request_tokens, &self.tags_enabled, generic_block)
+                .or_else(|| Self::check_blocking_list(&self.filters, request, request_tokens, &NO_TAGS, generic_block))
         } else {
             important_filter
         };
@@ -195,66 +465,359 @@ impl Blocker {
         let exception = match filter.as_ref() {
             // if no other rule matches, only check exceptions if forced to
             None if matched_rule || force_check_exceptions || redirect_filter.is_some() => {
-                #[cfg(feature = "metrics")]
-                print!("exceptions\t");
-                self.exceptions.check(request, &request_tokens, &self.tags_enabled)
+                self.exceptions.check(request, request_tokens, &self.tags_enabled)
             }
             None => None,
             // If matched an important filter, exceptions don't atter
             Some(f) if f.is_important() => None,
             Some(f) if f.has_bug() => {
-                #[cfg(feature = "metrics")]
-                print!("exceptions\t");
                 // Set `bug` of request
                 let mut request_bug = request.clone();
                 request_bug.bug = f.bug;
-                self.exceptions.check(&request_bug, &request_tokens, &self.tags_enabled)
+                self.exceptions.check(&request_bug, request_tokens, &self.tags_enabled)
             }
             Some(_) => {
-                #[cfg(feature = "metrics")]
-                print!("exceptions\t");
-                self.exceptions.check(request, &request_tokens, &self.tags_enabled)
+                self.exceptions.check(request, request_tokens, &self.tags_enabled)
             }
         };
 
-        #[cfg(feature = "metrics")]
-        println!();
-
-        // only match redirects if we have them set up
-        let redirect: Option<String> = redirect_filter.as_ref().and_then(|f| {
-            // Filter redirect option is set
-            if let Some(redirect) = f.redirect.as_ref() {
-                // And we have a matching redirect resource
-                if let Some(resource) = self.resources.get_resource(redirect) {
-                    let data_url = format!("data:{};base64,{}", resource.content_type, &resource.data);
-                    Some(data_url.trim().to_owned())
-                } else {
-                    // TODO: handle error - throw?
-                    #[cfg(test)]
-                    eprintln!("Matched rule with redirect option but did not find corresponding resource to send");
-                    None
-                }
-            } else {
-                None
-            }
-        });
+        let blocked = filter.is_some() || redirect_filter.is_some() || matched_rule;
+
+        // only match redirects if the request is blocked
+        let redirect: Option<String> = if blocked {
+            self.get_redirect(redirect_filters)
+        } else {
+            None
+        };
 
         // If something has already matched before but we don't know what, still return a match
-        let matched = exception.is_none() && (filter.is_some() || redirect_filter.is_some() || matched_rule);
+        let matched = exception.is_none() && blocked;
+
+        // Query parameters only need to be removed from requests that aren't blocked
+        let rewritten_url = if matched {
+            None
+        } else {
+            self.get_rewritten_url(request, request_tokens, &self.tags_enabled)
+        };
+
         BlockerResult {
             matched,
             important: filter.is_some() && filter.as_ref().map(|f| f.is_important()).unwrap_or_else(|| false),
             redirect,
+            rewritten_url,
             exception: exception.as_ref().map(|f| f.to_string()), // copy the exception
             filter: filter.as_ref().map(|f| f.to_string()),       // copy the filter
             error: None,
         }
     }
 
-    /// Given a "main_frame" request, check if some content security policies
+    /// Decide if a response should be blocked based on its headers, using filters with the
+    /// `$header` option. These filters are never considered at request time by `check`.
+    ///
+    /// As with `check`, a matching filter can be overridden by a `$header` exception matching the
+    /// same response or by a regular exception matching the request, unless it is `$important`.
+    pub fn check_response_headers(&self, request: &Request, headers: &[(&str, &str)]) -> BlockerResult {
+        if !request.is_supported {
+            return BlockerResult::default();
+        }
+
+        let mut request_tokens;
+        #[cfg(feature = "object-pooling")]
+        {
+            request_tokens = self.pool.pool.new();
+        }
+        #[cfg(not(feature = "object-pooling"))]
+        {
+            request_tokens = Vec::with_capacity(utils::TOKENS_BUFFER_SIZE);
+        }
+        request.get_tokens(&mut request_tokens);
+
+        let filters: Vec<&NetworkFilter> = self.headers.check_all(request, &request_tokens, &self.tags_enabled)
+            .into_iter()
+            .filter(|f| f.matches_headers(headers))
+            .collect();
+
+        let filter = filters.iter().copied().find(|f| !f.is_exception() && f.is_important())
+            .or_else(|| filters.iter().copied().find(|f| !f.is_exception()));
+
+        let exception = match filter {
+            None => None,
+            Some(f) if f.is_important() => None,
+            Some(_) => filters.iter().copied().find(|f| f.is_exception())
+                .or_else(|| self.exceptions.check(request, &request_tokens, &self.tags_enabled)),
+        };
+
+        BlockerResult {
+            matched: filter.is_some() && exception.is_none(),
+            important: filter.map(|f| f.is_important()).unwrap_or(false),
+            exception: exception.map(|f| f.to_string()),
+            filter: filter.map(|f| f.to_string()),
+            ..BlockerResult::default()
+        }
+    }
+
+    /// Evaluates a request like `check`, but also records every list that was visited and every
+    /// filter that matched along the way, as well as the filters that decided the result.
+    ///
+    /// This is much slower than `check`, since all matching filters are collected rather than
+    /// just the first one.
+    pub fn explain(&self, request: &Request) -> BlockerTrace {
+        let result = self.check(request);
+
+        if !request.is_supported {
+            return BlockerTrace {
+                steps: vec![],
+                decision: BlockerDecision::Unsupported,
+                filter: None,
+                exception: None,
+                result,
+                allowlisted_by: None,
+            };
+        }
+
+        static NO_TAGS: Lazy<HashSet<String>> = Lazy::new(HashSet::new);
+
+        let mut request_tokens;
+        #[cfg(feature = "object-pooling")]
+        {
+            request_tokens = self.pool.pool.new();
+        }
+        #[cfg(not(feature = "object-pooling"))]
+        {
+            request_tokens = Vec::with_capacity(utils::TOKENS_BUFFER_SIZE);
+        }
+        request.get_tokens(&mut request_tokens);
+
+        // Follows the same order of evaluation as `check_parameterised`. `check` returns the first
+        // filter found by `check_all`, so the first match of each list is the one that counts,
+        // unless generic filters are skipped because of a `$genericblock` exception.
+        let mut steps = vec![];
+
+        let generic_block_exceptions = self.check_generic_block(request, false);
+        let generic_block = !generic_block_exceptions.is_empty();
+        if !self.generic_block.filter_map.is_empty() {
+            steps.push(ExplainStep::new(FilterListKind::GenericBlock, &generic_block_exceptions));
+        }
+        let applies = |f: &&NetworkFilter| !generic_block || !f.is_generic();
+
+        let importants = self.importants.explain_all(request, &request_tokens, &NO_TAGS);
+        steps.push(ExplainStep::new(FilterListKind::Importants, &importants));
+
+        let redirects = self.redirects.explain_all(request, &request_tokens, &NO_TAGS);
+        steps.push(ExplainStep::new(FilterListKind::Redirects, &redirects));
+        let redirect_filter = redirects
+            .iter()
+            .copied()
+            .filter(applies)
+            .find(|f| !f.is_exception() && !f.is_redirect_rule());
+
+        let filter = match importants.iter().copied().find(applies) {
+            Some(f) => Some(f),
+            None => {
+                let tagged = self.filters_tagged.explain_all(request, &request_tokens, &self.tags_enabled);
+                steps.push(ExplainStep::new(FilterListKind::Tagged, &tagged));
+                match tagged.iter().copied().find(applies) {
+                    Some(f) => Some(f),
+                    None => {
+                        let filters = self.filters.explain_all(request, &request_tokens, &NO_TAGS);
+                        steps.push(ExplainStep::new(FilterListKind::Filters, &filters));
+                        filters.iter().copied().find(applies)
+                    }
+                }
+            }
+        };
+
+        let check_exceptions = match filter {
+            Some(f) => !f.is_important(),
+            None => redirect_filter.is_some(),
+        };
+        let exception = if check_exceptions {
+            let mut request_bug = None;
+            if let Some(f) = filter.filter(|f| f.has_bug()) {
+                let mut request = request.clone();
+                request.bug = f.bug;
+                request_bug = Some(request);
+            }
+            let exceptions = self.exceptions.explain_all(request_bug.as_ref().unwrap_or(request), &request_tokens, &self.tags_enabled);
+            steps.push(ExplainStep::new(FilterListKind::Exceptions, &exceptions));
+            exceptions.first().copied()
+        } else {
+            None
+        };
+
+        if !result.matched && request.url.contains('?') {
+            let removeparam = self.removeparam.explain_all(request, &request_tokens, &self.tags_enabled);
+            steps.push(ExplainStep::new(FilterListKind::RemoveParam, &removeparam));
+        }
+
+        let filter = filter.or(redirect_filter);
+        let decision = match (filter, exception) {
+            (None, _) => BlockerDecision::NoMatch,
+            (Some(f), _) if f.is_important() => BlockerDecision::Important,
+            (Some(_), Some(_)) => BlockerDecision::Excepted,
+            (Some(_), None) => BlockerDecision::Blocked,
+        };
+
+        BlockerTrace {
+            steps,
+            decision,
+            filter: filter.map(MatchedFilter::from),
+            exception: exception.map(MatchedFilter::from),
+            result,
+            allowlisted_by: None,
+        }
+    }
+
+    /// Returns a snapshot of the statistics collected by each list of filters.
+    #[cfg(feature = "metrics")]
+    pub fn statistics(&self) -> HashMap<FilterListKind, crate::statistics::NetworkListStatistics> {
+        self.lists().map(|(kind, list)| (kind, list.statistics.snapshot())).collect()
+    }
+
+    /// Resets the statistics of every list of filters.
+    #[cfg(feature = "metrics")]
+    pub fn reset_statistics(&self) {
+        self.lists().for_each(|(_, list)| list.statistics.reset());
+    }
+
+    #[cfg(feature = "metrics")]
+    fn lists(&self) -> impl Iterator<Item = (FilterListKind, &NetworkFilterList)> {
+        vec![
+            (FilterListKind::Importants, &self.importants),
+            (FilterListKind::Redirects, &self.redirects),
+            (FilterListKind::Tagged, &self.filters_tagged),
+            (FilterListKind::Filters, &self.filters),
+            (FilterListKind::Exceptions, &self.exceptions),
+            (FilterListKind::RemoveParam, &self.removeparam),
+            (FilterListKind::Csp, &self.csp),
+            (FilterListKind::GenericHide, &self.generic_hide),
+            (FilterListKind::Headers, &self.headers),
+            (FilterListKind::GenericBlock, &self.generic_block),
+        ].into_iter()
+    }
+
+    /// Picks the redirect resource to use for a blocked request from the matching `$redirect` and
+    /// `$redirect-rule` filters. `@@$redirect=resource` exceptions disable redirects to that
+    /// resource, and `@@$redirect-rule` exceptions without a resource disable all of them, unless
+    /// they come from an `$important` filter. Of the remaining filters, the one with the highest
+    /// priority and an available resource is used.
+    fn get_redirect(&self, redirect_filters: &[&NetworkFilter]) -> Option<String> {
+        let redirect_filters: Vec<_> = redirect_filters.iter().map(|f| (*f, &self.resources)).collect();
+        Self::resolve_redirect(&redirect_filters)
+    }
+
+    /// Same as `get_redirect`, for filters that can come from different blockers. Each filter is
+    /// given along with the resources of its blocker.
+    pub(crate) fn resolve_redirect(redirect_filters: &[(&NetworkFilter, &RedirectResourceStorage)]) -> Option<String> {
+        let all_excepted = redirect_filters.iter().any(|(f, _)| f.is_exception() && f.excepts_all_redirects());
+        let excepted: HashSet<&str> = redirect_filters
+            .iter()
+            .filter(|(f, _)| f.is_exception())
+            .filter_map(|(f, _)| f.redirect.as_deref())
+            .collect();
+
+        let mut candidates: Vec<(&NetworkFilter, &RedirectResourceStorage)> = redirect_filters
+            .iter()
+            .copied()
+            .filter(|(f, _)| !f.is_exception())
+            .filter(|(f, _)| f.is_important() || (!all_excepted && f.redirect.as_deref().map(|r| !excepted.contains(r)).unwrap_or(false)))
+            .collect();
+
+        // Stable sort, so that filters with equal priority keep their matching order
+        candidates.sort_by_key(|(f, _)| std::cmp::Reverse(f.redirect_priority));
+
+        candidates.into_iter().filter_map(|(f, resources)| f.redirect.as_ref().map(|r| (r, resources))).find_map(|(redirect, resources)| {
+            // And we have a matching redirect resource
+            if let Some(resource) = resources.get_resource(redirect) {
+                let data_url = format!("data:{};base64,{}", resource.content_type, &resource.data);
+                Some(data_url.trim().to_owned())
+            } else {
+                // TODO: handle error - throw?
+                #[cfg(test)]
+                eprintln!("Matched rule with redirect option but did not find corresponding resource to send");
+                None
+            }
+        })
+    }
+
+    /// Given a "main_frame" or "subdocument" request, check if some content security policies
     /// should be injected in the page.
-    pub fn get_csp_directives(&self, _request: Request) -> Option<String> {
-        unimplemented!()
+    ///
+    /// All matching `$csp` directives are merged into a single header value. A generic `@@$csp`
+    /// exception disables every directive for the request, while an exception with a value only
+    /// disables that specific directive. Directives from `$important` filters are not subject to
+    /// exceptions.
+    pub fn get_csp_directives(&self, request: &Request) -> Option<String> {
+        use crate::request::RequestType;
+
+        if request.request_type != RequestType::Document && request.request_type != RequestType::Subdocument {
+            return None;
+        }
+
+        let mut request_tokens;
+        #[cfg(feature = "object-pooling")]
+        {
+            request_tokens = self.pool.pool.new();
+        }
+        #[cfg(not(feature = "object-pooling"))]
+        {
+            request_tokens = Vec::with_capacity(utils::TOKENS_BUFFER_SIZE);
+        }
+        request.get_tokens(&mut request_tokens);
+
+        let filters = self.csp.check_all(request, &request_tokens, &self.tags_enabled);
+
+        let directives: Vec<&str> = resolve_option_values(&filters, |f| f.csp.as_deref())
+            .into_iter()
+            .flatten()
+            .collect();
+
+        if directives.is_empty() {
+            None
+        } else {
+            Some(directives.join(", "))
+        }
+    }
+
+    /// Applies any matching `$removeparam` filters to the request URL. Returns `None` if no query
+    /// parameters were removed.
+    fn get_rewritten_url(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>) -> Option<String> {
+        if !request.url.contains('?') {
+            return None;
+        }
+
+        let filters = self.removeparam.check_all(request, request_tokens, active_tags);
+        Self::rewrite_url(&request.url, &filters)
+    }
+
+    /// Every `$removeparam` filter and exception that matches the request, without recording
+    /// statistics, e.g. to combine them with those of other blockers.
+    pub(crate) fn removeparam_filters(&self, request: &Request, request_tokens: &[Hash]) -> Vec<&NetworkFilter> {
+        if !request.url.contains('?') {
+            return vec![];
+        }
+        self.removeparam.explain_all(request, request_tokens, &self.tags_enabled)
+    }
+
+    /// Removes the query parameters of `url` selected by the given `$removeparam` filters, after
+    /// resolving exceptions between them. Returns `None` if no query parameters were removed.
+    pub(crate) fn rewrite_url(url: &str, filters: &[&NetworkFilter]) -> Option<String> {
+        if filters.is_empty() {
+            return None;
+        }
+
+        let values = resolve_option_values(filters, |f| f.removeparam.as_deref());
+        if values.is_empty() {
+            return None;
+        }
+
+        // Any filter with the same value can provide its compiled regex
+        let values: Vec<Option<&NetworkFilter>> = values
+            .into_iter()
+            .map(|value| value.and_then(|v| filters.iter().copied().find(|f| f.removeparam.as_deref() == Some(v))))
+            .collect();
+
+        remove_query_params(url, &values)
     }
 
     pub fn new(network_filters: Vec<NetworkFilter>, options: &BlockerOptions) -> Blocker {
@@ -274,6 +837,12 @@ impl Blocker {
         let mut badfilters = Vec::with_capacity(100);
         // $generichide
         let mut generic_hide = Vec::with_capacity(4000);
+        // $genericblock
+        let mut generic_block = Vec::with_capacity(100);
+        // $removeparam
+        let mut removeparam = Vec::with_capacity(200);
+        // $header=
+        let mut headers = Vec::with_capacity(50);
         // All other filters
         let mut filters = Vec::with_capacity(network_filters.len());
 
@@ -295,11 +864,22 @@ impl Blocker {
                 }
                 if filter.is_csp() {
                     csp.push(filter);
-                } else if filter.is_generic_hide() {
+                } else if filter.is_removeparam() {
+                    removeparam.push(filter);
+                } else if filter.is_header() {
+                    headers.push(filter);
+                } else if filter.is_generic_block() {
+                    generic_block.push(filter);
+                } else if filter.is_cosmetic_hide_exception() {
                     generic_hide.push(filter);
                 } else if filter.is_exception() {
-                    exceptions.push(filter);
-                } else if filter.is_important() {
+                    // `@@$redirect=resource` exceptions only disable the redirect
+                    if filter.is_redirect() {
+                        redirects.push(filter);
+                    } else {
+                        exceptions.push(filter);
+                    }
+                } else if filter.is_important() && !filter.is_redirect_rule() {
                     // Add `$important,redirect` filters twice for temporary compatibility while
                     // fixing #131
                     if filter.is_redirect() {
@@ -328,6 +908,9 @@ impl Blocker {
             filters_tagged: NetworkFilterList::new(Vec::new(), options.enable_optimizations),
             filters: NetworkFilterList::new(filters, options.enable_optimizations),
             generic_hide: NetworkFilterList::new(generic_hide, options.enable_optimizations),
+            removeparam: NetworkFilterList::new(removeparam, options.enable_optimizations),
+            headers: NetworkFilterList::new(headers, options.enable_optimizations),
+            generic_block: NetworkFilterList::new(generic_block, options.enable_optimizations),
             // Tags special case for enabling/disabling them dynamically
             tags_enabled: HashSet::new(),
             tagged_filters_all,
@@ -353,16 +936,29 @@ impl Blocker {
         self.filters_tagged.optimize();
         self.filters.optimize();
         self.generic_hide.optimize();
+        self.removeparam.optimize();
+        self.headers.optimize();
+        self.generic_block.optimize();
     }
 
     pub fn filter_exists(&self, filter: &NetworkFilter) -> bool {
         if filter.is_csp() {
             self.csp.filter_exists(filter)
-        } else if filter.is_generic_hide() {
+        } else if filter.is_removeparam() {
+            self.removeparam.filter_exists(filter)
+        } else if filter.is_header() {
+            self.headers.filter_exists(filter)
+        } else if filter.is_generic_block() {
+            self.generic_block.filter_exists(filter)
+        } else if filter.is_cosmetic_hide_exception() {
             self.generic_hide.filter_exists(filter)
         } else if filter.is_exception() {
-            self.exceptions.filter_exists(filter)
-        } else if filter.is_important() {
+            if filter.is_redirect() {
+                self.redirects.filter_exists(filter)
+            } else {
+                self.exceptions.filter_exists(filter)
+            }
+        } else if filter.is_important() && !filter.is_redirect_rule() {
             self.importants.filter_exists(filter)
         } else if filter.is_redirect() {
             self.redirects.filter_exists(filter)
@@ -381,13 +977,25 @@ impl Blocker {
         } else if filter.is_csp() {
             self.csp.add_filter(filter);
             Ok(())
-        } else if filter.is_generic_hide() {
+        } else if filter.is_removeparam() {
+            self.removeparam.add_filter(filter);
+            Ok(())
+        } else if filter.is_header() {
+            self.headers.add_filter(filter);
+            Ok(())
+        } else if filter.is_generic_block() {
+            self.generic_block.add_filter(filter);
+            Ok(())
+        } else if filter.is_cosmetic_hide_exception() {
             self.generic_hide.add_filter(filter);
             Ok(())
+        } else if filter.is_exception() && filter.is_redirect() {
+            self.redirects.add_filter(filter);
+            Ok(())
         } else if filter.is_exception() {
             self.exceptions.add_filter(filter);
             Ok(())
-        } else if filter.is_important() {
+        } else if filter.is_important() && !filter.is_redirect_rule() {
             self.importants.add_filter(filter);
             Ok(())
         } else if filter.is_redirect() {
@@ -404,6 +1012,58 @@ impl Blocker {
         }
     }
 
+    /// Removes a filter that was previously added, either on construction or through
+    /// `add_filter`. Filters that were fused together by the optimizer are separated again.
+    pub fn remove_filter(&mut self, filter: &NetworkFilter) -> Result<(), BlockerError> {
+        let removed = if filter.is_badfilter() {
+            return Err(BlockerError::BadFilterRemoveUnsupported);
+        } else if filter.is_csp() {
+            self.csp.remove_filter(filter)?
+        } else if filter.is_removeparam() {
+            self.removeparam.remove_filter(filter)?
+        } else if filter.is_header() {
+            self.headers.remove_filter(filter)?
+        } else if filter.is_generic_block() {
+            self.generic_block.remove_filter(filter)?
+        } else if filter.is_cosmetic_hide_exception() {
+            self.generic_hide.remove_filter(filter)?
+        } else if filter.is_exception() && filter.is_redirect() {
+            self.redirects.remove_filter(filter)?
+        } else if filter.is_exception() {
+            self.exceptions.remove_filter(filter)?
+        } else if filter.is_important() && !filter.is_redirect_rule() {
+            // `$important,redirect` filters are also stored with the redirects by `Blocker::new`.
+            // Both removals are checked first so that neither list is changed on failure.
+            if filter.is_redirect() {
+                let redirect_removals = self.redirects.find_removals(filter)?;
+                let important_removals = self.importants.find_removals(filter)?;
+                self.redirects.apply_removals(redirect_removals);
+                self.importants.apply_removals(important_removals)
+            } else {
+                self.importants.remove_filter(filter)?
+            }
+        } else if filter.is_redirect() {
+            self.redirects.remove_filter(filter)?
+        } else if filter.tag.is_some() {
+            let count = self.tagged_filters_all.len();
+            self.tagged_filters_all.retain(|f| f.id != filter.id);
+            let removed = self.tagged_filters_all.len() != count;
+            if removed {
+                let tags_enabled = std::mem::take(&mut self.tags_enabled);
+                self.tags_with_set(tags_enabled);
+            }
+            removed
+        } else {
+            self.filters.remove_filter(filter)?
+        };
+
+        if removed {
+            Ok(())
+        } else {
+            Err(BlockerError::FilterNotFound)
+        }
+    }
+
     pub fn use_tags(&mut self, tags: &[&str]) {
         let tag_set: HashSet<String> = HashSet::from_iter(tags.iter().map(|&t| String::from(t)));
         self.tags_with_set(tag_set);
@@ -455,6 +1115,10 @@ impl Blocker {
 #[derive(Serialize, Deserialize, Default)]
 pub struct NetworkFilterList {
     filter_map: HashMap<Hash, Vec<Arc<NetworkFilter>>>,
+    // Not serialized
+    #[cfg(feature = "metrics")]
+    #[serde(skip)]
+    pub(crate) statistics: crate::statistics::ListStatistics,
 }
 
 impl NetworkFilterList {
@@ -497,6 +1161,8 @@ impl NetworkFilterList {
 
         let mut self_ = NetworkFilterList {
             filter_map,
+            #[cfg(feature = "metrics")]
+            statistics: Default::default(),
         };
 
         if optimize {
@@ -576,7 +1242,7 @@ impl NetworkFilterList {
         for token in tokens {
             if let Some(filters) = self.filter_map.get(&token) {
                 for saved_filter in filters {
-                    if saved_filter.id == filter.id {
+                    if saved_filter.id == filter.id || saved_filter.fused_ids.iter().flatten().any(|id| *id == filter.id) {
                         return true;
                     }
                 }
@@ -586,51 +1252,85 @@ impl NetworkFilterList {
         false
     }
 
-    pub fn check(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>) -> Option<&NetworkFilter> {
-        #[cfg(feature = "metrics")]
-        let mut filters_checked = 0;
-        #[cfg(feature = "metrics")]
-        let mut filter_buckets = 0;
+    /// Removes a filter from the list. If it was fused with other filters by the optimizer, only
+    /// its own pattern is removed from the fused filter. If the same filter was added more than
+    /// once, only one copy is removed. Returns `Ok(false)` if the filter was not
+    /// found.
+    pub fn remove_filter(&mut self, filter: &NetworkFilter) -> Result<bool, BlockerError> {
+        let removals = self.find_removals(filter)?;
+        Ok(self.apply_removals(removals))
+    }
 
-        #[cfg(not(feature = "metrics"))]
-        {
-            if self.filter_map.is_empty() {
-                return None;
+    /// Finds the copy of `filter` to remove from each of its token buckets, without changing the
+    /// list, so that a failure to separate fused filters leaves the list untouched. Each removal
+    /// is given as the token, the index in the bucket, and the fused filter that should replace
+    /// the one at that index, if any.
+    fn find_removals(&self, filter: &NetworkFilter) -> Result<Vec<(Hash, usize, Option<NetworkFilter>)>, BlockerError> {
+        let mut tokens: Vec<_> = filter.get_tokens().into_iter().flatten().collect();
+        // Filters without any usable token are stored under 0
+        tokens.push(0);
+        tokens.sort_unstable();
+        tokens.dedup();
+
+        let mut removals = vec![];
+        for token in tokens {
+            let bucket = match self.filter_map.get(&token) {
+                Some(bucket) => bucket,
+                None => continue,
+            };
+
+            // Only one copy is removed, in case the same filter was added more than once
+            for (i, candidate) in bucket.iter().enumerate() {
+                let fused_ids = candidate.fused_ids.as_deref().unwrap_or_default();
+                if candidate.id == filter.id && fused_ids.len() <= 1 {
+                    removals.push((token, i, None));
+                    break;
+                } else if fused_ids.contains(&filter.id) {
+                    let unfused = optimizer::unfuse(candidate, filter).ok_or(BlockerError::FusedFilterRemovalUnsupported)?;
+                    removals.push((token, i, Some(unfused)));
+                    break;
+                }
             }
         }
 
-        if let Some(source_hostname_hashes) = request.source_hostname_hashes.as_ref() {
-            for token in source_hostname_hashes {
-                if let Some(filter_bucket) = self.filter_map.get(token) {
-                    #[cfg(feature = "metrics")]
-                    {
-                        filter_buckets += 1;
-                    }
+        Ok(removals)
+    }
 
-                    for filter in filter_bucket {
-                        #[cfg(feature = "metrics")]
-                        {
-                            filters_checked += 1;
-                        }
-                        // if matched, also needs to be tagged with an active tag (or not tagged at all)
-                        if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
-                            #[cfg(feature = "metrics")]
-                            print!("true\t{}\t{}\tskipped\t{}\t{}\t", filter_buckets, filters_checked, filter_buckets, filters_checked);
-                            return Some(filter);
-                        }
+    fn apply_removals(&mut self, removals: Vec<(Hash, usize, Option<NetworkFilter>)>) -> bool {
+        let removed = !removals.is_empty();
+        for (token, i, unfused) in removals {
+            if let Some(bucket) = self.filter_map.get_mut(&token) {
+                match unfused {
+                    Some(unfused) => bucket[i] = Arc::new(unfused),
+                    None => {
+                        bucket.remove(i);
                     }
                 }
+                if bucket.is_empty() {
+                    self.filter_map.remove(&token);
+                }
             }
         }
+        removed
+    }
 
+    pub fn check(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>) -> Option<&NetworkFilter> {
         #[cfg(feature = "metrics")]
-        print!("false\t{}\t{}\t", filter_buckets, filters_checked);
+        let (mut buckets_visited, mut filters_checked) = (0, 0);
 
-        for token in request_tokens {
+        if self.filter_map.is_empty() {
+            #[cfg(feature = "metrics")]
+            self.statistics.record_check(buckets_visited, filters_checked, None);
+            return None;
+        }
+
+        let source_tokens = request.source_hostname_hashes.as_deref().unwrap_or_default();
+        for token in source_tokens.iter().chain(request_tokens.iter()) {
             if let Some(filter_bucket) = self.filter_map.get(token) {
                 #[cfg(feature = "metrics")]
                 {
-                    filter_buckets += 1;
+                    buckets_visited += 1;
+                    self.statistics.record_bucket_visit(*token);
                 }
                 for filter in filter_bucket {
                     #[cfg(feature = "metrics")]
@@ -640,7 +1340,7 @@ impl NetworkFilterList {
                     // if matched, also needs to be tagged with an active tag (or not tagged at all)
                     if filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true) {
                         #[cfg(feature = "metrics")]
-                        print!("true\t{}\t{}\t", filter_buckets, filters_checked);
+                        self.statistics.record_check(buckets_visited, filters_checked, Some(filter.as_ref()));
                         return Some(filter);
                     }
                 }
@@ -648,10 +1348,178 @@ impl NetworkFilterList {
         }
 
         #[cfg(feature = "metrics")]
-        print!("false\t{}\t{}\t", filter_buckets, filters_checked);
+        self.statistics.record_check(buckets_visited, filters_checked, None);
 
         None
     }
+
+    /// Returns every filter from the list that matches the request, rather than stopping at the
+    /// first one. Used for options like `$csp`, where all matching filters contribute to the
+    /// result.
+    pub fn check_all(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>) -> Vec<&NetworkFilter> {
+        self.collect_matches(request, request_tokens, |filter| Self::matches_with_tags(filter, request, active_tags), true)
+    }
+
+    /// Like `check_all`, but without recording statistics, so that explaining a request that was
+    /// already checked doesn't count it twice.
+    pub fn explain_all(&self, request: &Request, request_tokens: &[Hash], active_tags: &HashSet<String>) -> Vec<&NetworkFilter> {
+        self.collect_matches(request, request_tokens, |filter| Self::matches_with_tags(filter, request, active_tags), false)
+    }
+
+    /// Returns every filter from the list that matches the source document of the request, as
+    /// found by `NetworkFilter::matches_document`. `document_tokens` are the tokens of the
+    /// source hostname. Tagged filters are not supported.
+    pub fn check_all_documents(&self, request: &Request, document_tokens: &[Hash], record_statistics: bool) -> Vec<&NetworkFilter> {
+        let source_hashes = request.source_hostname_hashes.as_ref();
+        self.collect_matches(request, document_tokens, |filter| filter.tag.is_none() && filter.matches_document(source_hashes), record_statistics)
+    }
+
+    // if matched, also needs to be tagged with an active tag (or not tagged at all)
+    fn matches_with_tags(filter: &NetworkFilter, request: &Request, active_tags: &HashSet<String>) -> bool {
+        filter.matches(request) && filter.tag.as_ref().map(|t| active_tags.contains(t)).unwrap_or(true)
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    fn collect_matches<F: Fn(&NetworkFilter) -> bool>(&self, request: &Request, request_tokens: &[Hash], matches: F, record_statistics: bool) -> Vec<&NetworkFilter> {
+        let mut filters: Vec<&NetworkFilter> = vec![];
+        // A filter can be stored under more than one token
+        let mut seen_ids: HashSet<Hash> = HashSet::new();
+        #[cfg(feature = "metrics")]
+        let (mut buckets_visited, mut filters_checked) = (0, 0);
+
+        if self.filter_map.is_empty() {
+            #[cfg(feature = "metrics")]
+            if record_statistics {
+                self.statistics.record_check(buckets_visited, filters_checked, None);
+            }
+            return filters;
+        }
+
+        let source_tokens = request.source_hostname_hashes.as_deref().unwrap_or_default();
+        for token in source_tokens.iter().chain(request_tokens.iter()) {
+            if let Some(filter_bucket) = self.filter_map.get(token) {
+                #[cfg(feature = "metrics")]
+                if record_statistics {
+                    buckets_visited += 1;
+                    self.statistics.record_bucket_visit(*token);
+                }
+                for filter in filter_bucket {
+                    #[cfg(feature = "metrics")]
+                    {
+                        filters_checked += 1;
+                    }
+                    if matches(filter) && seen_ids.insert(filter.id) {
+                        filters.push(filter);
+                    }
+                }
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        if record_statistics {
+            self.statistics.record_check(buckets_visited, filters_checked, filters.iter().copied());
+        }
+
+        filters
+    }
+}
+
+/// Resolves the values of all filters with a value-carrying option, like `$csp` or `$removeparam`,
+/// that matched a request. An exception without a value cancels every other filter, while an
+/// exception with a value only cancels filters with that same value. `$important` filters are not
+/// subject to exceptions.
+///
+/// Returns the deduplicated values of the remaining filters in a stable order. `None` stands for
+/// a filter without a value.
+fn resolve_option_values<'a, F>(filters: &[&'a NetworkFilter], value: F) -> Vec<Option<&'a str>>
+where
+    F: Fn(&'a NetworkFilter) -> Option<&'a str>,
+{
+    let mut disable_all = false;
+    let mut disabled: HashSet<&str> = HashSet::new();
+    let mut important: HashSet<Option<&str>> = HashSet::new();
+    let mut enabled: HashSet<Option<&str>> = HashSet::new();
+
+    for filter in filters.iter().copied() {
+        if filter.is_exception() {
+            match value(filter) {
+                None => disable_all = true,
+                Some(v) => { disabled.insert(v); }
+            }
+        } else if filter.is_important() {
+            important.insert(value(filter));
+        } else {
+            enabled.insert(value(filter));
+        }
+    }
+
+    let mut values: Vec<Option<&str>> = important.iter().copied().collect();
+    if !disable_all {
+        values.extend(enabled
+            .into_iter()
+            .filter(|v| !important.contains(v))
+            .filter(|v| v.map(|v| !disabled.contains(v)).unwrap_or(true)));
+    }
+
+    values.sort_unstable();
+    values
+}
+
+/// Removes query parameters from `url` as described by the values of a list of `$removeparam`
+/// filters. `None` removes every parameter, `name` removes parameters with that exact name, and
+/// `/regex/` removes parameters whose `name=value` pair matches the regex. A leading `~` inverts
+/// the value, so only matching parameters are kept.
+///
+/// Returns `None` if the URL was left unchanged.
+fn remove_query_params(url: &str, filters: &[Option<&NetworkFilter>]) -> Option<String> {
+    enum ParamMatcher<'a> {
+        All,
+        Name(&'a str),
+        Regex(Arc<CompiledRegex>),
+    }
+
+    let matchers: Vec<(bool, ParamMatcher)> = filters.iter().map(|filter| {
+        match filter.and_then(|f| f.removeparam.as_deref().map(|value| (f, value))) {
+            None => (false, ParamMatcher::All),
+            Some((filter, value)) => {
+                let negated = value.starts_with('~');
+                match filter.get_removeparam_regex() {
+                    Some(regex) => (negated, ParamMatcher::Regex(regex)),
+                    None => (negated, ParamMatcher::Name(value.trim_start_matches('~'))),
+                }
+            }
+        }
+    }).collect();
+
+    let query_start = url.find('?')?;
+    let query_end = url[query_start..].find('#').map(|i| query_start + i).unwrap_or_else(|| url.len());
+    let query = &url[query_start + 1..query_end];
+
+    let params: Vec<&str> = query.split('&').filter(|p| !p.is_empty()).collect();
+    let kept: Vec<&str> = params.iter().copied().filter(|param| {
+        let name = param.split('=').next().unwrap_or_default();
+        !matchers.iter().any(|(negated, matcher)| {
+            let matches = match matcher {
+                ParamMatcher::All => true,
+                ParamMatcher::Name(n) => *n == name,
+                ParamMatcher::Regex(re) => re.is_match(param),
+            };
+            matches != *negated
+        })
+    }).collect();
+
+    if kept.len() == params.len() {
+        return None;
+    }
+
+    let mut rewritten = String::with_capacity(url.len());
+    rewritten.push_str(&url[..query_start]);
+    if !kept.is_empty() {
+        rewritten.push('?');
+        rewritten.push_str(&kept.join("&"));
+    }
+    rewritten.push_str(&url[query_end..]);
+    Some(rewritten)
 }
 
 fn insert_dup<K, V, H: std::hash::BuildHasher>(map: &mut HashMap<K, Vec<V>, H>, k: K, v: V)
@@ -1095,6 +1963,198 @@ mod blocker_tests {
         assert_eq!(matched_rule.error, None);
     }
 
+    #[test]
+    fn redirect_rule_and_priority() {
+        let filters = vec![
+            // only redirects when blocked by another filter
+            String::from("||redirect-rule.com^$script,redirect-rule=noop.js"),
+            String::from("||redirect-rule.com/blocked/*"),
+            // highest priority redirect wins
+            String::from("||priority.com^$script,redirect=noop.js"),
+            String::from("||priority.com^$script,redirect-rule=noop.txt:10"),
+            // exceptions disable the redirect without unblocking
+            String::from("||excepted.com^$script,redirect=noop.js"),
+            String::from("@@||excepted.com^$redirect=noop.js"),
+            String::from("||excepted2.com^$script,redirect=noop.js"),
+            String::from("||excepted2.com^$script,redirect=noop.txt:-1"),
+            String::from("@@||excepted2.com^$redirect=noop.js"),
+            // exceptions without a resource disable every redirect
+            String::from("||excepted-all.com^$script,redirect=noop.js"),
+            String::from("||excepted-all.com^$script,redirect-rule=noop.txt:10"),
+            String::from("@@||excepted-all.com^$redirect-rule"),
+            // priorities are part of the filter id, so `$badfilter` can target them
+            String::from("||bad.com^$script,redirect=noop.js:5"),
+            String::from("||bad.com^$script,redirect=noop.txt:10"),
+            String::from("||bad.com^$script,redirect=noop.txt:10,badfilter"),
+        ];
+
+        let (network_filters, _) = parse_filters(&filters, true, FilterFormat::Standard);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            enable_optimizations: false,
+        };
+
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+
+        blocker.add_resource(&Resource {
+            name: "noop.js".to_string(),
+            aliases: vec![],
+            kind: crate::resources::ResourceType::Mime(crate::resources::MimeType::ApplicationJavascript),
+            content: base64::encode("js"),
+        }).unwrap();
+        blocker.add_resource(&Resource {
+            name: "noop.txt".to_string(),
+            aliases: vec![],
+            kind: crate::resources::ResourceType::Mime(crate::resources::MimeType::TextPlain),
+            content: base64::encode("txt"),
+        }).unwrap();
+
+        let js = Some("data:application/javascript;base64,anM=".to_string());
+        let txt = Some("data:text/plain;base64,dHh0".to_string());
+
+        let check = |url: &str| blocker.check(&Request::from_urls(url, "https://example.com", "script").unwrap());
+
+        let result = check("https://redirect-rule.com/ad.js");
+        assert_eq!(result.matched, false);
+        assert_eq!(result.redirect, None);
+
+        let result = check("https://redirect-rule.com/blocked/ad.js");
+        assert_eq!(result.matched, true);
+        assert_eq!(result.redirect, js);
+
+        let result = check("https://priority.com/ad.js");
+        assert_eq!(result.matched, true);
+        assert_eq!(result.redirect, txt);
+
+        let result = check("https://excepted.com/ad.js");
+        assert_eq!(result.matched, true);
+        assert_eq!(result.redirect, None);
+        assert_eq!(result.exception, None);
+
+        let result = check("https://excepted2.com/ad.js");
+        assert_eq!(result.matched, true);
+        assert_eq!(result.redirect, txt);
+
+        let result = check("https://excepted-all.com/ad.js");
+        assert_eq!(result.matched, true);
+        assert_eq!(result.redirect, None);
+        assert_eq!(result.exception, None);
+
+        let result = check("https://bad.com/ad.js");
+        assert_eq!(result.matched, true);
+        assert_eq!(result.redirect, js);
+    }
+
+    #[test]
+    fn explain() {
+        let filters = vec![
+            String::from("||ads.com^"),
+            String::from("||ads.com/banner/*"),
+            String::from("@@||ads.com/banner/allowed.png"),
+            String::from("||important.com^$important"),
+            String::from("@@||important.com^"),
+            String::from("||redirect.com^$redirect=noop.js"),
+            String::from("||params.com^$removeparam=utm_source"),
+        ];
+
+        // Traces are available without debug mode
+        let (network_filters, _) = parse_filters(&filters, false, FilterFormat::Standard);
+        let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: false });
+
+        let explain = |url: &str| blocker.explain(&Request::from_urls(url, "https://example.com", "image").unwrap());
+        let lists = |trace: &BlockerTrace| trace.steps.iter().map(|s| (s.list, s.matches.len())).collect::<Vec<_>>();
+
+        let trace = explain("https://ads.com/banner/ad.png");
+        assert_eq!(trace.decision, BlockerDecision::Blocked);
+        assert!(trace.result.matched);
+        assert_eq!(lists(&trace), vec![
+            (FilterListKind::Importants, 0),
+            (FilterListKind::Redirects, 0),
+            (FilterListKind::Tagged, 0),
+            (FilterListKind::Filters, 2),
+            (FilterListKind::Exceptions, 0),
+        ]);
+        let filter = trace.filter.unwrap();
+        assert_eq!(filter.raw_line, None);
+        assert_eq!(filter.hostname, Some("ads.com".to_string()));
+        assert_eq!(trace.exception.is_none(), true);
+
+        let trace = explain("https://ads.com/banner/allowed.png");
+        assert_eq!(trace.decision, BlockerDecision::Excepted);
+        assert!(!trace.result.matched);
+        assert!(trace.exception.unwrap().exception);
+
+        // Exceptions are not checked once an important filter matched
+        let trace = explain("https://important.com/ad.png");
+        assert_eq!(trace.decision, BlockerDecision::Important);
+        assert_eq!(lists(&trace), vec![
+            (FilterListKind::Importants, 1),
+            (FilterListKind::Redirects, 0),
+        ]);
+
+        let trace = explain("https://redirect.com/ad.png");
+        assert_eq!(trace.decision, BlockerDecision::Blocked);
+        assert_eq!(trace.filter.unwrap().redirect, Some("noop.js".to_string()));
+
+        let trace = explain("https://params.com/page?utm_source=x");
+        assert_eq!(trace.decision, BlockerDecision::NoMatch);
+        assert_eq!(trace.filter.is_none(), true);
+        assert_eq!(trace.steps.last().map(|s| (s.list, s.matches.len())), Some((FilterListKind::RemoveParam, 1)));
+
+        let trace = blocker.explain(&Request::from_urls("ftp://ads.com/ad.png", "https://example.com", "image").unwrap());
+        assert_eq!(trace.decision, BlockerDecision::Unsupported);
+        assert!(trace.steps.is_empty());
+    }
+
+    #[test]
+    fn response_headers() {
+        let filters = vec![
+            String::from("||example.com^$script,header=via:1.1 google"),
+            String::from(r"||example.com^$header=x-ad:/^(banner|popup)\b/"),
+            String::from("||example.com^$header=x-tracker"),
+            String::from("||example.com^$header=content-type:~text/html"),
+            String::from("@@||example.com/allowed/*$header=x-tracker"),
+            String::from("@@||example.com/nav/"),
+            String::from("||example.com/nav/$header=x-ad:/./,important"),
+        ];
+
+        let (network_filters, _) = parse_filters(&filters, true, FilterFormat::Standard);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            enable_optimizations: false,
+        };
+
+        let blocker = Blocker::new(network_filters, &blocker_options);
+
+        let check = |url: &str, headers: &[(&str, &str)]| {
+            blocker.check_response_headers(&Request::from_urls(url, "https://example.com", "script").unwrap(), headers)
+        };
+
+        // `$header` filters are not applied at request time
+        assert!(!blocker.check(&Request::from_urls("https://example.com/a.js", "https://example.com", "script").unwrap()).matched);
+
+        assert!(check("https://example.com/a.js", &[("Via", "1.1 google")]).matched);
+        assert!(!check("https://example.com/a.js", &[("Via", "1.1 other")]).matched);
+        assert!(check("https://example.com/a.js", &[("X-Ad", "popup")]).matched);
+        assert!(!check("https://example.com/a.js", &[("X-Ad", "popups")]).matched);
+        assert!(check("https://example.com/a.js", &[("x-tracker", "")]).matched);
+        assert!(check("https://example.com/a.js", &[("Content-Type", "text/plain")]).matched);
+        assert!(!check("https://example.com/a.js", &[("Content-Type", "text/html")]).matched);
+        assert!(!check("https://example.com/a.js", &[]).matched);
+
+        // excepted by a `$header` exception
+        let result = check("https://example.com/allowed/a.js", &[("x-tracker", "1")]);
+        assert!(!result.matched);
+        assert_eq!(result.exception, Some(String::from("@@||example.com/allowed/*$header=x-tracker")));
+        assert!(check("https://example.com/allowed/a.js", &[("via", "1.1 google")]).matched);
+
+        // excepted by a regular exception, unless `$important`
+        assert!(!check("https://example.com/nav/a.js", &[("x-tracker", "1")]).matched);
+        let result = check("https://example.com/nav/a.js", &[("x-ad", "banner")]);
+        assert!(result.matched);
+        assert!(result.important);
+    }
+
     #[test]
     fn badfilter_does_not_match() {
         let filters = vec![
@@ -1348,6 +2408,89 @@ mod blocker_tests {
         }
     }
 
+    #[test]
+    fn filter_remove() {
+        let filters = vec![
+            String::from("||important.com^$important,redirect=noop.js"),
+            String::from("=ads="),
+            String::from("-ads-"),
+            String::from("||tagged.com^$tag=test"),
+            String::from("@@||exception.com^"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, FilterFormat::Standard);
+
+        let mut blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: true });
+        blocker.enable_tags(&["test"]);
+
+        let check = |blocker: &Blocker, url: &str| blocker.check(&Request::from_urls(url, "https://example.com", "script").unwrap()).matched;
+
+        // The two simple patterns share a token, so they are fused together by the optimizer
+        assert!(blocker.filters.filter_map.values().flatten().any(|f| f.fused_ids.as_ref().map(|ids| ids.len()) == Some(2)));
+        let filter = NetworkFilter::parse("=ads=", true).unwrap();
+        assert!(blocker.filter_exists(&filter));
+        blocker.remove_filter(&filter).unwrap();
+        assert!(!blocker.filter_exists(&filter));
+        assert!(!check(&blocker, "https://a.com/?=ads=1"));
+        assert!(check(&blocker, "https://a.com/1-ads-.js"));
+        assert_eq!(blocker.remove_filter(&filter), Err(BlockerError::FilterNotFound));
+        blocker.remove_filter(&NetworkFilter::parse("-ads-", true).unwrap()).unwrap();
+        assert!(!check(&blocker, "https://a.com/1-ads-.js"));
+
+        assert!(check(&blocker, "https://important.com/ad.js"));
+        blocker.remove_filter(&NetworkFilter::parse("||important.com^$important,redirect=noop.js", true).unwrap()).unwrap();
+        assert!(!check(&blocker, "https://important.com/ad.js"));
+        assert!(blocker.redirects.filter_map.is_empty());
+
+        assert!(check(&blocker, "https://tagged.com/ad.js"));
+        blocker.remove_filter(&NetworkFilter::parse("||tagged.com^$tag=test", true).unwrap()).unwrap();
+        assert!(!check(&blocker, "https://tagged.com/ad.js"));
+        assert_eq!(blocker.tags_enabled(), vec!["test".to_string()]);
+
+        let exception = NetworkFilter::parse("@@||exception.com^", true).unwrap();
+        blocker.add_filter(NetworkFilter::parse("||exception.com^", true).unwrap()).unwrap();
+        assert!(!check(&blocker, "https://exception.com/ad.js"));
+        blocker.remove_filter(&exception).unwrap();
+        assert!(check(&blocker, "https://exception.com/ad.js"));
+
+        let badfilter = NetworkFilter::parse("||exception.com^$badfilter", true).unwrap();
+        assert_eq!(blocker.remove_filter(&badfilter), Err(BlockerError::BadFilterRemoveUnsupported));
+    }
+
+    #[test]
+    fn filter_remove_fused_empty_pattern() {
+        let filters = vec![
+            String::from("$script,third-party"),
+            String::from("ad$script,third-party"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, FilterFormat::Standard);
+        let mut blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: true });
+
+        let filter = NetworkFilter::parse("ad$script,third-party", true).unwrap();
+        assert_eq!(blocker.remove_filter(&filter), Err(BlockerError::FusedFilterRemovalUnsupported));
+        assert!(blocker.filter_exists(&filter));
+    }
+
+    #[test]
+    fn filter_remove_duplicate() {
+        let filters = vec![
+            String::from("=ads="),
+            String::from("=ads="),
+            String::from("-ads-"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, FilterFormat::Standard);
+        let filter = NetworkFilter::parse("=ads=", true).unwrap();
+
+        // Only one copy is removed, with or without the optimizer fusing the duplicates together
+        for enable_optimizations in [false, true].iter() {
+            let mut blocker = Blocker::new(network_filters.clone(), &BlockerOptions { enable_optimizations: *enable_optimizations });
+            blocker.remove_filter(&filter).unwrap();
+            assert!(blocker.filter_exists(&filter));
+            blocker.remove_filter(&filter).unwrap();
+            assert!(!blocker.filter_exists(&filter));
+            assert!(blocker.filter_exists(&NetworkFilter::parse("-ads-", true).unwrap()));
+        }
+    }
+
     #[test]
     fn filter_add_tagged() {
         // Allow filter to be added twice when the engine is optimised
@@ -1406,8 +2549,161 @@ mod blocker_tests {
         let mut blocker = Blocker::new(Vec::new(), &blocker_options);
 
         blocker.add_filter(NetworkFilter::parse("@@||example.com$generichide", true).unwrap()).unwrap();
+        blocker.add_filter(NetworkFilter::parse("@@||example.org$specifichide", true).unwrap()).unwrap();
+        blocker.add_filter(NetworkFilter::parse("@@||example.net$elemhide", true).unwrap()).unwrap();
 
         assert!(blocker.check_generic_hide(&Request::from_url("https://example.com").unwrap()));
+        assert_eq!(blocker.check_cosmetic_hide(&Request::from_url("https://example.com").unwrap()), (true, false));
+        assert_eq!(blocker.check_cosmetic_hide(&Request::from_url("https://example.org").unwrap()), (false, true));
+        assert_eq!(blocker.check_cosmetic_hide(&Request::from_url("https://example.net").unwrap()), (true, true));
+        assert_eq!(blocker.check_cosmetic_hide(&Request::from_url("https://example.biz").unwrap()), (false, false));
+    }
+
+    #[test]
+    fn genericblock() {
+        let filters = vec![
+            "@@||example.com^$genericblock",
+            "@@||example.org^$genericblock,generichide",
+            "@@$genericblock,domain=example.info",
+            "@@||example.biz/path$genericblock",
+            "/generic-ad.",
+            "/generic-ad2.$important",
+            "||ads.net/redirected.js$redirect=noop.js",
+            "/specific-ad.$domain=example.com",
+        ];
+        let network_filters: Vec<_> = filters.iter().map(|f| NetworkFilter::parse(f, true).unwrap()).collect();
+        let blocker = Blocker::new(network_filters, &BlockerOptions { enable_optimizations: true });
+
+        let requests = vec![
+            ("https://ads.net/generic-ad.png", "https://example.com/page", false),
+            ("https://ads.net/generic-ad2.png", "https://sub.example.com/page", false),
+            ("https://ads.net/redirected.js", "https://example.com/page", false),
+            ("https://ads.net/specific-ad.png", "https://example.com/page", true),
+            ("https://ads.net/generic-ad.png", "https://example.net/page", true),
+            ("https://ads.net/generic-ad2.png", "https://example.net/page", true),
+            ("https://ads.net/generic-ad.png", "", true),
+            ("https://ads.net/generic-ad.png", "https://sub.example.info/page", false),
+            // The path of the source document is not known
+            ("https://ads.net/generic-ad.png", "https://example.biz/path", true),
+        ];
+
+        for (url, source_url, expected_matched) in requests {
+            let request = Request::from_urls(url, source_url, "image").unwrap();
+            let result = blocker.check(&request);
+            assert_eq!(result.matched, expected_matched, "{} from {}", url, source_url);
+            assert_eq!(blocker.explain(&request).result.matched, expected_matched);
+            if !expected_matched {
+                assert!(result.redirect.is_none());
+            }
+        }
+
+        let trace = blocker.explain(&Request::from_urls("https://ads.net/generic-ad.png", "https://example.com/", "image").unwrap());
+        assert_eq!(trace.decision, BlockerDecision::NoMatch);
+        assert_eq!(trace.steps[0].list, FilterListKind::GenericBlock);
+        assert_eq!(trace.steps[0].matches.len(), 1);
+
+        assert!(blocker.check(&Request::from_urls("https://ads.net/generic-ad.png", "https://example.org/", "image").unwrap()).exception.is_none());
+        assert!(!blocker.check(&Request::from_urls("https://ads.net/generic-ad.png", "https://example.org/", "image").unwrap()).matched);
+        assert!(blocker.check_generic_hide(&Request::from_url("https://example.org").unwrap()));
+        assert!(!blocker.check_generic_hide(&Request::from_url("https://example.com").unwrap()));
+    }
+
+    #[test]
+    fn csp_directives() {
+        let filters = vec![
+            String::from("||example.com^$csp=script-src 'none'"),
+            String::from("||example.com^$csp=worker-src 'none'"),
+            String::from("$csp=default-src 'self',domain=generic.com"),
+            String::from("||excepted.com^$csp=script-src 'none'"),
+            String::from("@@||excepted.com^$csp"),
+            String::from("||partial.com^$csp=script-src 'none'"),
+            String::from("||partial.com^$csp=worker-src 'none'"),
+            String::from("@@||partial.com^$csp=worker-src 'none'"),
+            String::from("||important.com^$csp=script-src 'none',important"),
+            String::from("||important.com^$csp=worker-src 'none'"),
+            String::from("@@||important.com^$csp"),
+            String::from("||bad.com^$csp=script-src 'none'"),
+            String::from("||bad.com^$csp=script-src 'none',badfilter"),
+            String::from("||tagged.com^$csp=script-src 'none',tag=test"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, FilterFormat::Standard);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            enable_optimizations: false,
+        };
+
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+        assert_eq!(blocker.get_csp_directives(&Request::from_urls("https://tagged.com", "https://tagged.com", "document").unwrap()), None);
+        blocker.enable_tags(&["test"]);
+
+        let csp = |url: &str, request_type: &str| {
+            blocker.get_csp_directives(&Request::from_urls(url, url, request_type).unwrap())
+        };
+
+        assert_eq!(csp("https://example.com", "document"), Some(String::from("script-src 'none', worker-src 'none'")));
+        assert_eq!(csp("https://sub.example.com/page.html", "sub_frame"), Some(String::from("script-src 'none', worker-src 'none'")));
+        // only documents receive CSP headers
+        assert_eq!(csp("https://example.com/script.js", "script"), None);
+        assert_eq!(csp("https://generic.com", "document"), Some(String::from("default-src 'self'")));
+        assert_eq!(csp("https://other.com", "document"), None);
+        assert_eq!(csp("https://excepted.com", "document"), None);
+        assert_eq!(csp("https://partial.com", "document"), Some(String::from("script-src 'none'")));
+        assert_eq!(csp("https://important.com", "document"), Some(String::from("script-src 'none'")));
+        assert_eq!(csp("https://bad.com", "document"), None);
+        assert_eq!(csp("https://tagged.com", "document"), Some(String::from("script-src 'none'")));
+    }
+
+    #[test]
+    fn removeparam() {
+        let filters = vec![
+            String::from("||example.com^$removeparam=utm_source"),
+            String::from("||example.com^$removeparam=/^fbclid=/"),
+            String::from("||keep.com^$removeparam=~id"),
+            String::from("||all.com^$removeparam"),
+            String::from("||excepted.com^$removeparam=utm_source"),
+            String::from("@@||excepted.com^$removeparam"),
+            String::from("||partial.com^$removeparam=utm_source"),
+            String::from("||partial.com^$removeparam=utm_medium"),
+            String::from("@@||partial.com^$removeparam=utm_medium"),
+            String::from("||important.com^$removeparam=utm_source,important"),
+            String::from("@@||important.com^$removeparam"),
+            String::from("||blocked.com^"),
+            String::from("||blocked.com^$removeparam=utm_source"),
+            String::from("||tagged.com^$removeparam=/^utm_/,tag=test"),
+        ];
+        let (network_filters, _) = parse_filters(&filters, true, FilterFormat::Standard);
+
+        let blocker_options: BlockerOptions = BlockerOptions {
+            enable_optimizations: false,
+        };
+
+        let mut blocker = Blocker::new(network_filters, &blocker_options);
+        blocker.enable_tags(&["test"]);
+
+        let rewrite = |url: &str| {
+            let result = blocker.check(&Request::from_urls(url, "https://source.com", "xhr").unwrap());
+            assert!(!result.matched || result.rewritten_url.is_none());
+            result.rewritten_url
+        };
+
+        assert_eq!(rewrite("https://example.com/page?utm_source=x&a=1"), Some(String::from("https://example.com/page?a=1")));
+        assert_eq!(rewrite("https://example.com/page?a=1&fbclid=2#frag"), Some(String::from("https://example.com/page?a=1#frag")));
+        assert_eq!(rewrite("https://example.com/page?utm_source=x&fbclid=2"), Some(String::from("https://example.com/page")));
+        assert_eq!(rewrite("https://example.com/page?a=1"), None);
+        assert_eq!(rewrite("https://example.com/page"), None);
+        assert_eq!(rewrite("https://other.com/page?utm_source=x"), None);
+        assert_eq!(rewrite("https://keep.com/?id=1&b=2&c=3"), Some(String::from("https://keep.com/?id=1")));
+        assert_eq!(rewrite("https://all.com/?b=2&c=3"), Some(String::from("https://all.com/")));
+        assert_eq!(rewrite("https://excepted.com/?utm_source=x"), None);
+        assert_eq!(rewrite("https://partial.com/?utm_source=x&utm_medium=y"), Some(String::from("https://partial.com/?utm_medium=y")));
+        assert_eq!(rewrite("https://important.com/?utm_source=x&b=1"), Some(String::from("https://important.com/?b=1")));
+        // blocked requests aren't rewritten
+        assert_eq!(rewrite("https://blocked.com/?utm_source=x"), None);
+        assert_eq!(rewrite("https://tagged.com/?utm_source=x&b=1"), Some(String::from("https://tagged.com/?b=1")));
+
+        // documents are rewritten as well
+        let result = blocker.check(&Request::from_urls("https://example.com/?utm_source=x", "https://example.com", "document").unwrap());
+        assert_eq!(result.rewritten_url, Some(String::from("https://example.com/")));
     }
 }
 
@@ -1441,11 +2737,10 @@ mod legacy_rule_parsing_tests {
     // not handling (and not including) filters with the following options:
     // - $popup
     // - $document
-    // - $elemhide
     // difference from original counts caused by not handling document/subdocument options and possibly miscounting on the blocker side.
     // Printing all non-cosmetic, non-html, non-comment/-empty rules and ones with no unsupported options yields 29142 items
-    // This engine also handles 3 rules that old one does not
-    const EASY_LIST: ListCounts = ListCounts { filters: 24062+3, cosmetic_filters: 31163, exceptions: 5800 };
+    // This engine also handles 3 rules that old one does not, as well as 2 $elemhide and 4 $genericblock exceptions
+    const EASY_LIST: ListCounts = ListCounts { filters: 24062+3, cosmetic_filters: 31163, exceptions: 5800+2+4 };
     // easyPrivacy = { 11817, 0, 0, 1020 };
     // differences in counts explained by hashset size underreporting as detailed in the next two cases
     const EASY_PRIVACY: ListCounts = ListCounts { filters: 11889, cosmetic_filters: 0, exceptions: 1021 };
@@ -1484,6 +2779,7 @@ mod legacy_rule_parsing_tests {
 
         // Some filters in the filter_map are pointed at by multiple tokens, increasing the total number of items
         assert!(vec_hashmap_len(&blocker.exceptions.filter_map) + vec_hashmap_len(&blocker.generic_hide.filter_map)
+            + vec_hashmap_len(&blocker.generic_block.filter_map)
             >= expectation.exceptions, "Number of collected exceptions does not match expectation");
 
         assert!(vec_hashmap_len(&blocker.filters.filter_map) +
@@ -2,13 +2,13 @@
 
 use crate::filters::network::{NetworkFilter, NetworkFilterMask};
 use crate::filters::cosmetic::CosmeticFilter;
-use crate::lists::ParsedFilter;
+use crate::lists::{FilterParseError, ParsedFilter};
 
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 
 /// By default, ABP rules do not block top-level document requests. There's no way to express that
@@ -73,14 +73,14 @@ pub enum CbType {
     MakeHttps,
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum CbLoadType {
     FirstParty,
     ThirdParty,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum CbResourceType {
     Document,
@@ -136,6 +136,70 @@ pub struct CbTrigger {
     pub unless_top_url: Option<Vec<String>>,
 }
 
+/// Known registrable domains, e.g. `google.com` and `google.co.uk`, loaded from a list derived
+/// from the Public Suffix List. Cosmetic rules for entities like `google.*` are expanded into
+/// every known domain of the entity, since content blocking rules can only match hostnames.
+#[derive(Clone, Debug, Default)]
+pub struct CbEntityDomains {
+    /// Known domains, by their entity (i.e. their first label)
+    domains: HashMap<String, Vec<String>>,
+}
+
+impl CbEntityDomains {
+    /// Accepts registrable domains, one per item. Empty items are ignored.
+    pub fn new<I: IntoIterator<Item = S>, S: AsRef<str>>(domains: I) -> Self {
+        let mut entity_domains = Self::default();
+        for domain in domains {
+            let domain = domain.as_ref().trim().trim_end_matches('.').to_ascii_lowercase();
+            if let Some((entity, _)) = domain.split_once('.') {
+                let known = entity_domains.domains.entry(entity.to_string()).or_default();
+                if !known.contains(&domain) {
+                    known.push(domain);
+                }
+            }
+        }
+        entity_domains
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    /// Returns the known domains for an entity like `google`, or hostnames of known domains for
+    /// an entity with subdomains, like `mail.google`.
+    fn expand(&self, entity: &str) -> Vec<String> {
+        let (subdomain, entity) = match entity.rsplit_once('.') {
+            Some((subdomain, entity)) => (Some(subdomain), entity),
+            None => (None, entity),
+        };
+        self.domains.get(entity).into_iter().flatten()
+            .map(|domain| match subdomain {
+                Some(subdomain) => format!("{}.{}", subdomain, domain),
+                None => domain.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Counts of the entities expanded by `FilterSet::into_content_blocking_with_entities` or
+/// `FilterSet::into_content_blocking_chunks`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CbEntityExpansion {
+    /// Number of entities (e.g. `google.*` or `~google.*`) expanded across all cosmetic rules.
+    pub entities_expanded: usize,
+    /// Number of domains that those entities were expanded into.
+    pub domains: usize,
+}
+
+/// A single list of content blocking rules, as returned by
+/// `FilterSet::into_content_blocking_chunks`, along with the `String` representation of the
+/// filters that it was converted from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CbRuleChunk {
+    pub rules: Vec<CbRule>,
+    pub filters_used: Vec<String>,
+}
+
 #[derive(Debug)]
 pub enum CbRuleCreationFailure {
     /// Currently, only filter rules parsed in debug mode can be translated into equivalent content
@@ -155,11 +219,22 @@ pub enum CbRuleCreationFailure {
     NetworkBadFilterUnsupported,
     /// Network rules with csp options cannot be supported in content blocking syntax.
     NetworkCspUnsupported,
+    /// Network rules with removeparam options cannot be supported in content blocking syntax.
+    NetworkRemoveparamUnsupported,
+    /// Network rules with constraints on the request's own domain (i.e. `$to` or `$denyallow`)
+    /// cannot be supported in content blocking syntax.
+    NetworkToDomainsUnsupported,
+    /// Network rules with HTTP method restrictions (i.e. `$method`) cannot be supported in content
+    /// blocking syntax.
+    NetworkMethodUnsupported,
+    /// Network rules matching response headers (i.e. `$header`) cannot be supported in content
+    /// blocking syntax.
+    NetworkHeaderUnsupported,
     /// `Blocker`-internal `NetworkFilter`s can be represented in optimized form, but these cannot
     /// be currently converted into content blocking syntax.
     OptimizedRulesUnsupported,
     /// Cosmetic rules with entities (e.g. google.*) rather than hostnames cannot be represented in
-    /// content blocking syntax.
+    /// content blocking syntax, unless every entity can be expanded using `CbEntityDomains`.
     CosmeticEntitiesUnsupported,
     /// Cosmetic rules with custom style specification (i.e. `:style(...)`) cannot be represented
     /// in content blocking syntax.
@@ -167,6 +242,17 @@ pub enum CbRuleCreationFailure {
     /// Cosmetic rules with scriptlet injections (i.e. `+js(...)`) cannot be represented in content
     /// blocking syntax.
     ScriptletInjectionsNotSupported,
+    /// Procedural cosmetic rules (i.e. `:has-text(...)`, `:upward(...)`, etc.) cannot be
+    /// represented in content blocking syntax.
+    ProceduralCosmeticFiltersNotSupported,
+    /// Network rules with elemhide or specifichide options cannot be supported in content
+    /// blocking syntax.
+    NetworkElemhideUnsupported,
+    /// Network rules with genericblock options cannot be supported in content blocking syntax.
+    NetworkGenericblockUnsupported,
+    /// The `ignore-previous-rules` rules, which are included in every chunk, leave no room for
+    /// other rules within the maximum number of rules per chunk.
+    TooManyIgnorePreviousRules,
 }
 
 impl TryFrom<ParsedFilter> for CbRuleEquivalent {
@@ -180,6 +266,95 @@ impl TryFrom<ParsedFilter> for CbRuleEquivalent {
     }
 }
 
+/// Merges `css-display-none` rules with identical triggers into a single rule with a
+/// comma-separated selector list, to reduce the number of rules. Selector lists are kept within
+/// `max_selector_len` bytes, although a single selector longer than that is left as it is.
+///
+/// WebKit ignores a whole rule if any selector in its list is invalid, so only the selectors for
+/// which `mergeable` returns `true` are merged, and every other rule is left as it is. It should
+/// only accept selectors known to be valid, e.g. with `filters::cosmetic::is_valid_css_selector`,
+/// which only rejects selectors if the `css-validation` feature is enabled.
+///
+/// `ignore-previous-rules` rules only apply to the rules before them, so rules are never merged
+/// across one. The merged rule takes the place of the first of its rules.
+pub fn coalesce_cosmetic_rules<M: FnMut(&str) -> bool>(rules: Vec<CbRule>, max_selector_len: usize, mergeable: M) -> Vec<CbRule> {
+    coalesce_cosmetic_rules_with(rules.into_iter().map(|rule| (rule, ())).collect(), max_selector_len, mergeable, |_, _| ())
+        .into_iter()
+        .map(|(rule, _)| rule)
+        .collect()
+}
+
+/// Like `coalesce_cosmetic_rules`, but each rule comes with some data, e.g. the filters it was
+/// converted from. The data of a merged rule is passed to `merge` along with the data of the rule
+/// it was merged into.
+pub(crate) fn coalesce_cosmetic_rules_with<T, M, F>(rules: Vec<(CbRule, T)>, max_selector_len: usize, mut mergeable: M, mut merge: F) -> Vec<(CbRule, T)>
+where
+    M: FnMut(&str) -> bool,
+    F: FnMut(&mut T, T),
+{
+    /// Hashable equivalent of a `CbTrigger`.
+    #[derive(PartialEq, Eq, Hash)]
+    struct TriggerKey {
+        url_filter: String,
+        url_filter_is_case_sensitive: Option<bool>,
+        if_domain: Option<Vec<String>>,
+        unless_domain: Option<Vec<String>>,
+        resource_type: Option<Vec<CbResourceType>>,
+        load_type: Vec<CbLoadType>,
+        if_top_url: Option<Vec<String>>,
+        unless_top_url: Option<Vec<String>>,
+    }
+
+    impl From<&CbTrigger> for TriggerKey {
+        fn from(trigger: &CbTrigger) -> Self {
+            let resource_type = trigger.resource_type.as_ref().map(|types| {
+                let mut types: Vec<_> = types.iter().cloned().collect();
+                types.sort_unstable();
+                types
+            });
+            Self {
+                url_filter: trigger.url_filter.clone(),
+                url_filter_is_case_sensitive: trigger.url_filter_is_case_sensitive,
+                if_domain: trigger.if_domain.clone(),
+                unless_domain: trigger.unless_domain.clone(),
+                resource_type,
+                load_type: trigger.load_type.clone(),
+                if_top_url: trigger.if_top_url.clone(),
+                unless_top_url: trigger.unless_top_url.clone(),
+            }
+        }
+    }
+
+    let mut coalesced: Vec<(CbRule, T)> = Vec::with_capacity(rules.len());
+    // Index in `coalesced` of the rule to merge into, for each trigger since the last
+    // `ignore-previous-rules` rule
+    let mut merge_targets: HashMap<TriggerKey, usize> = HashMap::new();
+
+    for (rule, data) in rules {
+        match (&rule.action.typ, &rule.action.selector) {
+            (CbType::IgnorePreviousRules, _) => merge_targets.clear(),
+            (CbType::CssDisplayNone, Some(selector)) if mergeable(selector) => {
+                let key = TriggerKey::from(&rule.trigger);
+                if let Some(&target) = merge_targets.get(&key) {
+                    let (target_rule, target_data) = &mut coalesced[target];
+                    let merged = target_rule.action.selector.as_mut().expect("Merge targets always have a selector");
+                    if merged.len() + ",".len() + selector.len() <= max_selector_len {
+                        merged.push(',');
+                        merged.push_str(selector);
+                        merge(target_data, data);
+                        continue;
+                    }
+                }
+                merge_targets.insert(key, coalesced.len());
+            }
+            _ => (),
+        }
+        coalesced.push((rule, data));
+    }
+
+    coalesced
+}
+
 fn non_empty(v: Vec<String>) -> Option<Vec<String>> {
     if v.len() > 0 { Some(v) } else { None }
 }
@@ -237,18 +412,36 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
         static REPLACE_WILDCARDS: Lazy<Regex> = Lazy::new(|| Regex::new(r##"\*"##).unwrap());
         static TRAILING_SEPARATOR: Lazy<Regex> = Lazy::new(|| Regex::new(r##"\^$"##).unwrap());
         if let Some(raw_line) = v.raw_line {
-            if v.redirect.is_some() {
+            if v.redirect.is_some() || v.mask.contains(NetworkFilterMask::ALL_REDIRECTS) {
                 return Err(CbRuleCreationFailure::NetworkRedirectUnsupported);
             }
             if v.mask.contains(NetworkFilterMask::GENERIC_HIDE) {
                 return Err(CbRuleCreationFailure::NetworkGenerichideUnsupported);
             }
+            if v.mask.intersects(NetworkFilterMask::ELEM_HIDE | NetworkFilterMask::SPECIFIC_HIDE) {
+                return Err(CbRuleCreationFailure::NetworkElemhideUnsupported);
+            }
+            if v.mask.contains(NetworkFilterMask::GENERIC_BLOCK) {
+                return Err(CbRuleCreationFailure::NetworkGenericblockUnsupported);
+            }
             if v.mask.contains(NetworkFilterMask::BAD_FILTER) {
                 return Err(CbRuleCreationFailure::NetworkBadFilterUnsupported);
             }
             if v.mask.contains(NetworkFilterMask::IS_CSP) {
                 return Err(CbRuleCreationFailure::NetworkCspUnsupported);
             }
+            if v.mask.contains(NetworkFilterMask::IS_REMOVEPARAM) {
+                return Err(CbRuleCreationFailure::NetworkRemoveparamUnsupported);
+            }
+            if v.opt_to_domains.is_some() || v.opt_not_to_domains.is_some() {
+                return Err(CbRuleCreationFailure::NetworkToDomainsUnsupported);
+            }
+            if v.opt_methods.is_some() || v.opt_not_methods.is_some() {
+                return Err(CbRuleCreationFailure::NetworkMethodUnsupported);
+            }
+            if v.header.is_some() {
+                return Err(CbRuleCreationFailure::NetworkHeaderUnsupported);
+            }
 
             let load_type = if v.mask.contains(NetworkFilterMask::THIRD_PARTY | NetworkFilterMask::FIRST_PARTY) {
                 vec![]
@@ -333,14 +526,14 @@ impl TryFrom<NetworkFilter> for CbRuleEquivalent {
                 let mut unless_domain = vec![];
 
                 // Unwraps are okay here - any rules with opt_domains or opt_not_domains must have
-                // an options section delimited by a '$' character, followed by a `domain=` option.
-                let opts = &raw_line[raw_line.find('$').unwrap() + "$".len()..];
-                let domains_start = &opts[opts.find("domain=").unwrap() + "domain=".len()..];
-                let domains = if let Some(comma) = domains_start.find(',') {
-                    &domains_start[..comma]
-                } else {
-                    domains_start
-                }.split('|');
+                // an options section delimited by a '$' character, followed by a `domain=` or
+                // `from=` option.
+                let opts = &raw_line[raw_line.rfind('$').unwrap() + "$".len()..];
+                let domains = opts.split(',')
+                    .rev()
+                    .find_map(|opt| opt.strip_prefix("domain=").or_else(|| opt.strip_prefix("from=")))
+                    .unwrap()
+                    .split('|');
 
                 domains.for_each(|domain| if domain.starts_with('~') {
                         unless_domain.push(format!("*{}", &domain["~".len()..]));
@@ -461,6 +654,14 @@ impl TryFrom<CosmeticFilter> for CbRule {
     type Error = CbRuleCreationFailure;
 
     fn try_from(v: CosmeticFilter) -> Result<Self, Self::Error> {
+        Self::from_cosmetic_filter(v, &CbEntityDomains::default(), &mut CbEntityExpansion::default())
+    }
+}
+
+impl CbRule {
+    /// Converts a cosmetic filter like `TryFrom<CosmeticFilter>`, but expands entities (e.g.
+    /// `google.*`) into the matching domains from `entity_domains`, counting them in `expansion`.
+    pub(crate) fn from_cosmetic_filter(v: CosmeticFilter, entity_domains: &CbEntityDomains, expansion: &mut CbEntityExpansion) -> Result<Self, CbRuleCreationFailure> {
         use crate::filters::cosmetic::{CosmeticFilterMask, CosmeticFilterLocationType};
 
         if v.style.is_some() {
@@ -469,25 +670,40 @@ impl TryFrom<CosmeticFilter> for CbRule {
         if v.mask.contains(CosmeticFilterMask::SCRIPT_INJECT) {
             return Err(CbRuleCreationFailure::ScriptletInjectionsNotSupported);
         }
+        if v.procedural.is_some() {
+            return Err(CbRuleCreationFailure::ProceduralCosmeticFiltersNotSupported);
+        }
 
         if let Some(raw_line) = v.raw_line {
             let mut hostnames_vec = vec![];
             let mut not_hostnames_vec = vec![];
 
-            let mut any_entities = false;
+            let mut any_unknown_entities = false;
+            let mut rule_expansion = CbEntityExpansion::default();
 
             // Unwrap is okay here - cosmetic rules must have a '#' character
             let sharp_index = raw_line.find('#').unwrap();
             CosmeticFilter::locations_before_sharp(&raw_line, sharp_index).for_each(|(location_type, location)| {
                 match location_type {
-                    CosmeticFilterLocationType::Entity => any_entities = true,
-                    CosmeticFilterLocationType::NotEntity => any_entities = true,
+                    CosmeticFilterLocationType::Entity | CosmeticFilterLocationType::NotEntity => {
+                        let domains = entity_domains.expand(location);
+                        if domains.is_empty() {
+                            any_unknown_entities = true;
+                        }
+                        rule_expansion.entities_expanded += 1;
+                        rule_expansion.domains += domains.len();
+                        if matches!(location_type, CosmeticFilterLocationType::Entity) {
+                            hostnames_vec.extend(domains);
+                        } else {
+                            not_hostnames_vec.extend(domains);
+                        }
+                    }
                     CosmeticFilterLocationType::Hostname => hostnames_vec.push(location.to_string()),
                     CosmeticFilterLocationType::NotHostname => not_hostnames_vec.push(location.to_string()),
                 }
             });
 
-            if any_entities {
+            if any_unknown_entities {
                 return Err(CbRuleCreationFailure::CosmeticEntitiesUnsupported);
             }
 
@@ -504,6 +720,9 @@ impl TryFrom<CosmeticFilter> for CbRule {
                 (not_hostnames_vec, hostnames_vec)
             };
 
+            expansion.entities_expanded += rule_expansion.entities_expanded;
+            expansion.domains += rule_expansion.domains;
+
             Ok(Self {
                 action: CbAction { typ: CbType::CssDisplayNone, selector: Some(v.selector) },
                 trigger: CbTrigger {
@@ -519,6 +738,241 @@ impl TryFrom<CosmeticFilter> for CbRule {
     }
 }
 
+/// Ways that converting a content blocking rule back into an equivalent filter can fail.
+#[derive(Debug)]
+pub enum CbRuleImportFailure {
+    /// Only `block`, `ignore-previous-rules` and `css-display-none` actions have equivalent
+    /// filters.
+    UnsupportedActionType,
+    /// Rules restricted by the URL of the main document (i.e. `if-top-url` or `unless-top-url`)
+    /// cannot be represented as filters.
+    TopUrlUnsupported,
+    /// The `url-filter` uses regex features that have no network filter equivalent, and it cannot
+    /// be kept as a regex filter either.
+    UrlFilterUnsupported,
+    /// `css-display-none` rules can only be restricted to domains, not by `url-filter`.
+    CosmeticUrlFilterUnsupported,
+    /// `css-display-none` rules must have a selector.
+    MissingSelector,
+    /// None of the rule's resource types have an equivalent network filter option, i.e. the rule
+    /// only applies to popups.
+    NoSupportedResourceTypes,
+    /// A `css-display-none` rule was converted into a `NetworkFilter`, or a network rule into a
+    /// `CosmeticFilter`.
+    WrongFilterType,
+    /// The equivalent filter could not be parsed.
+    FilterParseError(FilterParseError),
+    /// An `ignore-previous-rules` rule that only overrides earlier `css-display-none` rules can
+    /// only be converted if it applies to whole pages of some domains, or to pages matching a
+    /// `url-filter` on every domain.
+    CosmeticOverrideUnsupported,
+}
+
+impl CbRule {
+    /// Returns an equivalent `FilterFormat::Standard` filter. `important` makes network rules
+    /// `$important`, to keep them from being overridden by exceptions.
+    pub(crate) fn to_filter_rule(&self, important: bool) -> Result<String, CbRuleImportFailure> {
+        if self.trigger.if_top_url.is_some() || self.trigger.unless_top_url.is_some() {
+            return Err(CbRuleImportFailure::TopUrlUnsupported);
+        }
+
+        // Filters always apply to subdomains, so a leading `*` is implied
+        let domains: Vec<String> = self.trigger.if_domain.iter().flatten()
+            .map(|domain| domain.trim_start_matches('*').to_string())
+            .chain(self.trigger.unless_domain.iter().flatten()
+                .map(|domain| format!("~{}", domain.trim_start_matches('*'))))
+            .collect();
+
+        match self.action.typ {
+            CbType::CssDisplayNone => {
+                let selector = self.action.selector.as_ref().ok_or(CbRuleImportFailure::MissingSelector)?;
+                if !self.trigger.matches_any_url() {
+                    return Err(CbRuleImportFailure::CosmeticUrlFilterUnsupported);
+                }
+                Ok(format!("{}##{}", domains.join(","), selector))
+            }
+            CbType::Block | CbType::IgnorePreviousRules => {
+                let mut options = vec![];
+
+                if let Some(resource_types) = &self.trigger.resource_type {
+                    let mut types = vec![];
+                    macro_rules! push_if_type {
+                        ($source:ident, $($option:literal),+) => {
+                            if resource_types.contains(&CbResourceType::$source) {
+                                $( if !types.contains(&$option) { types.push($option); } )+
+                            }
+                        };
+                    }
+                    push_if_type!(Document, "subdocument");
+                    push_if_type!(SvgDocument, "subdocument");
+                    push_if_type!(Image, "image");
+                    push_if_type!(StyleSheet, "stylesheet");
+                    push_if_type!(Script, "script");
+                    push_if_type!(Font, "font");
+                    push_if_type!(Raw, "xmlhttprequest", "websocket", "ping", "other");
+                    push_if_type!(Media, "media");
+                    // TODO - Popup when implemented
+
+                    if types.is_empty() {
+                        return Err(CbRuleImportFailure::NoSupportedResourceTypes);
+                    }
+                    options.extend(types.into_iter().map(String::from));
+                }
+
+                match self.trigger.load_type.as_slice() {
+                    [CbLoadType::FirstParty] => options.push("first-party".to_string()),
+                    [CbLoadType::ThirdParty] => options.push("third-party".to_string()),
+                    _ => (),
+                }
+
+                if self.trigger.url_filter_is_case_sensitive == Some(true) {
+                    options.push("match-case".to_string());
+                }
+
+                if !domains.is_empty() {
+                    options.push(format!("domain={}", domains.join("|")));
+                }
+
+                if important && self.action.typ == CbType::Block {
+                    options.push("important".to_string());
+                }
+
+                let pattern = match url_filter_to_pattern(&self.trigger.url_filter) {
+                    Some(pattern) => pattern,
+                    // The last `$` of a filter always starts its options
+                    None if self.trigger.url_filter.contains('$') && options.is_empty() => {
+                        return Err(CbRuleImportFailure::UrlFilterUnsupported);
+                    }
+                    None => format!("/{}/", self.trigger.url_filter),
+                };
+
+                let exception = if self.action.typ == CbType::IgnorePreviousRules { "@@" } else { "" };
+                if options.is_empty() {
+                    Ok(format!("{}{}", exception, pattern))
+                } else {
+                    Ok(format!("{}{}${}", exception, pattern, options.join(",")))
+                }
+            }
+            CbType::BlockCookies | CbType::MakeHttps => Err(CbRuleImportFailure::UnsupportedActionType),
+        }
+    }
+
+    /// Returns filters equivalent to an `ignore-previous-rules` rule that can only override the
+    /// earlier `css-display-none` rules with the given `selectors`. A rule for whole pages of some
+    /// domains becomes cosmetic exceptions, e.g. `example.com#@#.ad`, and a rule for the pages
+    /// matching its `url-filter` becomes an `$elemhide` exception, e.g. `@@||example.com^$elemhide`.
+    pub(crate) fn to_cosmetic_exceptions(&self, selectors: &[&str]) -> Result<Vec<String>, CbRuleImportFailure> {
+        if self.trigger.if_top_url.is_some() || self.trigger.unless_top_url.is_some() {
+            return Err(CbRuleImportFailure::TopUrlUnsupported);
+        }
+        // Only the loads of documents are relevant to cosmetic rules
+        let documents = self.trigger.resource_type.as_ref()
+            .map(|types| types.contains(&CbResourceType::Document))
+            .unwrap_or(true);
+        if !documents || !self.trigger.load_type.is_empty() || self.trigger.unless_domain.is_some() {
+            return Err(CbRuleImportFailure::CosmeticOverrideUnsupported);
+        }
+
+        match &self.trigger.if_domain {
+            Some(if_domain) if self.trigger.matches_any_url() => {
+                let domains: Vec<&str> = if_domain.iter().map(|domain| domain.trim_start_matches('*')).collect();
+                Ok(selectors.iter().map(|selector| format!("{}#@#{}", domains.join(","), selector)).collect())
+            }
+            None if !self.trigger.matches_any_url() => {
+                let pattern = url_filter_to_pattern(&self.trigger.url_filter).ok_or(CbRuleImportFailure::UrlFilterUnsupported)?;
+                Ok(vec![format!("@@{}$elemhide", pattern)])
+            }
+            _ => Err(CbRuleImportFailure::CosmeticOverrideUnsupported),
+        }
+    }
+}
+
+impl CbTrigger {
+    /// Whether the `url-filter` matches every URL, as used for `css-display-none` rules.
+    fn matches_any_url(&self) -> bool {
+        ["", ".*", "^https?://", "^https?://.*"].contains(&self.url_filter.as_str())
+    }
+}
+
+/// Translates a `url-filter` into a network filter pattern, if it only uses the regex features
+/// that can be expressed with `||`, `|` and `*`.
+fn url_filter_to_pattern(url_filter: &str) -> Option<String> {
+    // As produced for hostname-anchored filters
+    const HOSTNAME_PREFIX: &str = r"^[^:]+:(//)?([^/]+\.)?";
+
+    let (mut pattern, rest) = if let Some(rest) = url_filter.strip_prefix(HOSTNAME_PREFIX) {
+        (String::from("||"), rest)
+    } else if let Some(rest) = url_filter.strip_prefix("^https?://") {
+        (String::from("|http*://"), rest)
+    } else if let Some(rest) = url_filter.strip_prefix('^') {
+        (String::from("|"), rest)
+    } else {
+        (String::new(), url_filter)
+    };
+
+    let (rest, right_anchor) = match rest.strip_suffix('$') {
+        Some(rest) if !rest.ends_with('\\') => (rest, true),
+        _ => (rest, false),
+    };
+
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                // Characters with a special meaning in filters, and escapes like `\d`
+                Some(escaped) if !"*^|$".contains(escaped) && !escaped.is_ascii_alphanumeric() => pattern.push(escaped),
+                _ => return None,
+            },
+            '.' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push('*');
+            }
+            '.' | '*' | '+' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' | '^' | '$' => return None,
+            c => pattern.push(c),
+        }
+    }
+
+    if pattern == "||" || pattern.starts_with('!') || pattern.contains('#') {
+        return None;
+    }
+    if right_anchor {
+        pattern.push('|');
+    }
+    if pattern.is_empty() {
+        pattern.push('*');
+    }
+    // Patterns between slashes would be parsed as regexes
+    if pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+        pattern.push('*');
+    }
+
+    Some(pattern)
+}
+
+impl TryFrom<CbRule> for NetworkFilter {
+    type Error = CbRuleImportFailure;
+
+    fn try_from(v: CbRule) -> Result<Self, Self::Error> {
+        if v.action.typ == CbType::CssDisplayNone {
+            return Err(CbRuleImportFailure::WrongFilterType);
+        }
+        let rule = v.to_filter_rule(false)?;
+        NetworkFilter::parse(&rule, true).map_err(|e| CbRuleImportFailure::FilterParseError(e.into()))
+    }
+}
+
+impl TryFrom<CbRule> for CosmeticFilter {
+    type Error = CbRuleImportFailure;
+
+    fn try_from(v: CbRule) -> Result<Self, Self::Error> {
+        if v.action.typ != CbType::CssDisplayNone {
+            return Err(CbRuleImportFailure::WrongFilterType);
+        }
+        let rule = v.to_filter_rule(false)?;
+        CosmeticFilter::parse(&rule, true).map_err(|e| CbRuleImportFailure::FilterParseError(e.into()))
+    }
+}
+
 #[cfg(test)]
 mod ab2cb_tests {
     use super::*;
@@ -661,6 +1115,39 @@ mod ab2cb_tests {
                 ]
             }
         }]"####);
+        test_from_abp("|ws://$from=4shared.com", r####"[{
+            "action": {
+                "type": "block"
+            },
+            "trigger": {
+                "url-filter": "^wss?://",
+                "if-domain": [
+                    "*4shared.com"
+                ]
+            }
+        }]"####);
+    }
+
+    #[test]
+    fn to_domains_unsupported() {
+        let filter = crate::lists::parse_filter("||cdn.com^$to=ads.cdn.com", true, FilterFormat::Standard).unwrap();
+        assert!(matches!(CbRuleEquivalent::try_from(filter), Err(CbRuleCreationFailure::NetworkToDomainsUnsupported)));
+        let filter = crate::lists::parse_filter("*$script,domain=a.com,denyallow=b.com", true, FilterFormat::Standard).unwrap();
+        assert!(matches!(CbRuleEquivalent::try_from(filter), Err(CbRuleCreationFailure::NetworkToDomainsUnsupported)));
+    }
+
+    #[test]
+    fn method_unsupported() {
+        let filter = crate::lists::parse_filter("||example.com/api^$method=post", true, FilterFormat::Standard).unwrap();
+        assert!(matches!(CbRuleEquivalent::try_from(filter), Err(CbRuleCreationFailure::NetworkMethodUnsupported)));
+        let filter = crate::lists::parse_filter("||example.com/api^$method=~get", true, FilterFormat::Standard).unwrap();
+        assert!(matches!(CbRuleEquivalent::try_from(filter), Err(CbRuleCreationFailure::NetworkMethodUnsupported)));
+    }
+
+    #[test]
+    fn header_unsupported() {
+        let filter = crate::lists::parse_filter("||example.com^$script,header=via:1.1 google", true, FilterFormat::Standard).unwrap();
+        assert!(matches!(CbRuleEquivalent::try_from(filter), Err(CbRuleCreationFailure::NetworkHeaderUnsupported)));
     }
 
     #[test]
@@ -1048,4 +1535,341 @@ mod ab2cb_tests {
             "action":{"type":"ignore-previous-rules"}
         }]"####).expect("content blocking rule under test could not be deserialized"));
     }
+
+    #[test]
+    fn entity_expansion() {
+        let entity_domains = CbEntityDomains::new(["google.com", "google.co.uk", "GOOGLE.com.", "example.org", ""]);
+
+        let filter = CosmeticFilter::parse("google.*,example.org##.ad", true).unwrap();
+        let mut expansion = CbEntityExpansion::default();
+        let rule = CbRule::from_cosmetic_filter(filter, &entity_domains, &mut expansion).unwrap();
+        assert_eq!(rule.trigger.if_domain, Some(vec!["google.com".to_string(), "google.co.uk".to_string(), "example.org".to_string()]));
+        assert_eq!(expansion, CbEntityExpansion { entities_expanded: 1, domains: 2 });
+
+        let filter = CosmeticFilter::parse("~mail.google.*##.ad", true).unwrap();
+        let rule = CbRule::from_cosmetic_filter(filter, &entity_domains, &mut expansion).unwrap();
+        assert_eq!(rule.trigger.unless_domain, Some(vec!["mail.google.com".to_string(), "mail.google.co.uk".to_string()]));
+        assert_eq!(expansion, CbEntityExpansion { entities_expanded: 2, domains: 4 });
+
+        // Entities without any known domain can't be expanded
+        let filter = CosmeticFilter::parse("google.*,yahoo.*##.ad", true).unwrap();
+        assert!(matches!(CbRule::from_cosmetic_filter(filter, &entity_domains, &mut expansion), Err(CbRuleCreationFailure::CosmeticEntitiesUnsupported)));
+        assert_eq!(expansion, CbEntityExpansion { entities_expanded: 2, domains: 4 });
+
+        let mut filter_set = crate::lists::FilterSet::new(true);
+        filter_set.add_filters(&["google.*##.ad".to_string(), "yahoo.*##.ad".to_string()], FilterFormat::Standard);
+        let (rules, filters_used, expansion) = filter_set.into_content_blocking_with_entities(crate::lists::RuleTypes::CosmeticOnly, &entity_domains).unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(filters_used, vec!["google.*##.ad"]);
+        assert_eq!(expansion, CbEntityExpansion { entities_expanded: 1, domains: 2 });
+
+        let mut filter_set = crate::lists::FilterSet::new(true);
+        filter_set.add_filters(&["google.*##.ad".to_string()], FilterFormat::Standard);
+        let (chunks, _, expansion) = filter_set.into_content_blocking_chunks(crate::lists::RuleTypes::CosmeticOnly, &entity_domains, 10, 1, None, |_| 0).unwrap();
+        assert_eq!(chunks[0].filters_used, vec!["google.*##.ad"]);
+        assert_eq!(expansion, CbEntityExpansion { entities_expanded: 1, domains: 2 });
+
+        assert!(matches!(
+            crate::lists::FilterSet::new(false).into_content_blocking_with_entities(crate::lists::RuleTypes::All, &entity_domains),
+            Err(CbRuleCreationFailure::NeedsDebugMode)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod cb2ab_tests {
+    use super::*;
+    use crate::lists::FilterSet;
+
+    fn rule(json: &str) -> CbRule {
+        serde_json::from_str(json).expect("content blocking rule under test could not be deserialized")
+    }
+
+    fn test_to_abp(cb: &str, abp_rule: &str) {
+        assert_eq!(rule(cb).to_filter_rule(false).unwrap(), abp_rule);
+    }
+
+    #[test]
+    fn network_rules() {
+        test_to_abp(r#"{"action": {"type": "block"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?ads\\.example\\.com"}}"#, "||ads.example.com");
+        test_to_abp(r#"{"action": {"type": "block"}, "trigger": {"url-filter": "&ad_box_"}}"#, "&ad_box_");
+        test_to_abp(r#"{"action": {"type": "block"}, "trigger": {"url-filter": "^https?://.*/banner\\.gif$"}}"#, "|http*://*/banner.gif|");
+        test_to_abp(r#"{"action": {"type": "block"}, "trigger": {"url-filter": "/ads/"}}"#, "/ads/*");
+        test_to_abp(r#"{"action": {"type": "block"}, "trigger": {"url-filter": "banner[0-9]+\\.gif", "resource-type": ["image"]}}"#, "/banner[0-9]+\\.gif/$image");
+        test_to_abp(r#"{
+            "action": {"type": "ignore-previous-rules"},
+            "trigger": {
+                "url-filter": "track",
+                "url-filter-is-case-sensitive": true,
+                "resource-type": ["script", "raw"],
+                "load-type": ["third-party"],
+                "unless-domain": ["*example.com", "example.net"]
+            }
+        }"#, "@@track$script,xmlhttprequest,websocket,ping,other,third-party,match-case,domain=~example.com|~example.net");
+    }
+
+    #[test]
+    fn cosmetic_rules() {
+        test_to_abp(r#"{"action": {"type": "css-display-none", "selector": ".ad"}, "trigger": {"url-filter": ".*"}}"#, "##.ad");
+        test_to_abp(r#"{"action": {"type": "css-display-none", "selector": ".ad"}, "trigger": {"url-filter": ".*", "if-domain": ["*example.com"]}}"#, "example.com##.ad");
+
+        assert!(CosmeticFilter::try_from(rule(r#"{"action": {"type": "css-display-none", "selector": ".ad"}, "trigger": {"url-filter": ".*", "unless-domain": ["*example.com"]}}"#)).is_ok());
+        assert!(matches!(NetworkFilter::try_from(rule(r#"{"action": {"type": "css-display-none", "selector": ".ad"}, "trigger": {"url-filter": ".*"}}"#)), Err(CbRuleImportFailure::WrongFilterType)));
+    }
+
+    #[test]
+    fn failures() {
+        assert!(matches!(rule(r#"{"action": {"type": "make-https"}, "trigger": {"url-filter": ".*"}}"#).to_filter_rule(false), Err(CbRuleImportFailure::UnsupportedActionType)));
+        assert!(matches!(rule(r#"{"action": {"type": "block"}, "trigger": {"url-filter": ".*", "if-top-url": ["https://example.com"]}}"#).to_filter_rule(false), Err(CbRuleImportFailure::TopUrlUnsupported)));
+        assert!(matches!(rule(r#"{"action": {"type": "block"}, "trigger": {"url-filter": "ad\\.js$", "resource-type": ["popup"]}}"#).to_filter_rule(false), Err(CbRuleImportFailure::NoSupportedResourceTypes)));
+        assert!(matches!(rule(r#"{"action": {"type": "block"}, "trigger": {"url-filter": "ads?\\.js$"}}"#).to_filter_rule(false), Err(CbRuleImportFailure::UrlFilterUnsupported)));
+        assert!(matches!(rule(r#"{"action": {"type": "css-display-none", "selector": ".ad"}, "trigger": {"url-filter": "example"}}"#).to_filter_rule(false), Err(CbRuleImportFailure::CosmeticUrlFilterUnsupported)));
+        assert!(matches!(rule(r#"{"action": {"type": "css-display-none"}, "trigger": {"url-filter": ".*"}}"#).to_filter_rule(false), Err(CbRuleImportFailure::MissingSelector)));
+    }
+
+    #[test]
+    fn add_content_blocking_rules() {
+        let mut rules: Vec<CbRule> = serde_json::from_str(r#"[
+            {"action": {"type": "ignore-previous-rules"}, "trigger": {"url-filter": "ads\\.js"}},
+            {"action": {"type": "block"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?ads\\.net"}},
+            {"action": {"type": "ignore-previous-rules"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?ads\\.net/allowed"}},
+            {"action": {"type": "block"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?ads\\.net/allowed/still-blocked"}},
+            {"action": {"type": "css-display-none", "selector": ".ad"}, "trigger": {"url-filter": ".*", "if-domain": ["*example.com"]}},
+            {"action": {"type": "block-cookies"}, "trigger": {"url-filter": ".*"}}
+        ]"#).unwrap();
+        rules.push(ignore_previous_fp_documents());
+
+        let mut filter_set = FilterSet::new(true);
+        let failures = filter_set.add_content_blocking_rules(&rules);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, 5);
+        assert!(matches!(failures[0].1, CbRuleImportFailure::UnsupportedActionType));
+
+        let engine = crate::engine::Engine::from_filter_set(filter_set, true);
+        let check = |url: &str| engine.check_network_urls(url, "https://example.com", "script").matched;
+        assert!(check("https://ads.net/ads.js"));
+        assert!(!check("https://ads.net/allowed/script.js"));
+        assert!(check("https://ads.net/allowed/still-blocked.js"));
+        assert!(engine.url_cosmetic_resources("https://example.com").hide_selectors.contains(".ad"));
+    }
+
+    #[test]
+    fn add_content_blocking_rules_between_overrides() {
+        let rules: Vec<CbRule> = serde_json::from_str(r#"[
+            {"action": {"type": "block"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?ads\\.net/"}},
+            {"action": {"type": "block"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?tracker\\.org/"}},
+            {"action": {"type": "ignore-previous-rules"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?ads\\.net/allowed"}},
+            {"action": {"type": "ignore-previous-rules"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?tracker\\.org/allowed"}},
+            {"action": {"type": "block"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?ads\\.net/allowed/still-blocked"}},
+            {"action": {"type": "block"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?tracker\\.org/allowed/blocked"}},
+            {"action": {"type": "ignore-previous-rules"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?tracker\\.org/allowed/blocked/again"}}
+        ]"#).unwrap();
+
+        let mut filter_set = FilterSet::new(true);
+        assert!(filter_set.add_content_blocking_rules(&rules).is_empty());
+
+        let engine = crate::engine::Engine::from_filter_set(filter_set, true);
+        let check = |url: &str| engine.check_network_urls(url, "https://example.com", "script").matched;
+        assert!(check("https://ads.net/ads.js"));
+        assert!(!check("https://ads.net/allowed/script.js"));
+        // Only an earlier override could match it, so it becomes `$important`
+        assert!(check("https://ads.net/allowed/still-blocked.js"));
+        // A later override could match it too, so it must stay overridable
+        assert!(!check("https://tracker.org/allowed/blocked/again.js"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filters(&[
+            "||ads.example.com^$script".to_string(),
+            "@@||ads.example.com/allowed.js".to_string(),
+            "example.com##.banner".to_string(),
+        ], crate::lists::FilterFormat::Standard);
+        let (rules, _) = filter_set.into_content_blocking(crate::lists::RuleTypes::All).unwrap();
+
+        let mut filter_set = FilterSet::new(true);
+        assert!(filter_set.add_content_blocking_rules(&rules).is_empty());
+        let engine = crate::engine::Engine::from_filter_set(filter_set, true);
+        assert!(engine.check_network_urls("https://ads.example.com/ad.js", "https://news.com", "script").matched);
+        assert!(!engine.check_network_urls("https://ads.example.com/allowed.js", "https://news.com", "script").matched);
+        assert!(!engine.check_network_urls("https://ads.example.com/ad.png", "https://news.com", "image").matched);
+        assert!(engine.url_cosmetic_resources("https://example.com").hide_selectors.contains(".banner"));
+    }
+
+    #[test]
+    fn round_trip_cosmetic_override() {
+        let rules: Vec<CbRule> = serde_json::from_str(r#"[
+            {"action": {"type": "css-display-none", "selector": "[data-ad]"}, "trigger": {"url-filter": ".*"}},
+            {"action": {"type": "css-display-none", "selector": ".banner"}, "trigger": {"url-filter": ".*", "if-domain": ["*example.net"]}},
+            {"action": {"type": "ignore-previous-rules"}, "trigger": {"url-filter": ".*", "if-domain": ["*example.com"]}},
+            {"action": {"type": "ignore-previous-rules"}, "trigger": {"url-filter": "^[^:]+:(//)?([^/]+\\.)?example\\.net"}},
+            {"action": {"type": "ignore-previous-rules"}, "trigger": {"url-filter": ".*", "unless-domain": ["*example.org"]}}
+        ]"#).unwrap();
+
+        let mut filter_set = FilterSet::new(true);
+        let failures = filter_set.add_content_blocking_rules(&rules);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, 4);
+        assert!(matches!(failures[0].1, CbRuleImportFailure::CosmeticOverrideUnsupported));
+
+        let engine = crate::engine::Engine::from_filter_set(filter_set, true);
+        let hidden = |url: &str| engine.url_cosmetic_resources(url).hide_selectors;
+        // `example.com#@#[data-ad]`
+        assert!(!hidden("https://www.example.com").contains("[data-ad]"));
+        assert!(hidden("https://other.com").contains("[data-ad]"));
+        // `@@||example.net$elemhide`
+        let resources = engine.url_cosmetic_resources("https://example.net");
+        assert!(resources.elemhide);
+        assert!(resources.hide_selectors.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod chunk_tests {
+    use super::*;
+    use crate::lists::{FilterFormat, FilterSet, RuleTypes};
+
+    fn filter_set(filters: &[&str]) -> FilterSet {
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filters(&filters.iter().map(|filter| filter.to_string()).collect::<Vec<_>>(), FilterFormat::Standard);
+        filter_set
+    }
+
+    #[test]
+    fn exceptions_in_every_chunk() {
+        let filters = filter_set(&[
+            "||one.com^",
+            "||two.com^",
+            "@@||one.com/allowed",
+            "||three.com^",
+            "##.ad",
+        ]);
+        let (chunks, dropped, _) = filters.into_content_blocking_chunks(RuleTypes::All, &Default::default(), 4, 10, None, |_| 0).unwrap();
+        assert!(dropped.is_empty());
+        assert_eq!(chunks.len(), 2);
+
+        // 2 other rules, the exception, and the first-party document exception
+        assert_eq!(chunks[0].filters_used, vec!["||one.com^", "||two.com^", "@@||one.com/allowed"]);
+        assert_eq!(chunks[0].rules.len(), 4);
+        assert_eq!(chunks[1].filters_used, vec!["||three.com^", "##.ad", "@@||one.com/allowed"]);
+        for chunk in &chunks {
+            assert_eq!(chunk.rules[2].action.typ, CbType::IgnorePreviousRules);
+            assert_eq!(chunk.rules[3], ignore_previous_fp_documents());
+        }
+    }
+
+    #[test]
+    fn ranking() {
+        let filters = filter_set(&[
+            "||one.com^",
+            "||two.com^",
+            "||three.com^",
+            "||four.com^",
+        ]);
+        let rank = |filter: &str| if filter.contains("three") || filter.contains("one") { 1 } else { 0 };
+        let (chunks, dropped, _) = filters.into_content_blocking_chunks(RuleTypes::NetworkOnly, &Default::default(), 2, 2, None, rank).unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].filters_used, vec!["||one.com^"]);
+        assert_eq!(chunks[1].filters_used, vec!["||three.com^"]);
+        assert_eq!(dropped, vec!["||two.com^", "||four.com^"]);
+
+        // No room left for anything but exceptions
+        assert!(matches!(
+            filter_set(&["||one.com^"]).into_content_blocking_chunks(RuleTypes::NetworkOnly, &Default::default(), 1, 2, None, |_| 0),
+            Err(CbRuleCreationFailure::TooManyIgnorePreviousRules)
+        ));
+
+        assert!(matches!(
+            FilterSet::new(false).into_content_blocking_chunks(RuleTypes::All, &Default::default(), 10, 1, None, |_| 0),
+            Err(CbRuleCreationFailure::NeedsDebugMode)
+        ));
+    }
+
+    #[test]
+    fn coalesce_before_chunking() {
+        let filters = &["||one.com^", "##.a", "##.b", "##.c"];
+
+        // Room for 2 rules alongside the first-party document exception
+        let (chunks, dropped, _) = filter_set(filters).into_content_blocking_chunks(RuleTypes::All, &Default::default(), 3, 1, None, |_| 0).unwrap();
+        assert_eq!(chunks[0].rules.len(), 3);
+        assert_eq!(dropped.len(), 2);
+
+        let (chunks, dropped, _) = filter_set(filters).into_content_blocking_chunks(RuleTypes::All, &Default::default(), 3, 1, Some(100), |_| 0).unwrap();
+        // Selectors are only merged once they can be validated
+        if !cfg!(feature = "css-validation") {
+            assert_eq!(chunks[0].rules.len(), 3);
+            assert_eq!(dropped.len(), 2);
+            return;
+        }
+        assert!(dropped.is_empty());
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].rules.len(), 3);
+        assert_eq!(chunks[0].rules[1].action.selector.as_deref(), Some(".a,.b,.c"));
+        assert_eq!(chunks[0].filters_used, vec!["||one.com^", "##.a", "##.b", "##.c"]);
+
+        // Merged rules are ranked by their highest ranked filter
+        let rank = |filter: &str| if filter == "##.c" { 1 } else { 0 };
+        let (chunks, dropped, _) = filter_set(filters).into_content_blocking_chunks(RuleTypes::All, &Default::default(), 2, 1, Some(100), rank).unwrap();
+        assert_eq!(chunks[0].filters_used, vec!["##.a", "##.b", "##.c"]);
+        assert_eq!(dropped, vec!["||one.com^"]);
+    }
+}
+
+#[cfg(test)]
+mod coalesce_tests {
+    use super::*;
+
+    fn css_rule(selector: &str, if_domain: Option<&str>) -> CbRule {
+        CbRule {
+            action: CbAction { typ: CbType::CssDisplayNone, selector: Some(selector.to_string()) },
+            trigger: CbTrigger {
+                url_filter: ".*".to_string(),
+                if_domain: if_domain.map(|domain| vec![domain.to_string()]),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn coalesce() {
+        let block = CbRule {
+            action: CbAction { typ: CbType::Block, selector: None },
+            trigger: CbTrigger { url_filter: "ads".to_string(), ..Default::default() },
+        };
+        let exception = CbRule {
+            action: CbAction { typ: CbType::IgnorePreviousRules, selector: None },
+            trigger: CbTrigger { url_filter: "example\\.com/allowed".to_string(), ..Default::default() },
+        };
+
+        let rules = vec![
+            css_rule(".a", None),
+            css_rule(".b", Some("example.com")),
+            block.clone(),
+            css_rule(".c", None),
+            css_rule(".d", Some("example.com")),
+            css_rule(".long-selector", None),
+            exception.clone(),
+            css_rule(".e", None),
+        ];
+        assert_eq!(coalesce_cosmetic_rules(rules, 8, |_| true), vec![
+            css_rule(".a,.c", None),
+            css_rule(".b,.d", Some("example.com")),
+            block,
+            css_rule(".long-selector", None),
+            exception,
+            css_rule(".e", None),
+        ]);
+
+        // Selectors that are not known to be valid stay in their own rule
+        let rules = vec![
+            css_rule(".a", None),
+            css_rule(".b:-abp-unknown", None),
+            css_rule(".c", None),
+        ];
+        assert_eq!(coalesce_cosmetic_rules(rules, 100, |selector| !selector.contains(":-abp-")), vec![
+            css_rule(".a,.c", None),
+            css_rule(".b:-abp-unknown", None),
+        ]);
+    }
 }
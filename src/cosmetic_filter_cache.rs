@@ -1,5 +1,6 @@
 use crate::filters::cosmetic::CosmeticFilter;
 use crate::filters::cosmetic::CosmeticFilterMask;
+use crate::filters::cosmetic::CosmeticFilterOperator;
 use crate::resources::{Resource, ScriptletResourceStorage};
 use crate::utils::Hash;
 
@@ -24,6 +25,17 @@ use serde::{Deserialize, Serialize};
 /// `generichide` is set to true if there is a corresponding `$generichide` exception network
 /// filter. If so, the page should not query for additional generic rules using
 /// `hidden_class_id_selectors`.
+///
+/// `specifichide` is set to true if there is a corresponding `$specifichide` exception network
+/// filter. If so, no hostname-specific hiding, style or procedural rules are included, although
+/// scriptlets are still injected.
+///
+/// `elemhide` is set to true if both generic and hostname-specific cosmetic rules are disabled,
+/// usually by an `$elemhide` exception network filter. `generichide` and `specifichide` are also
+/// set in that case.
+///
+/// `procedural_filters` is a set of procedural cosmetic filters, each represented as a chain of
+/// operators, that should be evaluated against the page's DOM.
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct UrlSpecificResources {
     pub hide_selectors: HashSet<String>,
@@ -31,6 +43,12 @@ pub struct UrlSpecificResources {
     pub exceptions: HashSet<String>,
     pub injected_script: String,
     pub generichide: bool,
+    #[serde(default)]
+    pub procedural_filters: HashSet<Vec<CosmeticFilterOperator>>,
+    #[serde(default)]
+    pub specifichide: bool,
+    #[serde(default)]
+    pub elemhide: bool,
 }
 
 impl UrlSpecificResources {
@@ -41,15 +59,21 @@ impl UrlSpecificResources {
             exceptions: HashSet::new(),
             injected_script: String::new(),
             generichide: false,
+            procedural_filters: HashSet::new(),
+            specifichide: false,
+            elemhide: false,
         }
     }
 }
 
-fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> (HashSet<String>, HashMap<String, Vec<String>>, Vec<String>) {
+type HostnameSpecificRules = (HashSet<String>, HashMap<String, Vec<String>>, Vec<String>, HashSet<Vec<CosmeticFilterOperator>>);
+
+fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> HostnameSpecificRules {
     if rules.is_empty() {
-        (HashSet::default(), HashMap::default(), vec![])
+        (HashSet::default(), HashMap::default(), vec![], HashSet::default())
     } else {
         let mut script_rules = Vec::with_capacity(10);
+        let mut procedural_rules = HashSet::new();
 
         let mut hide_rules = HashSet::with_capacity(rules.len());
         let mut style_rules: HashMap<String, Vec<String>> = HashMap::with_capacity(rules.len());
@@ -70,11 +94,14 @@ fn hostname_specific_rules(rules: &[&SpecificFilterType]) -> (HashSet<String>, H
                     SpecificFilterType::ScriptInject(sel) => {
                         script_rules.push(sel.to_owned());
                     }
+                    SpecificFilterType::Procedural(operators) => {
+                        procedural_rules.insert(operators.to_owned());
+                    }
                     _ => unreachable!()
                 }
             });
 
-        (hide_rules, style_rules, script_rules)
+        (hide_rules, style_rules, script_rules, procedural_rules)
     }
 }
 
@@ -90,6 +117,11 @@ pub(crate) struct CosmeticFilterCache {
     pub(crate) misc_generic_selectors: HashSet<String>,
 
     pub(crate) scriptlets: ScriptletResourceStorage,
+
+    // Not serialized
+    #[cfg(feature = "metrics")]
+    #[serde(skip)]
+    pub(crate) statistics: crate::statistics::Counters<String>,
 }
 
 impl CosmeticFilterCache {
@@ -105,6 +137,9 @@ impl CosmeticFilterCache {
             misc_generic_selectors: HashSet::new(),
 
             scriptlets: Default::default(),
+
+            #[cfg(feature = "metrics")]
+            statistics: Default::default(),
         }
     }
 
@@ -120,6 +155,9 @@ impl CosmeticFilterCache {
             misc_generic_selectors: HashSet::with_capacity(rules.len() / 30),
 
             scriptlets: Default::default(),
+
+            #[cfg(feature = "metrics")]
+            statistics: Default::default(),
         };
 
         for rule in rules {
@@ -140,6 +178,71 @@ impl CosmeticFilterCache {
         }
     }
 
+    /// Whether a rule equivalent to `rule` has already been added.
+    pub fn filter_exists(&self, rule: &CosmeticFilter) -> bool {
+        if rule.has_hostname_constraint() {
+            self.specific_rules.contains_rule(rule)
+        } else if rule.mask.contains(CosmeticFilterMask::IS_CLASS_SELECTOR) {
+            match &rule.key {
+                Some(key) if rule.mask.contains(CosmeticFilterMask::IS_SIMPLE) => self.simple_class_rules.contains(key),
+                Some(key) => self.complex_class_rules.get(key).map(|bucket| bucket.contains(&rule.selector)).unwrap_or(false),
+                None => false,
+            }
+        } else if rule.mask.contains(CosmeticFilterMask::IS_ID_SELECTOR) {
+            match &rule.key {
+                Some(key) if rule.mask.contains(CosmeticFilterMask::IS_SIMPLE) => self.simple_id_rules.contains(key),
+                Some(key) => self.complex_id_rules.get(key).map(|bucket| bucket.contains(&rule.selector)).unwrap_or(false),
+                None => false,
+            }
+        } else {
+            self.misc_generic_selectors.contains(&rule.selector)
+        }
+    }
+
+    /// Removes a filter that was previously added. Returns `false` if it could not be found.
+    pub fn remove_filter(&mut self, rule: &CosmeticFilter) -> bool {
+        if rule.has_hostname_constraint() {
+            if let Some(generic_rule) = rule.hidden_generic_rule() {
+                self.remove_generic_filter(&generic_rule);
+            }
+            self.specific_rules.remove_rule(rule)
+        } else {
+            self.remove_generic_filter(rule)
+        }
+    }
+
+    /// Remove a filter, assuming it has already been determined to be a generic rule
+    fn remove_generic_filter(&mut self, rule: &CosmeticFilter) -> bool {
+        fn remove_from_bucket(buckets: &mut HashMap<String, Vec<String>>, key: &str, selector: &str) -> bool {
+            if let Some(bucket) = buckets.get_mut(key) {
+                if let Some(index) = bucket.iter().position(|s| s == selector) {
+                    bucket.remove(index);
+                    if bucket.is_empty() {
+                        buckets.remove(key);
+                    }
+                    return true;
+                }
+            }
+            false
+        }
+
+        if rule.mask.contains(CosmeticFilterMask::IS_CLASS_SELECTOR) {
+            match &rule.key {
+                Some(key) if rule.mask.contains(CosmeticFilterMask::IS_SIMPLE) => self.simple_class_rules.remove(key),
+                Some(key) => remove_from_bucket(&mut self.complex_class_rules, key, &rule.selector),
+                None => false,
+            }
+        } else if rule.mask.contains(CosmeticFilterMask::IS_ID_SELECTOR) {
+            match &rule.key {
+                Some(key) if rule.mask.contains(CosmeticFilterMask::IS_SIMPLE) => self.simple_id_rules.remove(key),
+                Some(key) => remove_from_bucket(&mut self.complex_id_rules, key, &rule.selector),
+                None => false,
+            }
+        } else {
+            self.misc_generic_selectors.remove(&rule.selector)
+        }
+    }
+
     /// Add a filter, assuming it has already been determined to be a generic rule
     fn add_generic_filter(&mut self, rule: CosmeticFilter) {
         if rule.mask.contains(CosmeticFilterMask::IS_CLASS_SELECTOR) {
@@ -203,13 +306,18 @@ impl CosmeticFilterCache {
             return vec![];
         }
 
-        simple_classes.into_iter().map(|class| format!(".{}", class))
+        let selectors = simple_classes.into_iter().map(|class| format!(".{}", class))
             .chain(simple_ids.into_iter().map(|id| format!("#{}", id)))
             .chain(complex_selectors.into_iter().cloned())
-            .collect::<Vec<_>>()
+            .collect::<Vec<_>>();
+
+        #[cfg(feature = "metrics")]
+        selectors.iter().for_each(|selector| self.statistics.increment(format!("##{}", selector).as_str()));
+
+        selectors
     }
 
-    pub fn hostname_cosmetic_resources(&self, hostname: &str, generichide: bool) -> UrlSpecificResources {
+    pub fn hostname_cosmetic_resources(&self, hostname: &str, generichide: bool, specifichide: bool) -> UrlSpecificResources {
         let domain_str = {
             let (start, end) = crate::url_parser::get_host_domain(hostname);
             &hostname[start..end]
@@ -217,24 +325,36 @@ impl CosmeticFilterCache {
 
         let (request_entities, request_hostnames) = hostname_domain_hashes(hostname, domain_str);
 
+        // Rules are kept with the hash they were stored under, to tell apart the same rule for
+        // different hostnames in statistics
         let mut rules_that_apply = vec![];
         for hash in request_entities.iter().chain(request_hostnames.iter()) {
             if let Some(specific_rules) = self.specific_rules.retrieve(hash) {
-                rules_that_apply.extend(specific_rules);
+                rules_that_apply.extend(specific_rules.iter().map(|rule| (*hash, rule)));
             }
         };
 
         let mut exceptions = HostnameExceptionsBuilder::default();
 
-        rules_that_apply.iter().for_each(|r| {
+        rules_that_apply.iter().for_each(|(_, r)| {
             exceptions.insert_if_exception(r);
         });
 
-        let rules_that_apply = rules_that_apply.iter().map(|r| r.to_owned()).filter(|r| {
+        let rules_that_apply = rules_that_apply.into_iter().filter(|(_, r)| {
             exceptions.allow_specific_rule(r)
+        }).filter(|(_, r)| {
+            !specifichide || matches!(r, SpecificFilterType::ScriptInject(_))
         }).collect::<Vec<_>>();
 
-        let (hostname_hide_selectors, style_selectors, script_injections) = hostname_specific_rules(&rules_that_apply[..]);
+        #[cfg(feature = "metrics")]
+        rules_that_apply.iter().for_each(|(hash, rule)| {
+            let label = matched_label(hostname, domain_str, *hash);
+            self.statistics.increment(rule.statistics_key(&label).as_str());
+        });
+
+        let rules_that_apply = rules_that_apply.into_iter().map(|(_, r)| r).collect::<Vec<_>>();
+
+        let (hostname_hide_selectors, style_selectors, script_injections, procedural_filters) = hostname_specific_rules(&rules_that_apply[..]);
 
         let hide_selectors = if generichide {
             hostname_hide_selectors
@@ -258,6 +378,9 @@ impl CosmeticFilterCache {
             exceptions: exceptions.hide_exceptions,
             injected_script,
             generichide,
+            procedural_filters,
+            specifichide,
+            elemhide: generichide && specifichide,
         }
     }
 
@@ -287,6 +410,7 @@ struct HostnameExceptionsBuilder {
     hide_exceptions: HashSet<String>,
     style_exceptions: HashSet<(String, String)>,
     script_inject_exceptions: HashSet<String>,
+    procedural_exceptions: HashSet<Vec<CosmeticFilterOperator>>,
 }
 
 impl HostnameExceptionsBuilder {
@@ -295,7 +419,7 @@ impl HostnameExceptionsBuilder {
         use SpecificFilterType as Rule;
 
         match rule {
-            Rule::Hide(_) | Rule::Style(_, _) | Rule::ScriptInject(_) => (),
+            Rule::Hide(_) | Rule::Style(_, _) | Rule::ScriptInject(_) | Rule::Procedural(_) => (),
             Rule::Unhide(sel) => {
                 self.hide_exceptions.insert(sel.clone());
             }
@@ -305,6 +429,9 @@ impl HostnameExceptionsBuilder {
             Rule::UnhideScriptInject(script) => {
                 self.script_inject_exceptions.insert(script.clone());
             }
+            Rule::UnhideProcedural(operators) => {
+                self.procedural_exceptions.insert(operators.clone());
+            }
         }
     }
 
@@ -321,6 +448,7 @@ impl HostnameExceptionsBuilder {
             SpecificFilterType::Hide(sel) => !self.hide_exceptions.contains(sel),
             SpecificFilterType::Style(sel, style) => !self.style_exceptions.contains(&(sel.to_string(), style.to_string())),
             SpecificFilterType::ScriptInject(sel) => !self.script_inject_exceptions.contains(sel),
+            SpecificFilterType::Procedural(operators) => !self.procedural_exceptions.contains(operators),
             _ => false,
         }
     }
@@ -372,6 +500,53 @@ impl HostnameRuleDb {
         }
     }
 
+    /// Removes a rule previously stored with `store_rule`. Returns `false` if it was not found for
+    /// any of its hostnames.
+    pub fn remove_rule(&mut self, rule: &CosmeticFilter) -> bool {
+        let kind = SpecificFilterType::from(rule);
+        let mut removed = false;
+
+        for h in rule.hostnames.iter().chain(rule.entities.iter()).flatten() {
+            removed |= self.remove(h, &kind);
+        }
+
+        let kind = kind.negated();
+
+        for h in rule.not_hostnames.iter().chain(rule.not_entities.iter()).flatten() {
+            removed |= self.remove(h, &kind);
+        }
+
+        removed
+    }
+
+    /// Whether `rule` is stored for all of its hostnames, e.g. by an earlier `store_rule` call.
+    pub fn contains_rule(&self, rule: &CosmeticFilter) -> bool {
+        let kind = SpecificFilterType::from(rule);
+        let contains = |h: &Hash, kind: &SpecificFilterType| self.db.get(h).map(|bucket| bucket.contains(kind)).unwrap_or(false);
+
+        let mut hostnames = rule.hostnames.iter().chain(rule.entities.iter()).flatten().peekable();
+        let mut not_hostnames = rule.not_hostnames.iter().chain(rule.not_entities.iter()).flatten().peekable();
+        if hostnames.peek().is_none() && not_hostnames.peek().is_none() {
+            return false;
+        }
+
+        let negated = kind.clone().negated();
+        hostnames.all(|h| contains(h, &kind)) && not_hostnames.all(|h| contains(h, &negated))
+    }
+
+    fn remove(&mut self, hostname: &Hash, kind: &SpecificFilterType) -> bool {
+        if let Some(bucket) = self.db.get_mut(hostname) {
+            if let Some(index) = bucket.iter().position(|k| k == kind) {
+                bucket.remove(index);
+                if bucket.is_empty() {
+                    self.db.remove(hostname);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
     fn store(&mut self, hostname: &Hash, kind: SpecificFilterType) {
         if let Some(bucket) = self.db.get_mut(hostname) {
             bucket.push(kind);
@@ -389,7 +564,7 @@ impl HostnameRuleDb {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum SpecificFilterType {
     // Parameter is the rule's selector
     Hide(String),
@@ -402,6 +577,10 @@ pub enum SpecificFilterType {
     // Parameter is the rule's injected script
     ScriptInject(String),
     UnhideScriptInject(String),           // Barely happens in practice
+
+    // Parameter is the rule's chain of procedural operators
+    Procedural(Vec<CosmeticFilterOperator>),
+    UnhideProcedural(Vec<CosmeticFilterOperator>),
 }
 
 /// This implementation assumes the given rule has hostname or entity constraints, and that the
@@ -410,7 +589,13 @@ impl From<&CosmeticFilter> for SpecificFilterType {
     fn from(rule: &CosmeticFilter) -> Self {
         let unhide = rule.mask.contains(CosmeticFilterMask::UNHIDE);
 
-        if let Some(ref style) = rule.style {
+        if let Some(ref operators) = rule.procedural {
+            if unhide {
+                SpecificFilterType::UnhideProcedural(operators.clone())
+            } else {
+                SpecificFilterType::Procedural(operators.clone())
+            }
+        } else if let Some(ref style) = rule.style {
             if unhide {
                 SpecificFilterType::UnhideStyle(rule.selector.clone(), style.clone())
             } else {
@@ -433,6 +618,22 @@ impl From<&CosmeticFilter> for SpecificFilterType {
 }
 
 impl SpecificFilterType {
+    /// Key used to count matches of this rule in `CosmeticFilterCache::statistics`, written like
+    /// the rule itself for the hostname or entity `label` it was stored under.
+    #[cfg(feature = "metrics")]
+    fn statistics_key(&self, label: &str) -> String {
+        match self {
+            SpecificFilterType::Hide(sel) => format!("{}##{}", label, sel),
+            SpecificFilterType::Unhide(sel) => format!("{}#@#{}", label, sel),
+            SpecificFilterType::Style(sel, style) => format!("{}##{}:style({})", label, sel, style),
+            SpecificFilterType::UnhideStyle(sel, style) => format!("{}#@#{}:style({})", label, sel, style),
+            SpecificFilterType::ScriptInject(script) => format!("{}##+js({})", label, script),
+            SpecificFilterType::UnhideScriptInject(script) => format!("{}#@#+js({})", label, script),
+            SpecificFilterType::Procedural(operators) => format!("{}##{}", label, CosmeticFilterOperator::chain_to_string(operators)),
+            SpecificFilterType::UnhideProcedural(operators) => format!("{}#@#{}", label, CosmeticFilterOperator::chain_to_string(operators)),
+        }
+    }
+
     pub fn negated(self) -> Self {
         match self {
             SpecificFilterType::Hide(sel) => SpecificFilterType::Unhide(sel),
@@ -441,6 +642,8 @@ impl SpecificFilterType {
             SpecificFilterType::UnhideStyle(sel, style) => SpecificFilterType::Style(sel, style),
             SpecificFilterType::ScriptInject(script) => SpecificFilterType::UnhideScriptInject(script),
             SpecificFilterType::UnhideScriptInject(script) => SpecificFilterType::ScriptInject(script),
+            SpecificFilterType::Procedural(operators) => SpecificFilterType::UnhideProcedural(operators),
+            SpecificFilterType::UnhideProcedural(operators) => SpecificFilterType::Procedural(operators),
 
         }
     }
@@ -453,6 +656,27 @@ fn hostname_domain_hashes(hostname: &str, domain: &str) -> (Vec<Hash>, Vec<Hash>
     (request_entities, request_hostnames)
 }
 
+/// Finds the hostname, or the entity like `example.*`, among the labels of `hostname` that
+/// hashes to `hash`.
+#[cfg(feature = "metrics")]
+fn matched_label(hostname: &str, domain: &str, hash: Hash) -> String {
+    fn suffixes(hostname: &str) -> impl Iterator<Item = &str> {
+        std::iter::once(hostname).chain(hostname.match_indices('.').map(move |(i, _)| &hostname[i + 1..]))
+    }
+
+    if let Some(label) = suffixes(hostname).find(|label| crate::utils::fast_hash(label) == hash) {
+        return label.to_string();
+    }
+    // Entities are hashed without their public suffix
+    if let Some(dot) = domain.find('.') {
+        let without_public_suffix = &hostname[..hostname.len() - (domain.len() - dot)];
+        if let Some(label) = suffixes(without_public_suffix).find(|label| crate::utils::fast_hash(label) == hash) {
+            return format!("{}.*", label);
+        }
+    }
+    hash.to_string()
+}
+
 #[cfg(test)]
 mod cosmetic_cache_tests {
     use super::*;
@@ -473,15 +697,15 @@ mod cosmetic_cache_tests {
             "sub.example.com#@#.item2",
         ]);
 
-        let out = cfcache.hostname_cosmetic_resources("test.com", false);
+        let out = cfcache.hostname_cosmetic_resources("test.com", false, false);
         let mut expected = UrlSpecificResources::empty();
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("example.com", false, false);
         expected.exceptions.insert(".item".into());
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false, false);
         expected.exceptions.insert(".item2".into());
         assert_eq!(out, expected);
     }
@@ -492,20 +716,81 @@ mod cosmetic_cache_tests {
             "example.com,~sub.example.com##.item",
         ]);
 
-        let out = cfcache.hostname_cosmetic_resources("test.com", false);
+        let out = cfcache.hostname_cosmetic_resources("test.com", false, false);
         let mut expected = UrlSpecificResources::empty();
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("example.com", false, false);
         expected.hide_selectors.insert(".item".to_owned());
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false, false);
         let mut expected = UrlSpecificResources::empty();
         expected.exceptions.insert(".item".into());
         assert_eq!(out, expected);
     }
 
+    #[test]
+    fn remove_filter() {
+        let mut cfcache = cache_from_rules(vec![
+            "##.simple-class",
+            "##.complex-class > div",
+            "###simple-id",
+            "##a[href^=\"https://ads.com\"]",
+            "example.com,~sub.example.com##.item",
+            "example.com##.item2",
+        ]);
+
+        let remove = |cfcache: &mut CosmeticFilterCache, rule: &str| cfcache.remove_filter(&CosmeticFilter::parse(rule, false).unwrap());
+
+        assert!(remove(&mut cfcache, "##.simple-class"));
+        assert!(remove(&mut cfcache, "##.complex-class > div"));
+        assert!(remove(&mut cfcache, "###simple-id"));
+        assert!(remove(&mut cfcache, "##a[href^=\"https://ads.com\"]"));
+        assert!(!remove(&mut cfcache, "##.simple-class"));
+        assert!(cfcache.simple_class_rules.is_empty());
+        assert!(cfcache.complex_class_rules.is_empty());
+        assert!(cfcache.simple_id_rules.is_empty());
+        assert!(cfcache.misc_generic_selectors.is_empty());
+
+        assert!(remove(&mut cfcache, "example.com,~sub.example.com##.item"));
+        assert!(!remove(&mut cfcache, "other.com##.item2"));
+
+        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false, false);
+        let mut expected = UrlSpecificResources::empty();
+        expected.hide_selectors.insert(".item2".to_owned());
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn procedural_exceptions() {
+        use crate::filters::cosmetic::CosmeticFilterOperator::*;
+
+        let cfcache = cache_from_rules(vec![
+            "example.com,~sub.example.com##.ad:has-text(Sponsored)",
+            "sub.test.example.com#@#.ad:has-text(Sponsored)",
+            "a1.sub.example.com##.ad:upward(2)",
+        ]);
+
+        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false, false);
+        let mut expected = UrlSpecificResources::empty();
+        assert_eq!(out, expected);
+
+        let out = cfcache.hostname_cosmetic_resources("sub.test.example.com", false, false);
+        assert_eq!(out, expected);
+
+        let out = cfcache.hostname_cosmetic_resources("test.example.com", true, false);
+        expected.generichide = true;
+        expected.procedural_filters.insert(vec![CssSelector(".ad".to_owned()), HasText("Sponsored".to_owned())]);
+        assert_eq!(out, expected);
+
+        let out = cfcache.hostname_cosmetic_resources("a1.sub.example.com", false, false);
+        expected.generichide = false;
+        expected.procedural_filters.clear();
+        expected.procedural_filters.insert(vec![CssSelector(".ad".to_owned()), NthAncestor(2)]);
+        assert_eq!(out, expected);
+    }
+
     #[test]
     fn style_exceptions() {
         let cfcache = cache_from_rules(vec![
@@ -515,23 +800,23 @@ mod cosmetic_cache_tests {
             "a2.sub.example.com##.element:style(background: #000)",
         ]);
 
-        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false, false);
         let mut expected = UrlSpecificResources::empty();
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("sub.test.example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("sub.test.example.com", false, false);
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("a1.sub.example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("a1.sub.example.com", false, false);
         expected.hide_selectors.insert(".element".to_owned());
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("test.example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("test.example.com", false, false);
         expected.hide_selectors.clear();
         expected.style_selectors.insert(".element".to_owned(), vec!["background: #fff".to_owned()]);
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("a2.sub.example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("a2.sub.example.com", false, false);
         expected.style_selectors.clear();
         expected.style_selectors.insert(".element".to_owned(), vec!["background: #000".to_owned()]);
         assert_eq!(out, expected);
@@ -574,26 +859,26 @@ mod cosmetic_cache_tests {
             },
         ]);
 
-        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("sub.example.com", false, false);
         let mut expected = UrlSpecificResources::empty();
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("sub.test.example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("sub.test.example.com", false, false);
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("test.example.com", false);
+        let out = cfcache.hostname_cosmetic_resources("test.example.com", false, false);
         expected.injected_script = "set-constant.js, atob, trueFunc\n".to_owned();
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("cosmetic.net", false);
+        let out = cfcache.hostname_cosmetic_resources("cosmetic.net", false, false);
         expected.injected_script = "nowebrtc.js\n".to_owned();
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("g.cosmetic.net", false);
+        let out = cfcache.hostname_cosmetic_resources("g.cosmetic.net", false, false);
         expected.injected_script = "nowebrtc.js\nwindow.open-defuser.js\n".to_owned();
         assert_eq!(out, expected);
 
-        let out = cfcache.hostname_cosmetic_resources("c.g.cosmetic.net", false);
+        let out = cfcache.hostname_cosmetic_resources("c.g.cosmetic.net", false, false);
         expected.injected_script = "window.open-defuser.js\n".to_owned();
         assert_eq!(out, expected);
     }
@@ -646,7 +931,7 @@ mod cosmetic_cache_tests {
             "~test.com###test-element",
         ];
         let cfcache = CosmeticFilterCache::from_rules(rules.iter().map(|r| CosmeticFilter::parse(r, false).unwrap()).collect::<Vec<_>>());
-        let exceptions = cfcache.hostname_cosmetic_resources("example.co.uk", false).exceptions;
+        let exceptions = cfcache.hostname_cosmetic_resources("example.co.uk", false, false).exceptions;
 
         let out = cfcache.hidden_class_id_selectors(&["a-class".into()], &[], &exceptions);
         assert_eq!(out, [".a-class .with .children"]);
@@ -657,7 +942,7 @@ mod cosmetic_cache_tests {
         let out = cfcache.hidden_class_id_selectors(&[], &["test-element".into()], &exceptions);
         assert_eq!(out, ["#test-element"]);
 
-        let exceptions = cfcache.hostname_cosmetic_resources("a1.test.com", false).exceptions;
+        let exceptions = cfcache.hostname_cosmetic_resources("a1.test.com", false, false).exceptions;
 
         let out = cfcache.hidden_class_id_selectors(&["a-class".into()], &[], &exceptions);
         assert_eq!(out, [".a-class", ".a-class .with .children"]);
@@ -680,14 +965,14 @@ mod cosmetic_cache_tests {
         ];
         let cfcache = CosmeticFilterCache::from_rules(rules.iter().map(|r| CosmeticFilter::parse(r, false).unwrap()).collect::<Vec<_>>());
 
-        let hide_selectors = cfcache.hostname_cosmetic_resources("test.com", false).hide_selectors;
+        let hide_selectors = cfcache.hostname_cosmetic_resources("test.com", false, false).hide_selectors;
         let mut expected_hides = HashSet::new();
         expected_hides.insert("a[href=\"bad.com\"]".to_owned());
         expected_hides.insert("div > p".to_owned());
         expected_hides.insert("a[href=\"notbad.com\"]".to_owned());
         assert_eq!(hide_selectors, expected_hides);
 
-        let hide_selectors = cfcache.hostname_cosmetic_resources("example.com", false).hide_selectors;
+        let hide_selectors = cfcache.hostname_cosmetic_resources("example.com", false, false).hide_selectors;
         let mut expected_hides = HashSet::new();
         expected_hides.insert("a[href=\"bad.com\"]".to_owned());
         assert_eq!(hide_selectors, expected_hides);
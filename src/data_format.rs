@@ -12,6 +12,7 @@ use flate2::read::GzDecoder;
 use flate2::Compression;
 use rmp_serde as rmps;
 
+use crate::allowlist::Allowlist;
 use crate::blocker::{Blocker, NetworkFilterList};
 use crate::resources::{RedirectResourceStorage, ScriptletResourceStorage};
 use crate::filters::network::NetworkFilter;
@@ -88,6 +89,14 @@ struct SerializeFormatRest<'a> {
     scriptlets: &'a ScriptletResourceStorage,
 
     generic_hide: &'a NetworkFilterList,
+
+    removeparam: &'a NetworkFilterList,
+
+    headers: &'a NetworkFilterList,
+
+    generic_block: &'a NetworkFilterList,
+
+    allowlist: Option<&'a Allowlist>,
 }
 
 /// Structural representation of adblock engine data that can be built up from deserialization and
@@ -170,11 +179,24 @@ struct DeserializeFormatRest {
 
     #[serde(default)]
     generic_hide: NetworkFilterList,
+
+    #[serde(default)]
+    removeparam: NetworkFilterList,
+
+    #[serde(default)]
+    headers: NetworkFilterList,
+
+    #[serde(default)]
+    generic_block: NetworkFilterList,
+
+    /// `None` for data serialized before the allowlist was added to the format.
+    #[serde(default)]
+    allowlist: Option<Allowlist>,
 }
 
-impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache)> for SerializeFormat<'a> {
-    fn from(v: (&'a Blocker, &'a CosmeticFilterCache)) -> Self {
-        let (blocker, cfc) = v;
+impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache, &'a Allowlist)> for SerializeFormat<'a> {
+    fn from(v: (&'a Blocker, &'a CosmeticFilterCache, &'a Allowlist)) -> Self {
+        let (blocker, cfc, allowlist) = v;
         Self {
             part1: SerializeFormatPt1 {
                 csp: &blocker.csp,
@@ -206,13 +228,21 @@ impl<'a> From<(&'a Blocker, &'a CosmeticFilterCache)> for SerializeFormat<'a> {
                 scriptlets: &cfc.scriptlets,
 
                 generic_hide: &blocker.generic_hide,
+
+                removeparam: &blocker.removeparam,
+
+                headers: &blocker.headers,
+
+                generic_block: &blocker.generic_block,
+
+                allowlist: Some(allowlist),
             },
         }
     }
 }
 
-impl Into<(Blocker, CosmeticFilterCache)> for DeserializeFormat {
-    fn into(self) -> (Blocker, CosmeticFilterCache) {
+impl Into<(Blocker, CosmeticFilterCache, Option<Allowlist>)> for DeserializeFormat {
+    fn into(self) -> (Blocker, CosmeticFilterCache, Option<Allowlist>) {
         (Blocker {
             csp: self.part1.csp,
             exceptions: self.part1.exceptions,
@@ -233,6 +263,9 @@ impl Into<(Blocker, CosmeticFilterCache)> for DeserializeFormat {
             pool: Default::default(),
 
             generic_hide: self.rest.generic_hide,
+            removeparam: self.rest.removeparam,
+            headers: self.rest.headers,
+            generic_block: self.rest.generic_block,
         }, CosmeticFilterCache {
             simple_class_rules: self.rest.simple_class_rules,
             simple_id_rules: self.rest.simple_id_rules,
@@ -244,6 +277,9 @@ impl Into<(Blocker, CosmeticFilterCache)> for DeserializeFormat {
             misc_generic_selectors: self.rest.misc_generic_selectors,
 
             scriptlets: self.rest.scriptlets,
-        })
+
+            #[cfg(feature = "metrics")]
+            statistics: Default::default(),
+        }, self.rest.allowlist)
     }
 }
@@ -0,0 +1,667 @@
+//! Transforms network filter rules into the `declarativeNetRequest` rule format used by Chromium
+//! Manifest V3 extensions.
+
+use crate::filters::network::{FilterPart, NetworkFilter, NetworkFilterMask};
+use crate::request::RequestMethod;
+
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+
+/// Priority of rules that block requests or remove query parameters.
+pub const BLOCK_PRIORITY: u32 = 1;
+/// Largest `$redirect` priority, as in `$redirect=resource:N`, that keeps its order among
+/// `declarativeNetRequest` rules. Priorities beyond it, in either direction, are clamped.
+pub const MAX_REDIRECT_PRIORITY: u32 = 100;
+/// Priority of `$redirect` rules without an explicit priority. `declarativeNetRequest` gives
+/// precedence to blocking over redirecting at equal priority, so redirects need a higher priority
+/// to win over the filter that would otherwise block the request. A redirect with priority `N`
+/// gets `REDIRECT_PRIORITY + N`, which is always between `BLOCK_PRIORITY` and
+/// `EXCEPTION_PRIORITY`.
+pub const REDIRECT_PRIORITY: u32 = BLOCK_PRIORITY + MAX_REDIRECT_PRIORITY + 1;
+/// Priority of exception (`@@`) rules, which override blocking and redirecting rules.
+pub const EXCEPTION_PRIORITY: u32 = REDIRECT_PRIORITY + MAX_REDIRECT_PRIORITY + 1;
+/// Priority of `$important` rules, which are not subject to exceptions.
+pub const IMPORTANT_PRIORITY: u32 = EXCEPTION_PRIORITY + 1;
+/// Priority of `$important` exception rules, e.g. `@@||example.com^$important`, which override
+/// `$important` blocking rules as well.
+pub const IMPORTANT_EXCEPTION_PRIORITY: u32 = IMPORTANT_PRIORITY + 1;
+
+/// Rust representation of a single `declarativeNetRequest` rule.
+///
+/// This can be serialized with `serde_json` directly into the format expected by the
+/// `declarative_net_request` manifest key or `updateDynamicRules`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DnrRule {
+    /// Rules converted on their own have an `id` of 0, which must be replaced by a unique positive
+    /// id before use. `FilterSet::into_declarative_net_request` numbers rules from 1.
+    pub id: u32,
+    pub priority: u32,
+    pub action: DnrAction,
+    pub condition: DnrCondition,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DnrAction {
+    #[serde(rename = "type")]
+    pub typ: DnrActionType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<DnrRedirect>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DnrActionType {
+    Block,
+    Allow,
+    /// Allows the matching frame and every request made from it, as `$document` exceptions do.
+    AllowAllRequests,
+    Redirect,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnrRedirect {
+    /// Path of a redirect resource within the extension, starting with `/`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extension_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<DnrUrlTransform>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnrUrlTransform {
+    /// Replaces the whole query string; an empty string removes it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_transform: Option<DnrQueryTransform>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnrQueryTransform {
+    pub remove_params: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnrCondition {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url_filter: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex_filter: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_url_filter_case_sensitive: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initiator_domains: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub excluded_initiator_domains: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_domains: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub excluded_request_domains: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource_types: Option<Vec<DnrResourceType>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_methods: Option<Vec<DnrRequestMethod>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub excluded_request_methods: Option<Vec<DnrRequestMethod>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain_type: Option<DnrDomainType>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnrResourceType {
+    MainFrame,
+    SubFrame,
+    Stylesheet,
+    Script,
+    Image,
+    Font,
+    Object,
+    Xmlhttprequest,
+    Ping,
+    Media,
+    Websocket,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnrRequestMethod {
+    Connect,
+    Delete,
+    Get,
+    Head,
+    Options,
+    Patch,
+    Post,
+    Put,
+}
+
+impl From<RequestMethod> for DnrRequestMethod {
+    fn from(method: RequestMethod) -> Self {
+        match method {
+            RequestMethod::Connect => DnrRequestMethod::Connect,
+            RequestMethod::Delete => DnrRequestMethod::Delete,
+            RequestMethod::Get => DnrRequestMethod::Get,
+            RequestMethod::Head => DnrRequestMethod::Head,
+            RequestMethod::Options => DnrRequestMethod::Options,
+            RequestMethod::Patch => DnrRequestMethod::Patch,
+            RequestMethod::Post => DnrRequestMethod::Post,
+            RequestMethod::Put => DnrRequestMethod::Put,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DnrDomainType {
+    FirstParty,
+    ThirdParty,
+}
+
+/// Network filters that `FilterSet::into_declarative_net_request` could not convert, as their
+/// original rule text along with the reason.
+pub type DnrRuleFailures = Vec<(String, DnrRuleCreationFailure)>;
+
+/// Ways that creating a `declarativeNetRequest` rule from a network filter can fail.
+#[derive(Debug, PartialEq)]
+pub enum DnrRuleCreationFailure {
+    /// Currently, only filter rules parsed in debug mode can be translated into equivalent
+    /// `declarativeNetRequest` rules.
+    NeedsDebugMode,
+    /// Network rules with `$redirect-rule` options only redirect requests blocked by other rules,
+    /// which cannot be represented in `declarativeNetRequest` syntax.
+    NetworkRedirectRuleUnsupported,
+    /// Exceptions for `$redirect` or `$removeparam` rules cannot be represented in
+    /// `declarativeNetRequest` syntax without also allowing the request.
+    NetworkOptionExceptionUnsupported,
+    /// Network rules with generichide, elemhide or specifichide options only affect cosmetic
+    /// filtering.
+    NetworkCosmeticOptionsUnsupported,
+    /// Network rules with genericblock options cannot be supported in `declarativeNetRequest`
+    /// syntax.
+    NetworkGenericblockUnsupported,
+    /// Network rules with badfilter options cannot be supported in `declarativeNetRequest` syntax.
+    NetworkBadFilterUnsupported,
+    /// Network rules with csp options cannot be supported in `declarativeNetRequest` syntax.
+    NetworkCspUnsupported,
+    /// Network rules matching response headers (i.e. `$header`) cannot be supported in
+    /// `declarativeNetRequest` syntax.
+    NetworkHeaderUnsupported,
+    /// `declarativeNetRequest` can only remove query parameters by exact name, so negated or
+    /// regex `$removeparam` values are unsupported.
+    NetworkRemoveparamPatternUnsupported,
+    /// Rules with entities (e.g. google.*) in `$domain` or `$to` options cannot be represented in
+    /// `declarativeNetRequest` syntax.
+    NetworkEntitiesUnsupported,
+    /// A domain in a `$domain`, `$to` or `$denyallow` option could not be converted to punycode.
+    InvalidDomain,
+    /// `declarativeNetRequest` URL filters can only contain ASCII characters.
+    NonAsciiUrlFilterUnsupported,
+    /// `Blocker`-internal `NetworkFilter`s can be represented in optimized form, but these cannot
+    /// be currently converted into `declarativeNetRequest` syntax.
+    OptimizedRulesUnsupported,
+    /// Network rules with tag options are only active when their tag is enabled at runtime, which
+    /// cannot be represented in `declarativeNetRequest` syntax.
+    NetworkTagUnsupported,
+}
+
+impl TryFrom<NetworkFilter> for DnrRule {
+    type Error = DnrRuleCreationFailure;
+
+    fn try_from(v: NetworkFilter) -> Result<Self, Self::Error> {
+        let raw_line = v.raw_line.as_deref().ok_or(DnrRuleCreationFailure::NeedsDebugMode)?;
+
+        if v.mask.contains(NetworkFilterMask::IS_REDIRECT_RULE) {
+            return Err(DnrRuleCreationFailure::NetworkRedirectRuleUnsupported);
+        }
+        if v.is_exception() && (v.is_redirect() || v.is_removeparam()) {
+            return Err(DnrRuleCreationFailure::NetworkOptionExceptionUnsupported);
+        }
+        if v.is_cosmetic_hide_exception() {
+            return Err(DnrRuleCreationFailure::NetworkCosmeticOptionsUnsupported);
+        }
+        if v.is_generic_block() {
+            return Err(DnrRuleCreationFailure::NetworkGenericblockUnsupported);
+        }
+        if v.mask.contains(NetworkFilterMask::BAD_FILTER) {
+            return Err(DnrRuleCreationFailure::NetworkBadFilterUnsupported);
+        }
+        if v.is_csp() {
+            return Err(DnrRuleCreationFailure::NetworkCspUnsupported);
+        }
+        if v.header.is_some() {
+            return Err(DnrRuleCreationFailure::NetworkHeaderUnsupported);
+        }
+        if v.tag.is_some() {
+            return Err(DnrRuleCreationFailure::NetworkTagUnsupported);
+        }
+
+        let (url_filter, regex_filter) = url_filter(&v)?;
+
+        let (initiator_domains, excluded_initiator_domains) = if v.opt_domains.is_some() || v.opt_not_domains.is_some() {
+            option_domains(raw_line, &["domain", "from"])?
+        } else {
+            (None, None)
+        };
+        let (request_domains, excluded_request_domains) = if v.opt_to_domains.is_some() || v.opt_not_to_domains.is_some() {
+            option_domains(raw_line, &["to", "denyallow"])?
+        } else {
+            (None, None)
+        };
+
+        let domain_type = if v.mask.contains(NetworkFilterMask::THIRD_PARTY | NetworkFilterMask::FIRST_PARTY) {
+            None
+        } else if v.mask.contains(NetworkFilterMask::THIRD_PARTY) {
+            Some(DnrDomainType::ThirdParty)
+        } else if v.mask.contains(NetworkFilterMask::FIRST_PARTY) {
+            Some(DnrDomainType::FirstParty)
+        } else {
+            None
+        };
+
+        let methods = |methods: &Option<Vec<RequestMethod>>| methods.as_ref()
+            .map(|methods| methods.iter().map(|method| DnrRequestMethod::from(*method)).collect());
+
+        let (typ, priority, redirect) = if v.is_exception() {
+            let typ = if v.is_document() {
+                DnrActionType::AllowAllRequests
+            } else {
+                DnrActionType::Allow
+            };
+            (typ, EXCEPTION_PRIORITY, None)
+        } else if let Some(resource) = &v.redirect {
+            let redirect = DnrRedirect {
+                extension_path: Some(format!("/{}", resource)),
+                transform: None,
+            };
+            let max = MAX_REDIRECT_PRIORITY as i32;
+            let priority = (REDIRECT_PRIORITY as i32 + v.redirect_priority.max(-max).min(max)) as u32;
+            (DnrActionType::Redirect, priority, Some(redirect))
+        } else if v.is_removeparam() {
+            let transform = match v.removeparam.as_deref() {
+                None => DnrUrlTransform {
+                    query: Some(String::new()),
+                    query_transform: None,
+                },
+                Some(param) if param.starts_with('~') || param.starts_with('/') => {
+                    return Err(DnrRuleCreationFailure::NetworkRemoveparamPatternUnsupported);
+                }
+                Some(param) => DnrUrlTransform {
+                    query: None,
+                    query_transform: Some(DnrQueryTransform { remove_params: vec![param.to_string()] }),
+                },
+            };
+            let redirect = DnrRedirect {
+                extension_path: None,
+                transform: Some(transform),
+            };
+            (DnrActionType::Redirect, BLOCK_PRIORITY, Some(redirect))
+        } else {
+            (DnrActionType::Block, BLOCK_PRIORITY, None)
+        };
+
+        let priority = if v.is_important() && v.is_exception() {
+            IMPORTANT_EXCEPTION_PRIORITY
+        } else if v.is_important() {
+            IMPORTANT_PRIORITY
+        } else {
+            priority
+        };
+
+        Ok(DnrRule {
+            id: 0,
+            priority,
+            action: DnrAction { typ, redirect },
+            condition: DnrCondition {
+                is_url_filter_case_sensitive: if url_filter.is_some() || regex_filter.is_some() {
+                    // Patterns are lowercased during parsing, so they must also be matched
+                    // case-insensitively.
+                    Some(false)
+                } else {
+                    None
+                },
+                url_filter,
+                regex_filter,
+                initiator_domains,
+                excluded_initiator_domains,
+                request_domains,
+                excluded_request_domains,
+                resource_types: resource_types(&v),
+                request_methods: methods(&v.opt_methods),
+                excluded_request_methods: methods(&v.opt_not_methods),
+                domain_type,
+            },
+        })
+    }
+}
+
+/// Builds either a `urlFilter` or a `regexFilter` for the pattern of a network filter.
+/// `declarativeNetRequest` URL filters share the `||`, `|`, `^` and `*` syntax of network filters,
+/// so the pattern only needs to be reassembled from its parsed parts.
+fn url_filter(v: &NetworkFilter) -> Result<(Option<String>, Option<String>), DnrRuleCreationFailure> {
+    let part = match &v.filter {
+        FilterPart::AnyOf(_) => return Err(DnrRuleCreationFailure::OptimizedRulesUnsupported),
+        FilterPart::Simple(part) => part.as_str(),
+        FilterPart::Empty => "",
+    };
+
+    if v.is_complete_regex() {
+        return Ok((None, Some(part[1..part.len() - 1].to_string())));
+    }
+
+    let mut url_filter = if let Some(hostname) = &v.hostname {
+        format!("||{}", hostname)
+    } else if v.mask.contains(NetworkFilterMask::IS_LEFT_ANCHOR) {
+        String::from("|")
+    } else {
+        match (v.mask.contains(NetworkFilterMask::FROM_HTTP), v.mask.contains(NetworkFilterMask::FROM_HTTPS)) {
+            (true, true) => String::new(),
+            (true, false) => String::from("|http://"),
+            (false, true) => String::from("|https://"),
+            // `|ws://` patterns also match secure websockets
+            (false, false) => String::from("|ws"),
+        }
+    };
+
+    if !part.is_empty() && (url_filter.starts_with("|ws") || url_filter.starts_with("|http")) {
+        url_filter.push('*');
+    }
+    url_filter += part;
+
+    if v.mask.contains(NetworkFilterMask::IS_RIGHT_ANCHOR) {
+        if v.hostname.is_some() && part.is_empty() {
+            // `||hostname^` is parsed as a right-anchored hostname
+            url_filter.push('^');
+        } else {
+            url_filter.push('|');
+        }
+    }
+
+    if url_filter.is_empty() {
+        Ok((None, None))
+    } else if !url_filter.is_ascii() {
+        Err(DnrRuleCreationFailure::NonAsciiUrlFilterUnsupported)
+    } else {
+        Ok((Some(url_filter), None))
+    }
+}
+
+/// Reads the domains of the given options from the raw filter, since parsed filters only retain
+/// their hashes. Returns the included and excluded domains.
+#[allow(clippy::type_complexity)]
+fn option_domains(raw_line: &str, options: &[&str]) -> Result<(Option<Vec<String>>, Option<Vec<String>>), DnrRuleCreationFailure> {
+    let mut included = vec![];
+    let mut excluded = vec![];
+
+    let opts = raw_line.rfind('$').map(|i| &raw_line[i + "$".len()..]).unwrap_or("");
+    for opt in opts.split(',') {
+        let (name, value) = match opt.find('=') {
+            Some(i) => (&opt[..i], &opt[i + 1..]),
+            None => continue,
+        };
+        if !options.contains(&name) {
+            continue;
+        }
+        // `$denyallow=a.com` is equivalent to `$to=~a.com`
+        let negated_option = name == "denyallow";
+        for domain in value.split('|') {
+            let (negated, domain) = match domain.strip_prefix('~') {
+                Some(domain) => (true, domain),
+                None => (negated_option, domain),
+            };
+            if domain.ends_with(".*") {
+                return Err(DnrRuleCreationFailure::NetworkEntitiesUnsupported);
+            }
+            let domain = idna::domain_to_ascii(&domain.to_lowercase()).map_err(|_| DnrRuleCreationFailure::InvalidDomain)?;
+            if negated {
+                excluded.push(domain);
+            } else {
+                included.push(domain);
+            }
+        }
+    }
+
+    let non_empty = |domains: Vec<String>| if domains.is_empty() { None } else { Some(domains) };
+    Ok((non_empty(included), non_empty(excluded)))
+}
+
+/// Returns `None` if the filter applies to every resource type that is matched by default, i.e.
+/// everything except top-level documents.
+fn resource_types(v: &NetworkFilter) -> Option<Vec<DnrResourceType>> {
+    // Unlike blocking filters, `$removeparam` filters also apply to top-level documents by default
+    let applies_to_documents = v.is_document() || (v.is_removeparam() && v.mask.contains(NetworkFilterMask::FROM_ANY));
+    if v.mask.contains(NetworkFilterMask::FROM_ANY) && !applies_to_documents {
+        return None;
+    }
+
+    let mut types = vec![];
+    if applies_to_documents {
+        types.push(DnrResourceType::MainFrame);
+    }
+
+    macro_rules! push_if_flag {
+        ($flag:ident, $target:ident) => {
+            if v.mask.contains(NetworkFilterMask::$flag) {
+                types.push(DnrResourceType::$target);
+            }
+        };
+    }
+    push_if_flag!(FROM_SUBDOCUMENT, SubFrame);
+    push_if_flag!(FROM_STYLESHEET, Stylesheet);
+    push_if_flag!(FROM_SCRIPT, Script);
+    push_if_flag!(FROM_IMAGE, Image);
+    push_if_flag!(FROM_FONT, Font);
+    push_if_flag!(FROM_OBJECT, Object);
+    push_if_flag!(FROM_XMLHTTPREQUEST, Xmlhttprequest);
+    push_if_flag!(FROM_PING, Ping);
+    push_if_flag!(FROM_MEDIA, Media);
+    push_if_flag!(FROM_WEBSOCKET, Websocket);
+    push_if_flag!(FROM_OTHER, Other);
+
+    // `$document` exceptions allow the page itself, along with any frame loaded from it
+    if v.is_exception() && v.is_document() && !types.contains(&DnrResourceType::SubFrame) {
+        types.push(DnrResourceType::SubFrame);
+    }
+
+    Some(types)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lists::{parse_filter, FilterFormat, ParsedFilter};
+
+    fn test_from_abp(abp_rule: &str, dnr: &str) {
+        let filter = match parse_filter(abp_rule, true, FilterFormat::Standard) {
+            Ok(ParsedFilter::Network(filter)) => filter,
+            _ => panic!("Rule under test could not be parsed as a network filter"),
+        };
+        assert_eq!(DnrRule::try_from(filter).unwrap(), serde_json::from_str::<DnrRule>(dnr).expect("declarativeNetRequest rule under test could not be deserialized"));
+    }
+
+    fn failure(abp_rule: &str) -> DnrRuleCreationFailure {
+        let filter = match parse_filter(abp_rule, true, FilterFormat::Standard) {
+            Ok(ParsedFilter::Network(filter)) => filter,
+            _ => panic!("Rule under test could not be parsed as a network filter"),
+        };
+        DnrRule::try_from(filter).unwrap_err()
+    }
+
+    #[test]
+    fn url_filters() {
+        test_from_abp("||ads.example.com^", r#"{
+            "id": 0, "priority": 1,
+            "action": { "type": "block" },
+            "condition": { "urlFilter": "||ads.example.com^", "isUrlFilterCaseSensitive": false }
+        }"#);
+        test_from_abp("||cdn.net/ads/*.js|", r#"{
+            "id": 0, "priority": 1,
+            "action": { "type": "block" },
+            "condition": { "urlFilter": "||cdn.net/ads/*.js|", "isUrlFilterCaseSensitive": false }
+        }"#);
+        test_from_abp("|https://track.", r#"{
+            "id": 0, "priority": 1,
+            "action": { "type": "block" },
+            "condition": { "urlFilter": "|https://track.", "isUrlFilterCaseSensitive": false }
+        }"#);
+        test_from_abp("&ad_box_", r#"{
+            "id": 0, "priority": 1,
+            "action": { "type": "block" },
+            "condition": { "urlFilter": "&ad_box_", "isUrlFilterCaseSensitive": false }
+        }"#);
+        test_from_abp("/banner\\d+\\.gif/", r#"{
+            "id": 0, "priority": 1,
+            "action": { "type": "block" },
+            "condition": { "regexFilter": "banner\\d+\\.gif", "isUrlFilterCaseSensitive": false }
+        }"#);
+    }
+
+    #[test]
+    fn options() {
+        test_from_abp("||ads.net^$script,image,third-party,domain=example.com|~sub.example.com", r#"{
+            "id": 0, "priority": 1,
+            "action": { "type": "block" },
+            "condition": {
+                "urlFilter": "||ads.net^",
+                "isUrlFilterCaseSensitive": false,
+                "initiatorDomains": ["example.com"],
+                "excludedInitiatorDomains": ["sub.example.com"],
+                "resourceTypes": ["script", "image"],
+                "domainType": "thirdParty"
+            }
+        }"#);
+        test_from_abp("*$xhr,method=post,domain=example.com,denyallow=api.example.com", r#"{
+            "id": 0, "priority": 1,
+            "action": { "type": "block" },
+            "condition": {
+                "initiatorDomains": ["example.com"],
+                "excludedRequestDomains": ["api.example.com"],
+                "resourceTypes": ["xmlhttprequest"],
+                "requestMethods": ["post"]
+            }
+        }"#);
+        test_from_abp("||ads.net^$important", r#"{
+            "id": 0, "priority": 204,
+            "action": { "type": "block" },
+            "condition": { "urlFilter": "||ads.net^", "isUrlFilterCaseSensitive": false }
+        }"#);
+        test_from_abp("@@||ads.net^$important", r#"{
+            "id": 0, "priority": 205,
+            "action": { "type": "allow" },
+            "condition": { "urlFilter": "||ads.net^", "isUrlFilterCaseSensitive": false }
+        }"#);
+    }
+
+    #[test]
+    fn exceptions() {
+        test_from_abp("@@||ads.net/allowed.js", r#"{
+            "id": 0, "priority": 203,
+            "action": { "type": "allow" },
+            "condition": { "urlFilter": "||ads.net/allowed.js", "isUrlFilterCaseSensitive": false }
+        }"#);
+        test_from_abp("@@||example.com^$document", r#"{
+            "id": 0, "priority": 203,
+            "action": { "type": "allowAllRequests" },
+            "condition": {
+                "urlFilter": "||example.com^",
+                "isUrlFilterCaseSensitive": false,
+                "resourceTypes": ["main_frame", "sub_frame"]
+            }
+        }"#);
+    }
+
+    #[test]
+    fn redirects_and_removeparam() {
+        test_from_abp("||cdn.net/analytics.js$script,redirect=noop.js", r#"{
+            "id": 0, "priority": 102,
+            "action": { "type": "redirect", "redirect": { "extensionPath": "/noop.js" } },
+            "condition": {
+                "urlFilter": "||cdn.net/analytics.js",
+                "isUrlFilterCaseSensitive": false,
+                "resourceTypes": ["script"]
+            }
+        }"#);
+        test_from_abp("||cdn.net/analytics.js$script,redirect=noop.js:10", r#"{
+            "id": 0, "priority": 112,
+            "action": { "type": "redirect", "redirect": { "extensionPath": "/noop.js" } },
+            "condition": {
+                "urlFilter": "||cdn.net/analytics.js",
+                "isUrlFilterCaseSensitive": false,
+                "resourceTypes": ["script"]
+            }
+        }"#);
+        let priority = |rule: &str| match parse_filter(rule, true, FilterFormat::Standard) {
+            Ok(ParsedFilter::Network(filter)) => DnrRule::try_from(filter).unwrap().priority,
+            _ => unreachable!(),
+        };
+        assert_eq!(priority("||cdn.net^$redirect=noop.js:-5"), REDIRECT_PRIORITY - 5);
+        assert_eq!(priority("||cdn.net^$redirect=noop.js:1000"), EXCEPTION_PRIORITY - 1);
+        assert_eq!(priority("||cdn.net^$redirect=noop.js:-1000"), BLOCK_PRIORITY + 1);
+        test_from_abp("$removeparam=utm_source", r#"{
+            "id": 0, "priority": 1,
+            "action": { "type": "redirect", "redirect": { "transform": { "queryTransform": { "removeParams": ["utm_source"] } } } },
+            "condition": {
+                "resourceTypes": ["main_frame", "sub_frame", "stylesheet", "script", "image", "font", "object", "xmlhttprequest", "ping", "media", "websocket", "other"]
+            }
+        }"#);
+        test_from_abp("||tracker.net^$removeparam,xhr", r#"{
+            "id": 0, "priority": 1,
+            "action": { "type": "redirect", "redirect": { "transform": { "query": "" } } },
+            "condition": {
+                "urlFilter": "||tracker.net^",
+                "isUrlFilterCaseSensitive": false,
+                "resourceTypes": ["xmlhttprequest"]
+            }
+        }"#);
+    }
+
+    #[test]
+    fn failures() {
+        assert_eq!(DnrRule::try_from(NetworkFilter::parse("||ads.net^", false).unwrap()), Err(DnrRuleCreationFailure::NeedsDebugMode));
+        assert_eq!(failure("||cdn.net/lib.js$redirect-rule=noop.js"), DnrRuleCreationFailure::NetworkRedirectRuleUnsupported);
+        assert_eq!(failure("@@||cdn.net^$redirect=noop.js"), DnrRuleCreationFailure::NetworkOptionExceptionUnsupported);
+        assert_eq!(failure("@@||example.com^$generichide"), DnrRuleCreationFailure::NetworkCosmeticOptionsUnsupported);
+        assert_eq!(failure("||example.com^$csp=script-src 'none'"), DnrRuleCreationFailure::NetworkCspUnsupported);
+        assert_eq!(failure("$removeparam=/^utm_/"), DnrRuleCreationFailure::NetworkRemoveparamPatternUnsupported);
+        assert_eq!(failure("||ads.net^$domain=google.*"), DnrRuleCreationFailure::NetworkEntitiesUnsupported);
+        assert_eq!(failure("/реклама.gif"), DnrRuleCreationFailure::NonAsciiUrlFilterUnsupported);
+        assert_eq!(failure("||ads.net^$tag=stuff"), DnrRuleCreationFailure::NetworkTagUnsupported);
+    }
+
+    #[test]
+    fn filter_set() {
+        let mut filter_set = crate::lists::FilterSet::new(true);
+        filter_set.add_filters(&[
+            "||ads.net^".to_string(),
+            "##.ad".to_string(),
+            "@@||example.com^$generichide".to_string(),
+            "||cdn.net/analytics.js$script,redirect=noop.js".to_string(),
+        ], FilterFormat::Standard);
+
+        let (rules, filters_used, failures) = filter_set.into_declarative_net_request("web_accessible_resources/").unwrap();
+        assert_eq!(filters_used, vec!["||ads.net^", "||cdn.net/analytics.js$script,redirect=noop.js"]);
+        assert_eq!(rules.iter().map(|rule| rule.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(rules[1].action.redirect.as_ref().unwrap().extension_path.as_deref(), Some("/web_accessible_resources/noop.js"));
+        assert_eq!(failures, vec![("@@||example.com^$generichide".to_string(), DnrRuleCreationFailure::NetworkCosmeticOptionsUnsupported)]);
+
+        // Rules disabled by `$badfilter` are not converted
+        let mut filter_set = crate::lists::FilterSet::new(true);
+        filter_set.add_filters(&[
+            "||ads.net^".to_string(),
+            "||tracker.net^".to_string(),
+            "||tracker.net^$badfilter".to_string(),
+        ], FilterFormat::Standard);
+        let (rules, filters_used, failures) = filter_set.into_declarative_net_request("/").unwrap();
+        assert_eq!(filters_used, vec!["||ads.net^"]);
+        assert_eq!(rules.len(), 1);
+        assert!(failures.is_empty());
+
+        assert_eq!(crate::lists::FilterSet::new(false).into_declarative_net_request("/").unwrap_err(), DnrRuleCreationFailure::NeedsDebugMode);
+    }
+}
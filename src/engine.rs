@@ -1,15 +1,39 @@
-use crate::blocker::{Blocker, BlockerError, BlockerOptions, BlockerResult};
+use crate::allowlist::Allowlist;
+use crate::blocker::{Blocker, BlockerDecision, BlockerError, BlockerOptions, BlockerResult, BlockerTrace};
 use crate::cosmetic_filter_cache::{CosmeticFilterCache, UrlSpecificResources};
 use crate::lists::{FilterFormat, FilterSet};
-use crate::request::Request;
+use crate::request::{Request, RequestSource};
 use crate::resources::{Resource, RedirectResource};
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 /// Main adblocking engine that allows efficient querying of resources to block.
 pub struct Engine {
     pub blocker: Blocker,
-    cosmetic_cache: CosmeticFilterCache,
+    pub(crate) cosmetic_cache: CosmeticFilterCache,
+    allowlist: Allowlist,
+    /// For rules that `update_filter_list` found already present when adding them, e.g. because
+    /// another list contains them too, the number of additional lists that own them.
+    shared_rules: HashMap<String, usize>,
+}
+
+/// The result of evaluating a page's document URL with [`Engine::check_page`] or
+/// [`Engine::check_frame`]. It should be kept for as long as the page is loaded, and passed to
+/// [`Engine::check_network_urls_on_page`] and [`Engine::url_cosmetic_resources_on_page`] so that
+/// the document URL is not evaluated again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageCheck {
+    /// The URL of the page's document.
+    pub url: String,
+    /// The allowlist entry or `$document` exception that allowlists the whole page, if any.
+    /// Exceptions are only non-empty strings if the engine was created with debugging enabled.
+    pub allowlisted_by: Option<String>,
+}
+
+impl PageCheck {
+    pub fn is_allowlisted(&self) -> bool {
+        self.allowlisted_by.is_some()
+    }
 }
 
 impl Default for Engine {
@@ -32,6 +56,8 @@ impl Engine {
         Self {
             blocker: Blocker::new(vec![], &blocker_options),
             cosmetic_cache: CosmeticFilterCache::new(),
+            allowlist: Allowlist::new(),
+            shared_rules: HashMap::new(),
         }
     }
 
@@ -65,6 +91,8 @@ impl Engine {
         Self {
             blocker: Blocker::new(network_filters, &blocker_options),
             cosmetic_cache: CosmeticFilterCache::from_rules(cosmetic_filters),
+            allowlist: Allowlist::new(),
+            shared_rules: HashMap::new(),
         }
     }
 
@@ -72,44 +100,157 @@ impl Engine {
     pub fn serialize(&self) -> Result<Vec<u8>, BlockerError> {
         use crate::data_format::SerializeFormat;
 
-        let serialize_format = SerializeFormat::from((&self.blocker, &self.cosmetic_cache));
+        let serialize_format = SerializeFormat::from((&self.blocker, &self.cosmetic_cache, &self.allowlist));
 
         serialize_format.serialize().map_err(|_e| {
             BlockerError::SerializationError
         })
     }
 
-    /// Deserialize the `Engine` from the binary format generated by `Engine::serialize`.
+    /// Deserialize the `Engine` from the binary format generated by `Engine::serialize`. The
+    /// allowlist is replaced by the serialized one, even if that one is empty. Data serialized by
+    /// versions without an allowlist keeps the current allowlist.
     pub fn deserialize(&mut self, serialized: &[u8]) -> Result<(), BlockerError> {
         use crate::data_format::DeserializeFormat;
         let current_tags = self.blocker.tags_enabled();
         let deserialize_format = DeserializeFormat::deserialize(serialized).map_err(|_e| {
             BlockerError::DeserializationError
         })?;
-        let (blocker, cosmetic_cache) = deserialize_format.into();
+        let (blocker, cosmetic_cache, allowlist) = deserialize_format.into();
         self.blocker = blocker;
         self.blocker.use_tags(&current_tags.iter().map(|s| &**s).collect::<Vec<_>>());
         self.cosmetic_cache = cosmetic_cache;
+        if let Some(allowlist) = allowlist {
+            self.allowlist = allowlist;
+        }
         Ok(())
     }
 
     /// Check if a request for a network resource from `url`, of type `request_type`, initiated by
-    /// `source_url`, should be blocked.
+    /// `source_url`, should be blocked. Nothing is blocked on sites trusted by the allowlist.
     pub fn check_network_urls(&self, url: &str, source_url: &str, request_type: &str) -> BlockerResult {
         Request::from_urls(&url, &source_url, &request_type)
         .map(|request| {
-            self.blocker.check(&request)
+            self.check_request(&request)
         })
-        .unwrap_or_else(|_e| {
-            BlockerResult {
-                matched: false,
-                important: false,
-                redirect: None,
-                exception: None,
+        .unwrap_or_else(|_e| BlockerResult::request_parse_error())
+    }
+
+    /// Like `check_network_urls`, for many requests initiated by the same `source_url`. Each
+    /// request is given as a `(url, request_type)` pair, and its result is at the same position in
+    /// the returned `Vec`. The source URL is only parsed once, which is faster than checking each
+    /// request separately.
+    pub fn check_network_urls_batch(&self, source_url: &str, requests: &[(&str, &str)]) -> Vec<BlockerResult> {
+        let source = RequestSource::parse(source_url);
+        let requests: Vec<_> = requests.iter()
+            .map(|(url, request_type)| Request::from_url_with_source(url, &source, request_type))
+            .collect();
+
+        let mut results = self.check_request_batch(requests.iter().filter_map(|request| request.as_ref().ok())).into_iter();
+
+        requests.iter().map(|request| match request {
+            Ok(_) => results.next().expect("one result per checked request"),
+            Err(_) => BlockerResult::request_parse_error(),
+        }).collect()
+    }
+
+    /// Like `check_request`, for many requests at once. Consecutive requests with the same source
+    /// share the evaluation of `$genericblock` exceptions, so requests from a page are best
+    /// checked together, e.g. after building them with `Request::from_url_with_source`.
+    pub fn check_request_batch<'a, I: IntoIterator<Item = &'a Request>>(&self, requests: I) -> Vec<BlockerResult> {
+        let requests: Vec<_> = requests.into_iter()
+            .map(|request| (request, self.is_trusted_site(request)))
+            .collect();
+
+        let mut results = self.blocker.check_batch(requests.iter()
+            .filter(|(_, trusted)| !trusted)
+            .map(|(request, _)| *request))
+            .into_iter();
+
+        requests.iter().map(|(_, trusted)| if *trusted {
+            BlockerResult::default()
+        } else {
+            results.next().expect("one result per checked request")
+        }).collect()
+    }
+
+    /// Evaluates the document URL of a page or frame, to find whether the page is allowlisted,
+    /// either by the engine's allowlist or with a `$document` exception, e.g.
+    /// `@@||example.com^$document`. On an allowlisted page, nothing is blocked and no cosmetic
+    /// filtering is applied, even by `$important` filters.
+    ///
+    /// A top-level page is its own source, so `$document` exceptions restricted with `$domain` or
+    /// to first-party requests apply to it. Use `check_frame` for documents loaded in frames.
+    pub fn check_page(&self, document_url: &str) -> PageCheck {
+        self.check_document(document_url, document_url)
+    }
+
+    /// Like `check_page`, for a document loaded in a frame of the `parent` page. Frames of an
+    /// allowlisted page are allowlisted as well. Otherwise, the `$domain` and party options of
+    /// `$document` exceptions are matched against the parent page.
+    pub fn check_frame(&self, document_url: &str, parent: &PageCheck) -> PageCheck {
+        match &parent.allowlisted_by {
+            Some(entry) => PageCheck {
+                url: document_url.to_string(),
+                allowlisted_by: Some(entry.clone()),
+            },
+            None => self.check_document(document_url, &parent.url),
+        }
+    }
+
+    fn check_document(&self, document_url: &str, parent_url: &str) -> PageCheck {
+        let allowlisted_by = Request::from_urls(document_url, parent_url, "document")
+            .ok()
+            .and_then(|request| {
+                self.allowlist.matching_entry(&request.hostname).map(|entry| entry.to_string())
+                    .or_else(|| self.blocker.check_document_allowlist(&request).map(|filter| filter.to_string()))
+            });
+
+        PageCheck {
+            url: document_url.to_string(),
+            allowlisted_by,
+        }
+    }
+
+    /// Like `check_network_urls`, for a request made by a page that was evaluated with
+    /// `check_page`. Requests from allowlisted pages are never blocked, and are not checked
+    /// against any other filter.
+    pub fn check_network_urls_on_page(&self, page: &PageCheck, url: &str, request_type: &str) -> BlockerResult {
+        match &page.allowlisted_by {
+            Some(exception) => BlockerResult {
+                exception: Some(exception.clone()),
+                ..BlockerResult::default()
+            },
+            None => self.check_network_urls(url, &page.url, request_type),
+        }
+    }
+
+    /// Check if the response to `request` should be blocked based on its response `headers`,
+    /// given as `(name, value)` pairs. Only filters with the `$header` option are considered.
+    pub fn check_response_headers(&self, request: &Request, headers: &[(&str, &str)]) -> BlockerResult {
+        if self.is_trusted_site(request) {
+            return BlockerResult::default();
+        }
+        self.blocker.check_response_headers(request, headers)
+    }
+
+    /// Evaluate `request` the same way as the `check_network_urls*` methods, and report every
+    /// list of filters that was visited, every filter that matched, and which of them decided the
+    /// result. Useful for understanding why a request was blocked, even if the engine was not
+    /// created in debug mode. Requests from sites trusted by the allowlist are not checked
+    /// against any filter, and the trace names the allowlist entry instead.
+    pub fn explain(&self, request: &Request) -> BlockerTrace {
+        if let Some(entry) = self.trusted_site_entry(request) {
+            return BlockerTrace {
+                steps: vec![],
+                decision: BlockerDecision::Allowlisted,
                 filter: None,
-                error: Some("Error parsing request".to_owned())
-            }
-        })
+                exception: None,
+                result: BlockerResult::default(),
+                allowlisted_by: Some(entry.to_string()),
+            };
+        }
+        self.blocker.explain(request)
     }
 
     pub fn check_network_urls_with_hostnames(
@@ -121,7 +262,7 @@ impl Engine {
         third_party_request: Option<bool>
     ) -> BlockerResult {
         let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
-        self.blocker.check(&request)
+        self.check_request(&request)
     }
 
     pub fn check_network_urls_with_hostnames_subset(
@@ -135,7 +276,51 @@ impl Engine {
         force_check_exceptions: bool,
     ) -> BlockerResult {
         let request = Request::from_urls_with_hostname(url, hostname, source_hostname, request_type, third_party_request);
-        self.blocker.check_parameterised(&request, previously_matched_rule, force_check_exceptions)
+        self.check_request_subset(&request, previously_matched_rule, force_check_exceptions)
+    }
+
+    /// Like `check_network_urls_with_hostnames_subset`, for a `request` that was already built,
+    /// e.g. with an HTTP method set by `Request::with_method`.
+    pub fn check_request_subset(&self, request: &Request, previously_matched_rule: bool, force_check_exceptions: bool) -> BlockerResult {
+        if self.is_trusted_site(request) {
+            return BlockerResult::default();
+        }
+        self.blocker.check_parameterised(request, previously_matched_rule, force_check_exceptions)
+    }
+
+    /// Like `check_network_urls`, for a `request` that was already built. This allows checking
+    /// requests with extra details, like the HTTP method set by `Request::with_method`, which is
+    /// matched by filters using the `$method` option.
+    pub fn check_request(&self, request: &Request) -> BlockerResult {
+        if self.is_trusted_site(request) {
+            return BlockerResult::default();
+        }
+        self.blocker.check(request)
+    }
+
+    /// Whether the site that made the request, or the requested document for top-level requests,
+    /// is trusted by the allowlist.
+    pub(crate) fn is_trusted_site(&self, request: &Request) -> bool {
+        self.trusted_site_entry(request).is_some()
+    }
+
+    fn trusted_site_entry(&self, request: &Request) -> Option<&str> {
+        let site = if request.source_hostname.is_empty() {
+            &request.hostname
+        } else {
+            &request.source_hostname
+        };
+        self.allowlist.matching_entry(site)
+    }
+
+    /// Returns the merged `Content-Security-Policy` header value to be injected into a document
+    /// loaded from `url`, of type `request_type` (usually `"main_frame"` or `"sub_frame"`),
+    /// initiated by `source_url`. Returns `None` if no `$csp` filters apply.
+    pub fn get_csp_directives(&self, url: &str, source_url: &str, request_type: &str) -> Option<String> {
+        Request::from_urls(url, source_url, request_type)
+            .ok()
+            .filter(|request| !self.is_trusted_site(request))
+            .and_then(|request| self.blocker.get_csp_directives(&request))
     }
 
     /// Check if a given filter has been previously added to this `Engine`.
@@ -154,6 +339,137 @@ impl Engine {
         }
     }
 
+    /// Removes a single network or cosmetic filter from the engine, given in the same syntax it
+    /// was added with. Network filters that were fused together by the optimizer can be removed
+    /// individually.
+    ///
+    /// Fails if the filter can't be parsed or found, or if it can't be separated from the filters
+    /// it was fused with, in which case the engine is left unchanged.
+    pub fn remove_filter(&mut self, filter: &str) -> Result<(), BlockerError> {
+        use crate::lists::{parse_filter, ParsedFilter};
+
+        // Parsed in debug mode so that the rule can be found among the raw lines of fused filters
+        match parse_filter(filter, true, FilterFormat::Standard) {
+            Ok(ParsedFilter::Network(filter)) => self.blocker.remove_filter(&filter),
+            Ok(ParsedFilter::Cosmetic(filter)) => {
+                if self.cosmetic_cache.remove_filter(&filter) {
+                    Ok(())
+                } else {
+                    Err(BlockerError::FilterNotFound)
+                }
+            }
+            Err(_) => Err(BlockerError::UnparseableFilter),
+        }
+    }
+
+    /// Updates the engine from one version of a filter list to another, by removing the rules
+    /// that are no longer present and adding the new ones, rather than rebuilding the whole
+    /// engine. Enabled tags are kept.
+    ///
+    /// To apply a diff update, use `FilterListDiff::apply` or `lists::apply_rcs_diff` to get the
+    /// new version of the list first.
+    ///
+    /// `$badfilter` rules are resolved against the other rules of the same list. Rules from other
+    /// lists are not enabled or disabled when the list's `$badfilter` rules change; rebuild the
+    /// engine if that is needed.
+    ///
+    /// A rule that is already present when the update adds it, e.g. because another list
+    /// contains it too, is counted rather than added again, and is only removed once every list
+    /// that added it has dropped it. These counts are not serialized.
+    ///
+    /// If a rule can't be removed, an error is returned and the engine is left unchanged.
+    pub fn update_filter_list(&mut self, old_list: &str, new_list: &str, format: FilterFormat) -> Result<(), BlockerError> {
+        use crate::lists::{parse_active_filters, ParsedFilter};
+
+        let old_filters = parse_active_filters(old_list, format);
+        let new_filters = parse_active_filters(new_list, format);
+
+        let old_rules: HashSet<&str> = old_filters.iter().map(|(rule, _)| rule.as_str()).collect();
+        let new_rules: HashSet<&str> = new_filters.iter().map(|(rule, _)| rule.as_str()).collect();
+
+        // Rules that are still owned by another list only lose an owner
+        let (disowned, removed): (Vec<_>, Vec<_>) = old_filters.iter()
+            .filter(|(rule, _)| !new_rules.contains(rule.as_str()))
+            .partition(|(rule, _)| self.shared_rules.contains_key(rule));
+
+        let mut done = Vec::with_capacity(removed.len());
+        for (_, filter) in removed {
+            let result = match filter {
+                ParsedFilter::Network(filter) => self.blocker.remove_filter(filter),
+                ParsedFilter::Cosmetic(filter) => {
+                    if self.cosmetic_cache.remove_filter(filter) {
+                        Ok(())
+                    } else {
+                        Err(BlockerError::FilterNotFound)
+                    }
+                }
+            };
+            match result {
+                Ok(()) => done.push(filter),
+                // The rule may have been disabled by a `$badfilter` from another list
+                Err(BlockerError::FilterNotFound) => (),
+                Err(e) => {
+                    // Put back the rules removed so far, so that the engine is left unchanged
+                    for filter in done {
+                        match filter {
+                            ParsedFilter::Network(filter) => { let _ = self.blocker.add_filter(filter.clone()); }
+                            ParsedFilter::Cosmetic(filter) => self.cosmetic_cache.add_filter(filter.clone()),
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        for (rule, _) in disowned {
+            if let Some(owners) = self.shared_rules.get_mut(rule) {
+                *owners -= 1;
+                if *owners == 0 {
+                    self.shared_rules.remove(rule);
+                }
+            }
+        }
+
+        for (rule, filter) in new_filters.into_iter().filter(|(rule, _)| !old_rules.contains(rule.as_str())) {
+            let exists = match &filter {
+                ParsedFilter::Network(filter) => self.blocker.filter_exists(filter),
+                ParsedFilter::Cosmetic(filter) => self.cosmetic_cache.filter_exists(filter),
+            };
+            if exists {
+                *self.shared_rules.entry(rule).or_insert(0) += 1;
+                continue;
+            }
+            match filter {
+                // Neither `$badfilter` rules nor existing rules reach this point
+                ParsedFilter::Network(filter) => { let _ = self.blocker.add_filter(filter); }
+                ParsedFilter::Cosmetic(filter) => self.cosmetic_cache.add_filter(filter),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns a snapshot of the statistics collected while matching requests and pages, which
+    /// can be exported as JSON with `EngineStatistics::to_json`. Calls to `explain` are counted
+    /// as well.
+    ///
+    /// Statistics are not serialized, and those of `$tag` filters are reset whenever the enabled
+    /// tags change.
+    #[cfg(feature = "metrics")]
+    pub fn statistics(&self) -> crate::statistics::EngineStatistics {
+        crate::statistics::EngineStatistics {
+            network: self.blocker.statistics(),
+            cosmetic_hits: self.cosmetic_cache.statistics.snapshot(),
+        }
+    }
+
+    /// Resets all statistics collected so far.
+    #[cfg(feature = "metrics")]
+    pub fn reset_statistics(&self) {
+        self.blocker.reset_statistics();
+        self.cosmetic_cache.statistics.reset();
+    }
+
     /// Sets this engine's tags to be _only_ the ones provided in `tags`.
     ///
     /// Tags can be used to cheaply enable or disable network rules with a corresponding `$tag`
@@ -219,7 +535,9 @@ impl Engine {
     /// Returns a set of cosmetic filter resources required for a particular url. Once this has
     /// been called, all CSS ids and classes on a page should be passed to
     /// `hidden_class_id_selectors` to obtain any stylesheets consisting of generic rules (if the
-    /// returned `generichide` value is false).
+    /// returned `generichide` value is false). Hostname-specific rules are left out if the page
+    /// has a `$specifichide` or `$elemhide` exception. No resources are returned for sites trusted
+    /// by the allowlist.
     pub fn url_cosmetic_resources(&self, url: &str) -> UrlSpecificResources {
         let request = Request::from_url(url);
         if request.is_err() {
@@ -227,8 +545,44 @@ impl Engine {
         }
         let request = request.unwrap();
 
-        let generichide = self.blocker.check_generic_hide(&request);
-        self.cosmetic_cache.hostname_cosmetic_resources(&request.hostname, generichide)
+        if self.is_trusted_site(&request) {
+            return Self::allowlisted_cosmetic_resources();
+        }
+
+        let (generichide, specifichide) = self.blocker.check_cosmetic_hide(&request);
+        self.cosmetic_cache.hostname_cosmetic_resources(&request.hostname, generichide, specifichide)
+    }
+
+    /// Like `url_cosmetic_resources`, for a page that was evaluated with `check_page`. No cosmetic
+    /// filtering applies to allowlisted pages, so no resources are returned for them and
+    /// `elemhide` is set.
+    pub fn url_cosmetic_resources_on_page(&self, page: &PageCheck) -> UrlSpecificResources {
+        if page.is_allowlisted() {
+            return Self::allowlisted_cosmetic_resources();
+        }
+        self.url_cosmetic_resources(&page.url)
+    }
+
+    pub(crate) fn allowlisted_cosmetic_resources() -> UrlSpecificResources {
+        UrlSpecificResources {
+            generichide: true,
+            specifichide: true,
+            elemhide: true,
+            ..UrlSpecificResources::empty()
+        }
+    }
+
+    // Allowlist functionality
+
+    /// Sites on which nothing is blocked and no cosmetic filtering is applied, regardless of the
+    /// filters in use.
+    pub fn allowlist(&self) -> &Allowlist {
+        &self.allowlist
+    }
+
+    /// Edit the allowlist. Changes take effect immediately, without rebuilding any filters.
+    pub fn allowlist_mut(&mut self) -> &mut Allowlist {
+        &mut self.allowlist
     }
 }
 
@@ -556,6 +910,211 @@ mod tests {
         });
     }
 
+    #[test]
+    fn document_allowlist() {
+        let filters = vec![
+            String::from("||ads.net^"),
+            String::from("||important.net^$important"),
+            String::from("##.ad"),
+            String::from("example.com##.specific"),
+            String::from("@@||example.com^$document"),
+            String::from("@@||example.org^"),
+        ];
+        let engine = Engine::from_rules_debug(&filters, FilterFormat::Standard);
+
+        let page = engine.check_page("https://sub.example.com/page.html");
+        assert!(page.is_allowlisted());
+        assert_eq!(page.allowlisted_by.as_deref(), Some("@@||example.com^$document"));
+        for url in &["https://ads.net/ad.js", "https://important.net/ad.js"] {
+            let result = engine.check_network_urls_on_page(&page, url, "script");
+            assert!(!result.matched);
+            assert_eq!(result.exception, page.allowlisted_by);
+        }
+        let resources = engine.url_cosmetic_resources_on_page(&page);
+        assert!(resources.hide_selectors.is_empty());
+        assert!(resources.elemhide);
+
+        // An exception without `$document` only applies to the document request itself
+        let page = engine.check_page("https://example.org/");
+        assert!(!page.is_allowlisted());
+        assert!(engine.check_network_urls_on_page(&page, "https://ads.net/ad.js", "script").matched);
+        assert!(!engine.url_cosmetic_resources_on_page(&page).elemhide);
+
+        let page = engine.check_page("https://example.net/");
+        assert!(!page.is_allowlisted());
+        let result = engine.check_network_urls_on_page(&page, "https://ads.net/ad.js", "script");
+        assert!(result.matched);
+        assert_eq!(result.filter.as_deref(), Some("||ads.net^"));
+    }
+
+    #[test]
+    fn document_allowlist_frames() {
+        let filters = vec![
+            String::from("@@||first-party.com^$document,1p"),
+            String::from("@@||third-party.com^$document,3p"),
+            String::from("@@||widget.com^$document,domain=host.com"),
+        ];
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+
+        let other = engine.check_page("https://other.com/");
+        let host = engine.check_page("https://host.com/");
+
+        assert!(engine.check_page("https://first-party.com/").is_allowlisted());
+        assert!(!engine.check_frame("https://first-party.com/frame", &other).is_allowlisted());
+
+        assert!(!engine.check_page("https://third-party.com/").is_allowlisted());
+        assert!(engine.check_frame("https://third-party.com/frame", &other).is_allowlisted());
+
+        assert!(engine.check_frame("https://widget.com/frame", &host).is_allowlisted());
+        assert!(!engine.check_frame("https://widget.com/frame", &other).is_allowlisted());
+        assert!(!engine.check_page("https://widget.com/").is_allowlisted());
+    }
+
+    #[test]
+    fn document_allowlist_inherited_by_frames() {
+        let filters = vec![
+            String::from("@@||example.com^$document"),
+            String::from("||ads.net^"),
+        ];
+        let mut engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        engine.allowlist_mut().add("trusted.org").unwrap();
+
+        let page = engine.check_page("https://example.com/");
+        let frame = engine.check_frame("https://ads.net/frame", &page);
+        assert_eq!(frame.url, "https://ads.net/frame");
+        assert_eq!(frame.allowlisted_by, page.allowlisted_by);
+        assert!(!engine.check_network_urls_on_page(&frame, "https://ads.net/ad.js", "script").matched);
+
+        // Nested frames inherit it as well
+        let nested = engine.check_frame("https://other.com/frame", &frame);
+        assert!(nested.is_allowlisted());
+
+        let page = engine.check_page("https://trusted.org/");
+        let frame = engine.check_frame("https://ads.net/frame", &page);
+        assert_eq!(frame.allowlisted_by.as_deref(), Some("trusted.org"));
+
+        let page = engine.check_page("https://other.com/");
+        let frame = engine.check_frame("https://ads.net/frame", &page);
+        assert!(!frame.is_allowlisted());
+        assert!(engine.check_network_urls_on_page(&frame, "https://ads.net/ad.js", "script").matched);
+    }
+
+    #[test]
+    fn check_network_urls_batch() {
+        let filters = vec![
+            String::from("||ads.net^"),
+            String::from("@@||ads.net/allowed.js"),
+            String::from("||tracker.org^$third-party"),
+            String::from("/generic-ad."),
+            String::from("@@||example.com^$genericblock"),
+        ];
+        let mut engine = Engine::from_rules_debug(&filters, FilterFormat::Standard);
+
+        let requests = [
+            ("https://ads.net/ad.js", "script"),
+            ("https://ads.net/allowed.js", "script"),
+            ("not a url", "script"),
+            ("https://tracker.org/t.gif", "image"),
+            ("https://cdn.net/generic-ad.png", "image"),
+            ("https://example.net/", "xhr"),
+        ];
+        for source_url in &["https://www.example.com/", "https://tracker.org/", "", "not a url"] {
+            let results = engine.check_network_urls_batch(source_url, &requests);
+            assert_eq!(results.len(), requests.len());
+            for ((url, request_type), result) in requests.iter().zip(results) {
+                let expected = engine.check_network_urls(url, source_url, request_type);
+                assert_eq!(result.matched, expected.matched, "{} from {}", url, source_url);
+                assert_eq!(result.filter, expected.filter);
+                assert_eq!(result.exception, expected.exception);
+                assert_eq!(result.error, expected.error);
+            }
+        }
+
+        engine.allowlist_mut().add("example.com").unwrap();
+        let results = engine.check_network_urls_batch("https://example.com/", &requests);
+        assert!(results.iter().all(|result| !result.matched));
+        assert!(results[2].error.is_some());
+    }
+
+    #[test]
+    fn allowlist() {
+        let filters = vec![
+            String::from("||ads.net^"),
+            String::from("||important.net^$important"),
+            String::from("||tracker.org^"),
+            String::from("example.com##.ad"),
+        ];
+        let mut engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        engine.allowlist_mut().add("example.com").unwrap();
+        engine.allowlist_mut().add("*.trusted.*").unwrap();
+
+        assert!(!engine.check_network_urls("https://ads.net/ad.js", "https://sub.example.com/", "script").matched);
+        assert!(!engine.check_network_urls("https://important.net/ad.js", "https://www.trusted.org/", "script").matched);
+        assert!(!engine.check_network_urls_with_hostnames("https://ads.net/ad.js", "ads.net", "example.com", "script", None).matched);
+        assert!(engine.check_network_urls("https://ads.net/ad.js", "https://trusted.org/", "script").matched);
+        assert!(engine.check_network_urls("https://ads.net/ad.js", "https://example.net/", "script").matched);
+        // Requests without a source are checked against their own hostname
+        assert!(engine.check_network_urls("https://tracker.org/track.js", "", "script").matched);
+        engine.allowlist_mut().add("tracker.org").unwrap();
+        assert!(!engine.check_network_urls("https://tracker.org/track.js", "", "script").matched);
+        assert!(engine.check_network_urls("https://ads.net/ad.js", "https://example.net/", "script").matched);
+
+        let resources = engine.url_cosmetic_resources("https://example.com/");
+        assert!(resources.hide_selectors.is_empty());
+        assert!(resources.elemhide);
+        assert_eq!(engine.check_page("https://www.example.com/").allowlisted_by.as_deref(), Some("example.com"));
+
+        // The allowlist is kept through serialization
+        let serialized = engine.serialize().unwrap();
+        let mut deserialized = Engine::default();
+        deserialized.deserialize(&serialized).unwrap();
+        assert_eq!(deserialized.allowlist(), engine.allowlist());
+
+        // An empty serialized allowlist replaces the current one
+        let serialized = Engine::from_rules(&filters, FilterFormat::Standard).serialize().unwrap();
+        deserialized.deserialize(&serialized).unwrap();
+        assert!(deserialized.allowlist().is_empty());
+
+        engine.allowlist_mut().remove("example.com");
+        assert!(engine.check_network_urls("https://ads.net/ad.js", "https://sub.example.com/", "script").matched);
+        assert_eq!(engine.url_cosmetic_resources("https://example.com/").hide_selectors.len(), 1);
+    }
+
+    #[test]
+    fn elemhide_specifichide() {
+        let filters = vec![
+            String::from("##a[href=\"generic.com\"]"),
+
+            String::from("@@||example.com$specifichide"),
+            String::from("example.com##.specific"),
+            String::from("example.com##.styled:style(color: red)"),
+
+            String::from("@@||example2.com$ehide"),
+            String::from("example2.com##.specific"),
+
+            String::from("@@||example3.com$shide"),
+            String::from("@@||example3.com$ghide"),
+            String::from("example3.com##.specific"),
+        ];
+        let url_results = vec![
+            ("https://example.com", vec!["a[href=\"generic.com\"]"], false, true, false),
+            ("https://example2.com", vec![], true, true, true),
+            ("https://example3.com", vec![], true, true, true),
+            ("https://example4.com", vec!["a[href=\"generic.com\"]"], false, false, false),
+        ];
+
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+
+        url_results.into_iter().for_each(|(url, expected_result, expected_generichide, expected_specifichide, expected_elemhide)| {
+            let result = engine.url_cosmetic_resources(url);
+            assert_eq!(result.hide_selectors, expected_result.iter().map(|s| s.to_string()).collect::<HashSet<_>>(), "{}", url);
+            assert!(result.style_selectors.is_empty());
+            assert_eq!(result.generichide, expected_generichide);
+            assert_eq!(result.specifichide, expected_specifichide);
+            assert_eq!(result.elemhide, expected_elemhide);
+        });
+    }
+
     #[test]
     fn important_redirect() {
         let mut filter_set = FilterSet::new(true);
@@ -576,4 +1135,345 @@ mod tests {
 
         assert!(result.redirect.is_some());
     }
+
+    #[test]
+    fn csp_directives() {
+        let filters = vec![
+            String::from("||example.com^$csp=script-src 'self' * 'unsafe-inline'"),
+            String::from("||example.com^$csp=img-src 'none'"),
+            String::from("@@||example.com/allowed^$csp=img-src 'none'"),
+            String::from("@@||example.com/all^$csp"),
+        ];
+
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+
+        assert_eq!(
+            engine.get_csp_directives("https://example.com", "https://example.com", "main_frame"),
+            Some(String::from("img-src 'none', script-src 'self' * 'unsafe-inline'"))
+        );
+        assert_eq!(
+            engine.get_csp_directives("https://example.com/allowed", "https://example.com/allowed", "main_frame"),
+            Some(String::from("script-src 'self' * 'unsafe-inline'"))
+        );
+        assert_eq!(engine.get_csp_directives("https://example.com/all", "https://example.com/all", "main_frame"), None);
+        assert_eq!(engine.get_csp_directives("https://example.com/image.png", "https://example.com", "image"), None);
+        assert_eq!(engine.get_csp_directives("not a url", "", "main_frame"), None);
+    }
+
+    #[test]
+    fn response_headers() {
+        let filters = vec![
+            String::from("||example.com^$script,header=via:1.1 google"),
+        ];
+
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        let serialized = engine.serialize().unwrap();
+        let mut deserialized_engine = Engine::default();
+        deserialized_engine.deserialize(&serialized).unwrap();
+
+        let request = Request::from_urls("https://example.com/a.js", "https://example.com", "script").unwrap();
+        assert!(!deserialized_engine.check_network_urls("https://example.com/a.js", "https://example.com", "script").matched);
+        assert!(deserialized_engine.check_response_headers(&request, &[("Via", "1.1 google")]).matched);
+        assert!(!deserialized_engine.check_response_headers(&request, &[("Via", "1.1 other")]).matched);
+    }
+
+    #[test]
+    fn method() {
+        let filters = vec![
+            String::from("||example.com/api^$method=post|put"),
+            String::from("||example.com/track^$method=~get"),
+        ];
+
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+
+        let check = |url: &str, method: &str| {
+            let request = Request::from_urls(url, "https://example.com", "xhr").unwrap().with_method(method);
+            engine.check_request(&request).matched
+        };
+        assert!(check("https://example.com/api", "POST"));
+        assert!(!check("https://example.com/api", "GET"));
+        assert!(!engine.check_network_urls("https://example.com/api", "https://example.com", "xhr").matched);
+        assert!(check("https://example.com/track", "HEAD"));
+        assert!(!check("https://example.com/track", "get"));
+        assert!(engine.check_network_urls("https://example.com/track", "https://example.com", "xhr").matched);
+
+        let url = "https://example.com/api";
+        let request = Request::from_urls_with_hostname(url, "example.com", "example.com", "xhr", None);
+        assert!(engine.check_request_subset(&request.clone().with_method("PUT"), false, false).matched);
+        assert!(!engine.check_request_subset(&request.clone().with_method("GET"), false, false).matched);
+
+        let source = RequestSource::parse("https://example.com");
+        let requests: Vec<_> = [("https://example.com/api", "POST"), ("https://example.com/api", "GET"), ("https://example.com/track", "HEAD")].iter()
+            .map(|(url, method)| Request::from_url_with_source(url, &source, "xhr").unwrap().with_method(method))
+            .collect();
+        let results = engine.check_request_batch(&requests);
+        assert_eq!(results.iter().map(|r| r.matched).collect::<Vec<_>>(), vec![true, false, true]);
+    }
+
+    #[test]
+    fn remove_filter() {
+        let filters = vec![
+            String::from("/banner/ad-"),
+            String::from("/sidebar/ad-"),
+            String::from("example.com##.ad"),
+        ];
+
+        let mut engine = Engine::from_rules(&filters, FilterFormat::Standard);
+
+        engine.remove_filter("/banner/ad-").unwrap();
+        assert!(!engine.check_network_urls("https://a.com/banner/ad-1.js", "https://a.com", "script").matched);
+        assert!(engine.check_network_urls("https://a.com/sidebar/ad-1.js", "https://a.com", "script").matched);
+
+        engine.remove_filter("example.com##.ad").unwrap();
+        assert!(engine.url_cosmetic_resources("https://example.com").hide_selectors.is_empty());
+
+        assert_eq!(engine.remove_filter("example.com##.ad"), Err(BlockerError::FilterNotFound));
+        assert_eq!(engine.remove_filter("||a.com^$unknown-option"), Err(BlockerError::UnparseableFilter));
+
+        // The raw line of the removed filter is dropped from the fused filter in debug mode
+        let mut engine = Engine::from_rules_debug(&filters, FilterFormat::Standard);
+        engine.remove_filter("/banner/ad-").unwrap();
+        let result = engine.check_network_urls("https://a.com/sidebar/ad-1.js", "https://a.com", "script");
+        assert_eq!(result.filter, Some(String::from("/sidebar/ad-")));
+    }
+
+    #[test]
+    fn update_filter_list() {
+        let old_list = [
+            "||removed.com^",
+            "||kept.com^",
+            "||tagged.com^$tag=test",
+            "||unbroken.com^",
+            "||unbroken.com^$badfilter",
+            "example.com##.removed",
+            "example.com##.kept",
+        ].join("\n");
+        let new_list = [
+            "||kept.com^",
+            "||tagged.com^$tag=test",
+            "||tagged-new.com^$tag=test",
+            "||unbroken.com^",
+            "||kept.com^$badfilter",
+            "||added.com^",
+            "example.com##.kept",
+            "example.com##.added",
+        ].join("\n");
+
+        let mut engine = Engine::from_rules(&old_list.lines().map(String::from).collect::<Vec<_>>(), FilterFormat::Standard);
+        engine.enable_tags(&["test"]);
+
+        let blocked = |engine: &Engine, url: &str| engine.check_network_urls(url, "https://example.com", "script").matched;
+        assert!(blocked(&engine, "https://removed.com/ad.js"));
+        assert!(!blocked(&engine, "https://unbroken.com/ad.js"));
+
+        engine.update_filter_list(&old_list, &new_list, FilterFormat::Standard).unwrap();
+
+        assert!(!blocked(&engine, "https://removed.com/ad.js"));
+        assert!(blocked(&engine, "https://added.com/ad.js"));
+        // `$badfilter` rules added or removed in the list are taken into account
+        assert!(!blocked(&engine, "https://kept.com/ad.js"));
+        assert!(blocked(&engine, "https://unbroken.com/ad.js"));
+        // Tags stay enabled
+        assert!(blocked(&engine, "https://tagged.com/ad.js"));
+        assert!(blocked(&engine, "https://tagged-new.com/ad.js"));
+        assert_eq!(engine.blocker.tags_enabled(), vec!["test".to_string()]);
+
+        let hidden = engine.url_cosmetic_resources("https://example.com").hide_selectors;
+        assert_eq!(hidden, [".kept", ".added"].iter().map(|s| s.to_string()).collect::<HashSet<_>>());
+
+        // Applying an RCS diff and then updating gives the same result as rebuilding
+        let diff = "d1 1\na2 1\n||removed.com^\n";
+        let newer_list = crate::lists::apply_rcs_diff(&new_list, diff).unwrap();
+        engine.update_filter_list(&new_list, &newer_list, FilterFormat::Standard).unwrap();
+        assert!(blocked(&engine, "https://removed.com/ad.js"));
+        assert!(!blocked(&engine, "https://kept.com/ad.js"));
+
+        // Cosmetic rules that are already gone don't make the update fail
+        engine.remove_filter("example.com##.added").unwrap();
+        engine.update_filter_list(&newer_list, &old_list, FilterFormat::Standard).unwrap();
+        let hidden = engine.url_cosmetic_resources("https://example.com").hide_selectors;
+        assert_eq!(hidden, [".kept", ".removed"].iter().map(|s| s.to_string()).collect::<HashSet<_>>());
+    }
+
+    #[test]
+    fn update_filter_list_shared_rules() {
+        let list_a = ["||shared.com^", "||a.com^", "##.shared"].join("\n");
+        let list_b = ["||shared.com^", "##.shared"].join("\n");
+
+        let mut engine = Engine::from_rules(&list_a.lines().map(String::from).collect::<Vec<_>>(), FilterFormat::Standard);
+        engine.update_filter_list("", &list_b, FilterFormat::Standard).unwrap();
+
+        let blocked = |engine: &Engine, url: &str| engine.check_network_urls(url, "https://example.com", "script").matched;
+        let hidden = |engine: &Engine| engine.hidden_class_id_selectors(&["shared".to_string()], &[], &HashSet::default());
+
+        // Dropping the rules from one list keeps them for the other
+        engine.update_filter_list(&list_b, "", FilterFormat::Standard).unwrap();
+        assert!(blocked(&engine, "https://shared.com/ad.js"));
+        assert_eq!(hidden(&engine), vec![".shared"]);
+
+        engine.update_filter_list(&list_a, "", FilterFormat::Standard).unwrap();
+        assert!(!blocked(&engine, "https://shared.com/ad.js"));
+        assert!(!blocked(&engine, "https://a.com/ad.js"));
+        assert!(hidden(&engine).is_empty());
+    }
+
+    #[test]
+    fn update_filter_list_failure_leaves_engine_unchanged() {
+        let old_list = [
+            "||removed.com^",
+            "$script,third-party",
+            "ad$script,third-party",
+        ].join("\n");
+        let new_list = "$script,third-party";
+
+        let mut engine = Engine::from_rules(&old_list.lines().map(String::from).collect::<Vec<_>>(), FilterFormat::Standard);
+
+        // `ad$script,third-party` was fused with a filter without a pattern, so it can't be removed
+        assert_eq!(engine.update_filter_list(&old_list, new_list, FilterFormat::Standard), Err(BlockerError::FusedFilterRemovalUnsupported));
+
+        let blocked = |engine: &Engine, url: &str| engine.check_network_urls(url, "https://example.com", "image").matched;
+        assert!(blocked(&engine, "https://removed.com/image.png"));
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn statistics() {
+        use crate::blocker::FilterListKind;
+        use crate::filters::network::NetworkFilter;
+
+        let filters = vec![
+            String::from("||ads.com^"),
+            String::from("||unused.com^"),
+            String::from("@@||ads.com/allowed^"),
+            String::from("example.com##.specific"),
+            String::from("other.com##.specific"),
+            String::from("example.*##.entity"),
+            String::from("##.generic-class"),
+            String::from("example.com##.ad:-abp-contains(Sponsored)"),
+        ];
+
+        let engine = Engine::from_rules_debug(&filters, FilterFormat::Standard);
+        let ads = NetworkFilter::parse("||ads.com^", true).unwrap();
+        let unused = NetworkFilter::parse("||unused.com^", true).unwrap();
+
+        engine.check_network_urls("https://ads.com/ad.js", "https://example.com", "script");
+        engine.check_network_urls("https://ads.com/allowed/ad.js", "https://example.com", "script");
+        engine.check_network_urls("https://other.com/ad.js", "https://example.com", "script");
+        engine.url_cosmetic_resources("https://example.com");
+        engine.hidden_class_id_selectors(&["generic-class".to_string()], &[], &HashSet::new());
+        // Explaining a request doesn't count it again
+        engine.explain(&Request::from_urls("https://ads.com/ad.js", "https://example.com", "script").unwrap());
+
+        let statistics = engine.statistics();
+        let filters = &statistics.network[&FilterListKind::Filters];
+        assert_eq!(filters.checks, 4);
+        assert_eq!(filters.filter_hits.get(&ads.id), Some(&3));
+        assert_eq!(filters.filter_hits.get(&unused.id), None);
+        assert_eq!(filters.buckets_visited.iter().sum::<u64>(), 4);
+        assert_eq!(statistics.network[&FilterListKind::Exceptions].checks, 3);
+        assert_eq!(statistics.network[&FilterListKind::Exceptions].filter_hits.len(), 1);
+        assert_eq!(statistics.cosmetic_hits.get("example.com##.specific"), Some(&1));
+        assert_eq!(statistics.cosmetic_hits.get("other.com##.specific"), None);
+        assert_eq!(statistics.cosmetic_hits.get("example.*##.entity"), Some(&1));
+        assert_eq!(statistics.cosmetic_hits.get("##.generic-class"), Some(&1));
+        // Procedural rules are written with the canonical operator names
+        assert_eq!(statistics.cosmetic_hits.get("example.com##.ad:has-text(Sponsored)"), Some(&1));
+        assert!(statistics.to_json().contains("\"Filters\""));
+
+        engine.reset_statistics();
+        let statistics = engine.statistics();
+        assert_eq!(statistics.network[&FilterListKind::Filters].checks, 0);
+        assert!(statistics.network[&FilterListKind::Filters].filter_hits.is_empty());
+        assert!(statistics.cosmetic_hits.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn statistics_optimized() {
+        use crate::blocker::FilterListKind;
+        use crate::filters::network::NetworkFilter;
+
+        let filters = vec![
+            String::from("-ads-"),
+            String::from("=ads="),
+        ];
+
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        let dashes = NetworkFilter::parse("-ads-", true).unwrap();
+        let equals = NetworkFilter::parse("=ads=", true).unwrap();
+
+        engine.check_network_urls("https://example.com/-ads-/ad.js", "https://example.com", "script");
+
+        // The filters are fused together, so the hit is counted for both of them
+        let statistics = engine.statistics();
+        let hits = &statistics.network[&FilterListKind::Filters].filter_hits;
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits.get(&dashes.id), Some(&1));
+        assert_eq!(hits.get(&equals.id), Some(&1));
+    }
+
+    #[test]
+    fn explain() {
+        let filters = vec![
+            String::from("||example.com/ads/*$image"),
+            String::from("@@||example.com/ads/allowed.png"),
+        ];
+
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        let serialized = engine.serialize().unwrap();
+        let mut deserialized_engine = Engine::default();
+        deserialized_engine.deserialize(&serialized).unwrap();
+
+        let request = Request::from_urls("https://example.com/ads/allowed.png", "https://example.com", "image").unwrap();
+        let trace = deserialized_engine.explain(&request);
+        assert_eq!(trace.decision, BlockerDecision::Excepted);
+        assert_eq!(trace.filter.unwrap().pattern, Some("/ads/".to_string()));
+        assert_eq!(trace.exception.unwrap().exception, true);
+        assert_eq!(trace.result.matched, false);
+    }
+
+    #[test]
+    fn explain_allowlisted() {
+        let filters = vec![
+            String::from("||ads.net^"),
+        ];
+
+        let mut engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        engine.allowlist_mut().add("trusted.org").unwrap();
+
+        let request = Request::from_urls("https://ads.net/ad.js", "https://www.trusted.org", "script").unwrap();
+        let trace = engine.explain(&request);
+        assert_eq!(trace.decision, BlockerDecision::Allowlisted);
+        assert_eq!(trace.allowlisted_by.as_deref(), Some("trusted.org"));
+        assert!(trace.steps.is_empty());
+        assert!(trace.filter.is_none());
+        assert!(!trace.result.matched);
+
+        let request = Request::from_urls("https://ads.net/ad.js", "https://other.org", "script").unwrap();
+        let trace = engine.explain(&request);
+        assert_eq!(trace.decision, BlockerDecision::Blocked);
+        assert_eq!(trace.allowlisted_by, None);
+    }
+
+    #[test]
+    fn procedural_cosmetic_filters() {
+        use crate::filters::cosmetic::CosmeticFilterOperator::*;
+
+        let filters = vec![
+            String::from("example.com##.ad:has-text(Sponsored):upward(2)"),
+            String::from("example.com##.block"),
+        ];
+
+        let engine = Engine::from_rules(&filters, FilterFormat::Standard);
+        let serialized = engine.serialize().unwrap();
+        let mut deserialized_engine = Engine::default();
+        deserialized_engine.deserialize(&serialized).unwrap();
+
+        let result = deserialized_engine.url_cosmetic_resources("https://example.com");
+        assert_eq!(result.hide_selectors, vec![".block".to_string()].into_iter().collect::<HashSet<_>>());
+        assert_eq!(
+            result.procedural_filters,
+            vec![vec![CssSelector(".ad".to_string()), HasText("Sponsored".to_string()), NthAncestor(2)]].into_iter().collect::<HashSet<_>>()
+        );
+    }
 }
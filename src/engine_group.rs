@@ -0,0 +1,438 @@
+//! Combines several [`Engine`]s, e.g. one for default lists and others for regional lists, so that
+//! they behave as if all of their filters were loaded in a single `Engine`.
+
+use crate::blocker::{Blocker, BlockerResult};
+use crate::cosmetic_filter_cache::UrlSpecificResources;
+use crate::engine::{Engine, PageCheck};
+use crate::request::Request;
+
+use std::collections::HashSet;
+
+/// Owns several `Engine`s and returns combined results for them:
+/// - an exception from any engine overrides a matching filter from any other engine
+/// - a `$important` filter from any engine blocks the request regardless of exceptions
+/// - `$redirect` and `$redirect-rule` filters from all engines compete by priority, and
+///   `@@$redirect` exceptions apply to redirects from any engine. Engines earlier in the group
+///   win ties.
+/// - `$genericblock` exceptions from any engine disable the generic filters of every engine
+/// - `$document` exceptions from any engine allowlist the whole page, see `check_page`
+/// - `$generichide`, `$specifichide` and cosmetic hiding exceptions from any engine apply to the
+///   cosmetic rules of every engine
+///
+/// Each engine can still be updated, serialized or deserialized on its own through `engines_mut`.
+#[derive(Default)]
+pub struct EngineGroup {
+    engines: Vec<Engine>,
+}
+
+impl EngineGroup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_engines(engines: Vec<Engine>) -> Self {
+        Self { engines }
+    }
+
+    /// Adds an engine at the end of the group.
+    pub fn push(&mut self, engine: Engine) {
+        self.engines.push(engine);
+    }
+
+    /// Removes and returns the engine at `index`.
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn remove(&mut self, index: usize) -> Engine {
+        self.engines.remove(index)
+    }
+
+    pub fn engines(&self) -> &[Engine] {
+        &self.engines
+    }
+
+    pub fn engines_mut(&mut self) -> &mut [Engine] {
+        &mut self.engines
+    }
+
+    pub fn len(&self) -> usize {
+        self.engines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.engines.is_empty()
+    }
+
+    /// Check if a request for a network resource from `url`, of type `request_type`, initiated by
+    /// `source_url`, should be blocked by any engine of the group.
+    pub fn check_network_urls(&self, url: &str, source_url: &str, request_type: &str) -> BlockerResult {
+        match Request::from_urls(url, source_url, request_type) {
+            Ok(request) => self.check(&request),
+            Err(_) => BlockerResult::request_parse_error(),
+        }
+    }
+
+    /// Decide if a network request should be blocked, redirected or allowed, taking the filters
+    /// of all engines into account. Nothing is blocked on sites trusted by the allowlist of any
+    /// engine.
+    pub fn check(&self, request: &Request) -> BlockerResult {
+        if self.engines.is_empty() || !request.is_supported || self.engines.iter().any(|engine| engine.is_trusted_site(request)) {
+            return BlockerResult::default();
+        }
+
+        let mut request_tokens;
+        #[cfg(feature = "object-pooling")]
+        {
+            request_tokens = self.engines[0].blocker.pool.pool.new();
+        }
+        #[cfg(not(feature = "object-pooling"))]
+        {
+            request_tokens = Vec::with_capacity(crate::utils::TOKENS_BUFFER_SIZE);
+        }
+        request.get_tokens(&mut request_tokens);
+
+        // A `$genericblock` exception from any engine disables the generic filters of all of them
+        let generic_block = self.engines.iter()
+            .any(|engine| !engine.blocker.check_generic_block(request, true).is_empty());
+
+        let engine_redirect_filters: Vec<_> = self.engines.iter()
+            .map(|engine| engine.blocker.redirect_filters(request, &request_tokens, generic_block))
+            .collect();
+
+        // Exceptions are always checked, since they can override filters from other engines
+        let mut results: Vec<BlockerResult> = self.engines.iter()
+            .zip(&engine_redirect_filters)
+            .map(|(engine, redirect_filters)| {
+                engine.blocker.check_tokenized(request, &request_tokens, generic_block, redirect_filters, false, true)
+            })
+            .collect();
+
+        let redirect_filters: Vec<_> = self.engines.iter()
+            .zip(engine_redirect_filters)
+            .flat_map(|(engine, filters)| filters.into_iter().map(move |filter| (filter, &engine.blocker.resources)))
+            .collect();
+
+        // `$important` filters are not subject to exceptions, so the first engine with one decides
+        if let Some(index) = results.iter().position(|result| result.important) {
+            let mut result = results.swap_remove(index);
+            result.redirect = Blocker::resolve_redirect(&redirect_filters);
+            return result;
+        }
+
+        let filter = results.iter().find_map(|result| result.filter.clone());
+        let blocked = filter.is_some()
+            || redirect_filters.iter().any(|(f, _)| !f.is_exception() && !f.is_redirect_rule());
+        let exception = if blocked {
+            results.iter().find_map(|result| result.exception.clone())
+        } else {
+            None
+        };
+        let matched = blocked && exception.is_none();
+
+        let redirect = if blocked {
+            Blocker::resolve_redirect(&redirect_filters)
+        } else {
+            None
+        };
+
+        // Query parameters only need to be removed from requests that aren't blocked
+        let rewritten_url = if matched {
+            None
+        } else {
+            // Filters from one engine can be excepted by another, so they are all applied at once
+            let removeparam_filters: Vec<_> = self.engines.iter()
+                .flat_map(|engine| engine.blocker.removeparam_filters(request, &request_tokens))
+                .collect();
+            Blocker::rewrite_url(&request.url, &removeparam_filters)
+        };
+
+        BlockerResult {
+            matched,
+            important: false,
+            redirect,
+            rewritten_url,
+            exception,
+            filter,
+            error: None,
+        }
+    }
+
+    /// Evaluates the document URL of a page against all engines, as `Engine::check_page` does
+    /// for a single engine. The page is allowlisted if the allowlist or a `$document` exception
+    /// of any engine applies to it.
+    pub fn check_page(&self, document_url: &str) -> PageCheck {
+        self.check_document(document_url, document_url)
+    }
+
+    /// Like `check_page`, for a document loaded in a frame of the `parent` page, as
+    /// `Engine::check_frame` does for a single engine.
+    pub fn check_frame(&self, document_url: &str, parent: &PageCheck) -> PageCheck {
+        match &parent.allowlisted_by {
+            Some(entry) => PageCheck {
+                url: document_url.to_string(),
+                allowlisted_by: Some(entry.clone()),
+            },
+            None => self.check_document(document_url, &parent.url),
+        }
+    }
+
+    fn check_document(&self, document_url: &str, parent_url: &str) -> PageCheck {
+        let allowlisted_by = Request::from_urls(document_url, parent_url, "document")
+            .ok()
+            .and_then(|request| {
+                self.engines.iter()
+                    .find_map(|engine| engine.allowlist().matching_entry(&request.hostname).map(|entry| entry.to_string()))
+                    .or_else(|| self.engines.iter()
+                        .find_map(|engine| engine.blocker.check_document_allowlist(&request).map(|filter| filter.to_string())))
+            });
+
+        PageCheck {
+            url: document_url.to_string(),
+            allowlisted_by,
+        }
+    }
+
+    /// Like `check_network_urls`, for a request made by a page that was evaluated with
+    /// `check_page`, as `Engine::check_network_urls_on_page` does for a single engine.
+    pub fn check_network_urls_on_page(&self, page: &PageCheck, url: &str, request_type: &str) -> BlockerResult {
+        match &page.allowlisted_by {
+            Some(exception) => BlockerResult {
+                exception: Some(exception.clone()),
+                ..BlockerResult::default()
+            },
+            None => self.check_network_urls(url, &page.url, request_type),
+        }
+    }
+
+    /// Like `url_cosmetic_resources`, for a page that was evaluated with `check_page`, as
+    /// `Engine::url_cosmetic_resources_on_page` does for a single engine.
+    pub fn url_cosmetic_resources_on_page(&self, page: &PageCheck) -> UrlSpecificResources {
+        if page.is_allowlisted() {
+            return Engine::allowlisted_cosmetic_resources();
+        }
+        self.url_cosmetic_resources(&page.url)
+    }
+
+    /// Returns the cosmetic filter resources of all engines for a particular url, as
+    /// `Engine::url_cosmetic_resources` does for a single engine.
+    ///
+    /// Hiding exceptions from one engine also remove hiding rules from the others, but exceptions
+    /// for style rules and scriptlets only apply within their own engine.
+    pub fn url_cosmetic_resources(&self, url: &str) -> UrlSpecificResources {
+        let request = match Request::from_url(url) {
+            Ok(request) => request,
+            Err(_) => return UrlSpecificResources::empty(),
+        };
+
+        if self.engines.iter().any(|engine| engine.is_trusted_site(&request)) {
+            return Engine::allowlisted_cosmetic_resources();
+        }
+
+        let (generichide, specifichide) = self.engines.iter()
+            .map(|engine| engine.blocker.check_cosmetic_hide(&request))
+            .fold((false, false), |(generic, specific), (g, s)| (generic || g, specific || s));
+
+        let mut combined = UrlSpecificResources {
+            generichide,
+            specifichide,
+            elemhide: generichide && specifichide,
+            ..UrlSpecificResources::empty()
+        };
+
+        for engine in &self.engines {
+            let resources = engine.cosmetic_cache.hostname_cosmetic_resources(&request.hostname, generichide, specifichide);
+            combined.hide_selectors.extend(resources.hide_selectors);
+            for (selector, styles) in resources.style_selectors {
+                let combined_styles = combined.style_selectors.entry(selector).or_default();
+                styles.into_iter().for_each(|style| {
+                    if !combined_styles.contains(&style) {
+                        combined_styles.push(style);
+                    }
+                });
+            }
+            combined.exceptions.extend(resources.exceptions);
+            combined.injected_script += &resources.injected_script;
+            combined.procedural_filters.extend(resources.procedural_filters);
+        }
+
+        let UrlSpecificResources { hide_selectors, exceptions, .. } = &mut combined;
+        hide_selectors.retain(|selector| !exceptions.contains(selector));
+
+        combined
+    }
+
+    /// Returns the generic selectors of all engines matching the given classes and ids, as
+    /// `Engine::hidden_class_id_selectors` does for a single engine. `exceptions` should be
+    /// passed directly from the `UrlSpecificResources` returned by `url_cosmetic_resources`.
+    pub fn hidden_class_id_selectors(&self, classes: &[String], ids: &[String], exceptions: &HashSet<String>) -> Vec<String> {
+        let mut seen = HashSet::new();
+        self.engines.iter()
+            .flat_map(|engine| engine.hidden_class_id_selectors(classes, ids, exceptions))
+            .filter(|selector| seen.insert(selector.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lists::FilterFormat;
+    use crate::resources::{MimeType, Resource, ResourceType};
+
+    fn engine(rules: &[&str]) -> Engine {
+        let rules: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+        Engine::from_rules_debug(&rules, FilterFormat::Standard)
+    }
+
+    fn resource(name: &str, content: &str) -> Resource {
+        Resource {
+            name: name.to_string(),
+            aliases: vec![],
+            kind: ResourceType::Mime(MimeType::ApplicationJavascript),
+            content: base64::encode(content),
+        }
+    }
+
+    #[test]
+    fn exceptions_and_important() {
+        let group = EngineGroup::from_engines(vec![
+            engine(&["||ads.net^", "@@||tracker.org^"]),
+            engine(&["@@||ads.net/allowed.js", "||tracker.org^$important"]),
+        ]);
+
+        let result = group.check_network_urls("https://ads.net/ad.js", "https://example.com", "script");
+        assert!(result.matched);
+        assert_eq!(result.filter.as_deref(), Some("||ads.net^"));
+
+        let result = group.check_network_urls("https://ads.net/allowed.js", "https://example.com", "script");
+        assert!(!result.matched);
+        assert_eq!(result.filter.as_deref(), Some("||ads.net^"));
+        assert_eq!(result.exception.as_deref(), Some("@@||ads.net/allowed.js"));
+
+        let result = group.check_network_urls("https://tracker.org/t.js", "https://example.com", "script");
+        assert!(result.matched);
+        assert!(result.important);
+        assert_eq!(result.exception, None);
+
+        let result = group.check_network_urls("https://example.net/", "https://example.com", "script");
+        assert!(!result.matched);
+        assert_eq!(result.exception, None);
+    }
+
+    #[test]
+    fn method() {
+        let group = EngineGroup::from_engines(vec![
+            engine(&["||example.com/api^$method=post"]),
+            engine(&["@@||example.com/api^$method=post,domain=allowed.com"]),
+        ]);
+
+        let check = |source_url: &str, method: &str| {
+            let request = Request::from_urls("https://example.com/api", source_url, "xhr").unwrap().with_method(method);
+            group.check(&request).matched
+        };
+        assert!(check("https://example.com", "POST"));
+        assert!(!check("https://example.com", "GET"));
+        assert!(!check("https://allowed.com", "POST"));
+    }
+
+    #[test]
+    fn genericblock() {
+        let group = EngineGroup::from_engines(vec![
+            engine(&["/generic-ad.", "/specific-ad.$domain=example.com"]),
+            engine(&["@@||example.com^$genericblock"]),
+        ]);
+
+        assert!(!group.check_network_urls("https://ads.net/generic-ad.png", "https://example.com/", "image").matched);
+        assert!(group.check_network_urls("https://ads.net/specific-ad.png", "https://example.com/", "image").matched);
+        assert!(group.check_network_urls("https://ads.net/generic-ad.png", "https://example.org/", "image").matched);
+    }
+
+    #[test]
+    fn document_allowlist() {
+        let mut second = engine(&["@@||example.com^$document"]);
+        second.allowlist_mut().add("trusted.org").unwrap();
+        let group = EngineGroup::from_engines(vec![
+            engine(&["||ads.net^$important"]),
+            second,
+        ]);
+
+        let page = group.check_page("https://sub.example.com/");
+        assert_eq!(page.allowlisted_by.as_deref(), Some("@@||example.com^$document"));
+        assert!(!group.check_network_urls_on_page(&page, "https://ads.net/ad.js", "script").matched);
+        assert!(group.url_cosmetic_resources_on_page(&page).elemhide);
+
+        assert_eq!(group.check_frame("https://trusted.org/frame", &group.check_page("https://other.com/")).allowlisted_by.as_deref(), Some("trusted.org"));
+        // Frames of an allowlisted page are allowlisted as well
+        let frame = group.check_frame("https://ads.net/frame", &page);
+        assert_eq!(frame.allowlisted_by.as_deref(), Some("@@||example.com^$document"));
+
+        let page = group.check_page("https://example.net/");
+        assert!(!page.is_allowlisted());
+        assert!(group.check_network_urls_on_page(&page, "https://ads.net/ad.js", "script").matched);
+        assert!(!group.url_cosmetic_resources_on_page(&page).elemhide);
+    }
+
+    #[test]
+    fn redirects() {
+        let mut first = engine(&[
+            "||cdn.net/lib.js$script,redirect=first.js",
+            "||cdn.net/excepted.js$script,redirect=first.js",
+            "||cdn.net/rule.js",
+        ]);
+        first.add_resource(resource("first.js", "first")).unwrap();
+        let mut second = engine(&[
+            "||cdn.net/lib.js$script,redirect=second.js:10",
+            "||cdn.net/excepted.js$script,redirect=second.js:10",
+            "@@||cdn.net/excepted.js$redirect=second.js",
+            "||cdn.net/rule.js$script,redirect-rule=second.js",
+        ]);
+        second.add_resource(resource("second.js", "second")).unwrap();
+        let group = EngineGroup::from_engines(vec![first, second]);
+
+        let redirect = |content: &str| Some(format!("data:application/javascript;base64,{}", base64::encode(content)));
+
+        // The highest priority wins across engines, and each redirect uses its own engine's resources
+        assert_eq!(group.check_network_urls("https://cdn.net/lib.js", "https://example.com", "script").redirect, redirect("second"));
+        // Redirect exceptions apply across engines
+        assert_eq!(group.check_network_urls("https://cdn.net/excepted.js", "https://example.com", "script").redirect, redirect("first"));
+        // `$redirect-rule` applies to requests blocked by another engine
+        let result = group.check_network_urls("https://cdn.net/rule.js", "https://example.com", "script");
+        assert!(result.matched);
+        assert_eq!(result.redirect, redirect("second"));
+    }
+
+    #[test]
+    fn cosmetic_resources() {
+        let mut group = EngineGroup::new();
+        group.push(engine(&["##.ad", "##a[href^=\"https://ads.net\"]", "example.com##.banner", "example.com##.promo"]));
+        group.push(engine(&["example.com#@#.promo", "@@||generic.org^$generichide", "##div[data-ad]"]));
+        assert_eq!(group.len(), 2);
+
+        let resources = group.url_cosmetic_resources("https://example.com/");
+        let expected: HashSet<String> = ["a[href^=\"https://ads.net\"]", ".banner", "div[data-ad]"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(resources.hide_selectors, expected);
+        assert!(!resources.generichide);
+
+        let selectors = group.hidden_class_id_selectors(&["ad".to_string(), "promo".to_string()], &[], &resources.exceptions);
+        assert_eq!(selectors, vec![".ad".to_string()]);
+
+        let resources = group.url_cosmetic_resources("https://generic.org/");
+        assert!(resources.generichide);
+        assert!(resources.hide_selectors.is_empty());
+    }
+
+    #[test]
+    fn removeparam_across_engines() {
+        let group = EngineGroup::from_engines(vec![
+            engine(&["||example.com^$removeparam=utm_source", "||excepted.com^$removeparam=utm_source"]),
+            engine(&["||example.com^$removeparam=fbclid", "@@||excepted.com^$removeparam"]),
+        ]);
+
+        let rewrite = |url: &str| group.check_network_urls(url, "https://source.com", "xhr").rewritten_url;
+
+        // Filters from both engines are applied to the same URL
+        assert_eq!(rewrite("https://example.com/?utm_source=x&fbclid=y&a=1"), Some(String::from("https://example.com/?a=1")));
+        // An exception from one engine cancels the filters of another
+        assert_eq!(rewrite("https://excepted.com/?utm_source=x"), None);
+        assert_eq!(rewrite("https://other.com/?utm_source=x"), None);
+    }
+}
@@ -7,7 +7,8 @@ use serde::{Deserialize, Serialize};
 
 use crate::utils::Hash;
 
-use css_validation::{is_valid_css_selector, is_valid_css_style};
+pub use css_validation::is_valid_css_selector;
+use css_validation::is_valid_css_style;
 
 #[derive(Debug, PartialEq)]
 pub enum CosmeticFilterError {
@@ -20,6 +21,8 @@ pub enum CosmeticFilterError {
     GenericUnhide,
     GenericScriptInject,
     GenericStyle,
+    GenericProcedural,
+    InvalidProceduralOperator,
     DoubleNegation,
     EmptyRule,
 }
@@ -52,6 +55,54 @@ pub struct CosmeticFilter {
     pub selector: String,
     pub key: Option<String>,
     pub style: Option<String>,
+    /// Set for rules using procedural operators like `:has-text(...)` or `:upward(...)`. In that
+    /// case, `selector` holds the original text of the rule after the separator.
+    #[serde(default)]
+    pub procedural: Option<Vec<CosmeticFilterOperator>>,
+}
+
+/// A single step of a procedural cosmetic filter, as described by uBlock Origin's
+/// [procedural cosmetic filter syntax][1].
+///
+/// Procedural filters are represented as a chain of operators. Starting from the document, each
+/// operator selects, filters, or acts on the elements produced by the previous one. `Remove` and
+/// `Style` are actions, and can only appear at the end of a chain.
+///
+/// [1]: https://github.com/gorhill/uBlock/wiki/Procedural-cosmetic-filters
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CosmeticFilterOperator {
+    /// Selects elements matching a plain CSS selector. After the first step of a chain, the
+    /// selector is applied relative to each of the current elements.
+    CssSelector(String),
+    /// `:has-text(text)`, `:-abp-contains(text)`; text can also be a `/regex/`.
+    HasText(String),
+    /// `:has(...)`, `:-abp-has(...)`, `:if(...)`; keeps elements with a matching descendant.
+    Has(Vec<CosmeticFilterOperator>),
+    /// `:not(...)`, `:if-not(...)`; only used when the argument is itself procedural.
+    Not(Vec<CosmeticFilterOperator>),
+    /// `:matches-css(property: value)`
+    MatchesCss(String),
+    /// `:matches-css-before(property: value)`
+    MatchesCssBefore(String),
+    /// `:matches-css-after(property: value)`
+    MatchesCssAfter(String),
+    /// `:matches-path(path)`; text can also be a `/regex/`.
+    MatchesPath(String),
+    /// `:min-text-length(n)`
+    MinTextLength(u32),
+    /// `:nth-ancestor(n)`, `:upward(n)`
+    NthAncestor(u32),
+    /// `:upward(selector)`
+    Upward(String),
+    /// `:watch-attr(attributes)`
+    WatchAttr(String),
+    /// `:xpath(expression)`
+    Xpath(String),
+    /// `:remove()`; removes the elements from the page instead of hiding them.
+    Remove,
+    /// `:style(style)`; applies a custom style instead of hiding the elements.
+    Style(String),
 }
 
 pub enum CosmeticFilterLocationType {
@@ -167,8 +218,21 @@ impl CosmeticFilter {
         line: &'a str,
         suffix_start_index: usize,
         selector: &mut &'a str,
-        style: &mut Option<String>
+        style: &mut Option<String>,
+        procedural: &mut Option<Vec<CosmeticFilterOperator>>,
     ) -> Result<(), CosmeticFilterError> {
+        // uBO's HTML filtering syntax (`##^...`) is not supported
+        if line[suffix_start_index..].starts_with('^') {
+            return Err(CosmeticFilterError::UnsupportedSyntax);
+        }
+
+        let operators = parse_procedural_operators(&line[suffix_start_index..])?;
+        if operators.iter().any(|op| !matches!(op, CosmeticFilterOperator::CssSelector(_) | CosmeticFilterOperator::Style(_))) {
+            validate_procedural_chain(&operators)?;
+            *procedural = Some(operators);
+            return Ok(());
+        }
+
         let mut index_after_colon = suffix_start_index;
         while let Some(colon_index) = line[index_after_colon..].find(':') {
             let colon_index = colon_index + index_after_colon;
@@ -182,19 +246,9 @@ impl CosmeticFilter {
                     return Err(CosmeticFilterError::InvalidStyleSpecifier);
                 }
             } else if content_after_colon.starts_with("-abp-")
-            || content_after_colon.starts_with("contains")
-            || content_after_colon.starts_with("has")
-            || content_after_colon.starts_with("if")
-            || content_after_colon.starts_with("if-not")
-            || content_after_colon.starts_with("matches-css")
-            || content_after_colon.starts_with("matches-css-after")
-            || content_after_colon.starts_with("matches-css-before")
             || content_after_colon.starts_with("properties")
             || content_after_colon.starts_with("subject")
-            || content_after_colon.starts_with("xpath")
-            || content_after_colon.starts_with("nth-ancestor")
-            || content_after_colon.starts_with("upward")
-            || content_after_colon.starts_with("remove")
+            || content_after_colon.starts_with("others")
             {
                 return Err(CosmeticFilterError::UnsupportedSyntax);
             }
@@ -240,6 +294,7 @@ impl CosmeticFilter {
                 return Err(CosmeticFilterError::EmptyRule);
             }
             let mut style = None;
+            let mut procedural = None;
             if line.len() - suffix_start_index > 4 && line[suffix_start_index..].starts_with("+js(") && line.ends_with(')') {
                 if sharp_index == 0 {
                     return Err(CosmeticFilterError::GenericScriptInject);
@@ -247,10 +302,16 @@ impl CosmeticFilter {
                 mask |= CosmeticFilterMask::SCRIPT_INJECT;
                 selector = &line[suffix_start_index + 4..line.len() - 1];
             } else {
-                CosmeticFilter::parse_after_sharp_nonscript(line, suffix_start_index, &mut selector, &mut style)?;
+                CosmeticFilter::parse_after_sharp_nonscript(line, suffix_start_index, &mut selector, &mut style, &mut procedural)?;
             }
 
-            if !mask.contains(CosmeticFilterMask::SCRIPT_INJECT) && !is_valid_css_selector(selector) {
+            if procedural.is_some() {
+                // Procedural operators are too expensive to be applied on every page, including
+                // every page but a few excluded ones, like `~example.com##.ad:has-text(Ad)`
+                if hostnames.is_none() && entities.is_none() {
+                    return Err(CosmeticFilterError::GenericProcedural);
+                }
+            } else if !mask.contains(CosmeticFilterMask::SCRIPT_INJECT) && !is_valid_css_selector(selector) {
                 return Err(CosmeticFilterError::InvalidCssSelector);
             } else if let Some(ref style) = style {
                 if !is_valid_css_style(style) {
@@ -268,7 +329,7 @@ impl CosmeticFilter {
                 mask |= CosmeticFilterMask::IS_UNICODE;
             }
 
-            let key = if !mask.contains(CosmeticFilterMask::SCRIPT_INJECT) {
+            let key = if !mask.contains(CosmeticFilterMask::SCRIPT_INJECT) && procedural.is_none() {
                 if selector.starts_with('.') {
                     let key = key_from_selector(selector)?;
                     mask |= CosmeticFilterMask::IS_CLASS_SELECTOR;
@@ -304,6 +365,7 @@ impl CosmeticFilter {
                 selector: String::from(selector),
                 key,
                 style,
+                procedural,
             })
         } else {
             Err(CosmeticFilterError::MissingSharp)
@@ -328,12 +390,13 @@ impl CosmeticFilter {
     /// To account for this inconsistency, this method will generate and return the corresponding
     /// 'hidden' generic rule if one applies.
     ///
-    /// Note that this behavior is not applied to script injections or custom style rules.
+    /// Note that this behavior is not applied to script injections, custom style rules, or
+    /// procedural rules.
     pub fn hidden_generic_rule(&self) -> Option<CosmeticFilter> {
         if self.hostnames.is_some() || self.entities.is_some() {
             None
         } else if (self.not_hostnames.is_some() || self.not_entities.is_some()) &&
-            (self.style.is_none() && !self.mask.contains(CosmeticFilterMask::SCRIPT_INJECT))
+            (self.style.is_none() && self.procedural.is_none() && !self.mask.contains(CosmeticFilterMask::SCRIPT_INJECT))
         {
             let mut generic_rule = self.clone();
             generic_rule.not_hostnames = None;
@@ -345,6 +408,223 @@ impl CosmeticFilter {
     }
 }
 
+/// Splits the part of a cosmetic rule following the separator into a chain of procedural
+/// operators. Any text that is not part of a procedural operator is kept as a plain CSS selector.
+fn parse_procedural_operators(selector: &str) -> Result<Vec<CosmeticFilterOperator>, CosmeticFilterError> {
+    let bytes = selector.as_bytes();
+    let mut operators = vec![];
+    let mut css_start = 0;
+    let mut in_attribute = false;
+    let mut quote: Option<u8> = None;
+    let mut i = 0;
+
+    // Only ASCII characters are inspected, so every slice boundary is also a char boundary
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b'\\' {
+            i += 2;
+            continue;
+        }
+        if let Some(q) = quote {
+            if c == q {
+                quote = None;
+            }
+            i += 1;
+            continue;
+        }
+        match c {
+            b'"' | b'\'' if in_attribute => quote = Some(c),
+            b'[' => in_attribute = true,
+            b']' => in_attribute = false,
+            b':' if !in_attribute => {
+                let name_start = i + 1;
+                let name_end = selector[name_start..]
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+                    .map(|end| name_start + end)
+                    .unwrap_or_else(|| selector.len());
+                if bytes.get(name_end) == Some(&b'(') {
+                    let name = &selector[name_start..name_end];
+                    let arg_end = find_closing_parenthesis(selector, name_end)?;
+                    let arg = &selector[name_end + 1..arg_end];
+                    if let Some(operator) = CosmeticFilterOperator::parse(name, arg)? {
+                        let css = selector[css_start..i].trim();
+                        if !css.is_empty() {
+                            operators.push(CosmeticFilterOperator::CssSelector(css.to_string()));
+                        }
+                        operators.push(operator);
+                        css_start = arg_end + 1;
+                    }
+                    i = arg_end + 1;
+                    continue;
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+
+    let css = selector[css_start..].trim();
+    if !css.is_empty() {
+        operators.push(CosmeticFilterOperator::CssSelector(css.to_string()));
+    }
+
+    Ok(operators)
+}
+
+/// Returns the index of the `)` matching the `(` at `open_index`. Parentheses in quoted strings,
+/// like `:has-text(")")`, are skipped, while a quote without a closing one is kept as a regular
+/// character, like in `:has-text(Don't miss)`.
+fn find_closing_parenthesis(selector: &str, open_index: usize) -> Result<usize, CosmeticFilterError> {
+    let bytes = selector.as_bytes();
+    let mut depth = 0;
+    let mut i = open_index;
+
+    // Only ASCII characters are inspected, so every index is also a char boundary
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            quote @ (b'"' | b'\'') => {
+                if let Some(end) = find_closing_quote(bytes, i + 1, quote) {
+                    i = end;
+                }
+            }
+            b'(' => depth += 1,
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(i);
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    Err(CosmeticFilterError::InvalidProceduralOperator)
+}
+
+/// Returns the index of the first unescaped `quote` from `start`.
+fn find_closing_quote(bytes: &[u8], start: usize, quote: u8) -> Option<usize> {
+    let mut i = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            c if c == quote => return Some(i),
+            _ => (),
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Checks that actions only appear at the end of a procedural chain, and that the leading CSS
+/// selector is valid. Chains nested in `:has(...)` and `:not(...)` are checked as well; they can't
+/// contain actions, and their leading selector is not validated since it may be relative, like
+/// `> .ad`.
+fn validate_procedural_chain(operators: &[CosmeticFilterOperator]) -> Result<(), CosmeticFilterError> {
+    validate_procedural_operators(operators, false)
+}
+
+fn validate_procedural_operators(operators: &[CosmeticFilterOperator], nested: bool) -> Result<(), CosmeticFilterError> {
+    for (i, operator) in operators.iter().enumerate() {
+        match operator {
+            CosmeticFilterOperator::Remove | CosmeticFilterOperator::Style(_) if nested || i + 1 != operators.len() => {
+                return Err(CosmeticFilterError::InvalidProceduralOperator);
+            }
+            CosmeticFilterOperator::Style(style) if !is_valid_css_style(style) => {
+                return Err(CosmeticFilterError::InvalidCssStyle);
+            }
+            CosmeticFilterOperator::CssSelector(selector) if i == 0 && !nested && !is_valid_css_selector(selector) => {
+                return Err(CosmeticFilterError::InvalidCssSelector);
+            }
+            CosmeticFilterOperator::Has(chain) | CosmeticFilterOperator::Not(chain) => {
+                validate_procedural_operators(chain, true)?;
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+impl CosmeticFilterOperator {
+    /// Parses a single `:name(arg)` operator. Returns `None` if `name` is not a procedural
+    /// operator, in which case it should be treated as part of a CSS selector.
+    fn parse(name: &str, arg: &str) -> Result<Option<Self>, CosmeticFilterError> {
+        fn non_empty(arg: &str) -> Result<String, CosmeticFilterError> {
+            if arg.trim().is_empty() {
+                Err(CosmeticFilterError::InvalidProceduralOperator)
+            } else {
+                Ok(arg.to_string())
+            }
+        }
+
+        fn number(arg: &str) -> Result<u32, CosmeticFilterError> {
+            arg.trim().parse::<u32>().map_err(|_| CosmeticFilterError::InvalidProceduralOperator)
+        }
+
+        fn chain(arg: &str) -> Result<Vec<CosmeticFilterOperator>, CosmeticFilterError> {
+            let operators = parse_procedural_operators(arg)?;
+            if operators.is_empty() {
+                return Err(CosmeticFilterError::InvalidProceduralOperator);
+            }
+            Ok(operators)
+        }
+
+        let operator = match name {
+            "has-text" | "-abp-contains" | "contains" => Self::HasText(non_empty(arg)?),
+            "has" | "-abp-has" | "if" => Self::Has(chain(arg)?),
+            "not" | "if-not" => {
+                let operators = chain(arg)?;
+                // A `:not(...)` with a plain CSS argument is regular CSS
+                if name == "not" && operators.iter().all(|op| matches!(op, Self::CssSelector(_))) {
+                    return Ok(None);
+                }
+                Self::Not(operators)
+            }
+            "matches-css" => Self::MatchesCss(non_empty(arg)?),
+            "matches-css-before" => Self::MatchesCssBefore(non_empty(arg)?),
+            "matches-css-after" => Self::MatchesCssAfter(non_empty(arg)?),
+            "matches-path" => Self::MatchesPath(non_empty(arg)?),
+            "min-text-length" => Self::MinTextLength(number(arg)?),
+            "nth-ancestor" => Self::NthAncestor(number(arg)?),
+            "upward" => match number(arg) {
+                Ok(n) => Self::NthAncestor(n),
+                Err(_) => Self::Upward(non_empty(arg)?),
+            },
+            "watch-attr" => Self::WatchAttr(arg.to_string()),
+            "xpath" => Self::Xpath(non_empty(arg)?),
+            "remove" if arg.is_empty() => Self::Remove,
+            "remove" => return Err(CosmeticFilterError::InvalidProceduralOperator),
+            "style" => Self::Style(arg.to_string()),
+            "-abp-properties" | "properties" | "subject" | "others" => return Err(CosmeticFilterError::UnsupportedSyntax),
+            _ => return Ok(None),
+        };
+
+        Ok(Some(operator))
+    }
+
+    /// Writes a chain of operators back in the procedural filter syntax, using the canonical
+    /// name of each operator, e.g. `div:has-text(Ad):upward(2)`.
+    pub fn chain_to_string(operators: &[Self]) -> String {
+        operators.iter().map(|operator| match operator {
+            Self::CssSelector(selector) => selector.clone(),
+            Self::HasText(text) => format!(":has-text({})", text),
+            Self::Has(chain) => format!(":has({})", Self::chain_to_string(chain)),
+            Self::Not(chain) => format!(":not({})", Self::chain_to_string(chain)),
+            Self::MatchesCss(arg) => format!(":matches-css({})", arg),
+            Self::MatchesCssBefore(arg) => format!(":matches-css-before({})", arg),
+            Self::MatchesCssAfter(arg) => format!(":matches-css-after({})", arg),
+            Self::MatchesPath(path) => format!(":matches-path({})", path),
+            Self::MinTextLength(n) => format!(":min-text-length({})", n),
+            Self::NthAncestor(n) => format!(":nth-ancestor({})", n),
+            Self::Upward(selector) => format!(":upward({})", selector),
+            Self::WatchAttr(attributes) => format!(":watch-attr({})", attributes),
+            Self::Xpath(expression) => format!(":xpath({})", expression),
+            Self::Remove => ":remove()".to_string(),
+            Self::Style(style) => format!(":style({})", style),
+        }).collect()
+    }
+}
+
 /// Returns a slice of `hostname` up to and including the segment that overlaps with the first
 /// segment of `domain`. This has the effect of stripping ".com", ".co.uk", etc.
 fn get_hostname_without_public_suffix<'a>(hostname: &'a str, domain: &str) -> Option<&'a str> {
@@ -632,6 +912,7 @@ mod parse_tests {
         selector: String,
         key: Option<String>,
         style: Option<String>,
+        procedural: Option<Vec<CosmeticFilterOperator>>,
 
         unhide: bool,
         script_inject: bool,
@@ -650,6 +931,7 @@ mod parse_tests {
                 selector: filter.selector.clone(),
                 key: filter.key.as_ref().cloned(),
                 style: filter.style.as_ref().cloned(),
+                procedural: filter.procedural.as_ref().cloned(),
 
                 unhide: filter.mask.contains(CosmeticFilterMask::UNHIDE),
                 script_inject: filter.mask.contains(CosmeticFilterMask::SCRIPT_INJECT),
@@ -676,6 +958,7 @@ mod parse_tests {
                 selector: "".to_string(),
                 key: None,
                 style: None,
+                procedural: None,
 
                 unhide: false,
                 script_inject: false,
@@ -1232,15 +1515,111 @@ mod parse_tests {
 
     #[test]
     fn unsupported() {
-        assert!(CosmeticFilter::parse("yandex.*##.serp-item:if(:scope > div.organic div.organic__subtitle:matches-css-after(content: /[Рр]еклама/))", false).is_err());
-        assert!(CosmeticFilter::parse(r#"facebook.com,facebookcorewwwi.onion##.ego_column:if(a[href^="/campaign/landing"])"#, false).is_err());
-        assert!(CosmeticFilter::parse(r#"thedailywtf.com##.article-body > div:has(a[href*="utm_medium"])"#, false).is_err());
         assert!(CosmeticFilter::parse(r#"readcomiconline.to##^script:has-text(this[atob)"#, false).is_err());
-        assert!(CosmeticFilter::parse("twitter.com##article:has-text(/Promoted|Gesponsert|Реклама|Promocionado/):xpath(../..)", false).is_err());
+        assert!(CosmeticFilter::parse("example.com##div:-abp-properties(width: 300px)", false).is_err());
         assert!(CosmeticFilter::parse("##", false).is_err());
         assert!(CosmeticFilter::parse("", false).is_err());
     }
 
+    #[test]
+    fn procedural() {
+        use CosmeticFilterOperator::*;
+
+        fn check_procedural(rule: &str, expected: Vec<CosmeticFilterOperator>) {
+            let filter = CosmeticFilter::parse(rule, false).unwrap();
+            assert_eq!(filter.procedural.as_ref(), Some(&expected), "{}", rule);
+            assert_eq!(filter.key, None);
+
+            // Writing the operators back gives an equivalent rule
+            let written = format!("example.com##{}", CosmeticFilterOperator::chain_to_string(&expected));
+            assert_eq!(CosmeticFilter::parse(&written, false).unwrap().procedural, Some(expected), "{}", written);
+        }
+
+        check_procedural(
+            "yandex.*##.serp-item:if(:scope > div.organic div.organic__subtitle:matches-css-after(content: /[Рр]еклама/))",
+            vec![
+                CssSelector(".serp-item".to_string()),
+                Has(vec![
+                    CssSelector(":scope > div.organic div.organic__subtitle".to_string()),
+                    MatchesCssAfter("content: /[Рр]еклама/".to_string()),
+                ]),
+            ],
+        );
+        check_procedural(
+            r#"facebook.com,facebookcorewwwi.onion##.ego_column:if(a[href^="/campaign/landing"])"#,
+            vec![
+                CssSelector(".ego_column".to_string()),
+                Has(vec![CssSelector(r#"a[href^="/campaign/landing"]"#.to_string())]),
+            ],
+        );
+        check_procedural(
+            r#"thedailywtf.com##.article-body > div:has(a[href*="utm_medium"])"#,
+            vec![
+                CssSelector(".article-body > div".to_string()),
+                Has(vec![CssSelector(r#"a[href*="utm_medium"]"#.to_string())]),
+            ],
+        );
+        check_procedural(
+            "twitter.com##article:has-text(/Promoted|Gesponsert|Реклама|Promocionado/):xpath(../..)",
+            vec![
+                CssSelector("article".to_string()),
+                HasText("/Promoted|Gesponsert|Реклама|Promocionado/".to_string()),
+                Xpath("../..".to_string()),
+            ],
+        );
+        check_procedural(
+            "example.com##.ad:not(:has-text(Sponsored)):upward(2)",
+            vec![
+                CssSelector(".ad".to_string()),
+                Not(vec![HasText("Sponsored".to_string())]),
+                NthAncestor(2),
+            ],
+        );
+        check_procedural(
+            "example.com##.banner:upward(.container):remove()",
+            vec![CssSelector(".banner".to_string()), Upward(".container".to_string()), Remove],
+        );
+        check_procedural(
+            "example.com##p:min-text-length(100):style(color: red)",
+            vec![CssSelector("p".to_string()), MinTextLength(100), Style("color: red".to_string())],
+        );
+        check_procedural(
+            "example.com#@#div[data-x=\":has-text(a)\"]:has-text(b)",
+            vec![CssSelector("div[data-x=\":has-text(a)\"]".to_string()), HasText("b".to_string())],
+        );
+
+        // Plain `:not` and `:style` are still handled as regular CSS
+        let filter = CosmeticFilter::parse("example.com##.ad:not(.b)", false).unwrap();
+        assert_eq!(filter.procedural, None);
+        let filter = CosmeticFilter::parse("example.com##.ad:style(color: red)", false).unwrap();
+        assert_eq!(filter.procedural, None);
+        assert_eq!(filter.style, Some("color: red".to_string()));
+
+        check_procedural(
+            r#"example.com##div:has-text(")"):upward(1)"#,
+            vec![CssSelector("div".to_string()), HasText(r#"")""#.to_string()), NthAncestor(1)],
+        );
+        check_procedural(
+            r#"example.com##div:has-text(\)):upward(1)"#,
+            vec![CssSelector("div".to_string()), HasText(r#"\)"#.to_string()), NthAncestor(1)],
+        );
+        check_procedural(
+            "example.com##div:has-text(Don't miss)",
+            vec![CssSelector("div".to_string()), HasText("Don't miss".to_string())],
+        );
+
+        assert_eq!(CosmeticFilter::parse("##.ad:has-text(a)", false).err(), Some(CosmeticFilterError::GenericProcedural));
+        assert_eq!(CosmeticFilter::parse("~example.com##.ad:has-text(a)", false).err(), Some(CosmeticFilterError::GenericProcedural));
+        assert_eq!(CosmeticFilter::parse("~example.*##.ad:has-text(a)", false).err(), Some(CosmeticFilterError::GenericProcedural));
+        assert_eq!(CosmeticFilter::parse("example.com##.ad:remove():upward(2)", false).err(), Some(CosmeticFilterError::InvalidProceduralOperator));
+        assert_eq!(CosmeticFilter::parse("example.com##.ad:remove(x)", false).err(), Some(CosmeticFilterError::InvalidProceduralOperator));
+        // Actions can't be nested in other operators
+        assert_eq!(CosmeticFilter::parse("example.com##.ad:has(.b:remove())", false).err(), Some(CosmeticFilterError::InvalidProceduralOperator));
+        assert_eq!(CosmeticFilter::parse("example.com##.ad:not(:has(.b:has-text(c):remove()))", false).err(), Some(CosmeticFilterError::InvalidProceduralOperator));
+        assert_eq!(CosmeticFilter::parse("example.com##.ad:min-text-length(a)", false).err(), Some(CosmeticFilterError::InvalidProceduralOperator));
+        assert_eq!(CosmeticFilter::parse("example.com##.ad:has-text(a", false).err(), Some(CosmeticFilterError::InvalidProceduralOperator));
+    }
+
     #[test]
     fn hidden_generic() {
         let rule = CosmeticFilter::parse("##.selector", false).unwrap();
@@ -21,18 +21,32 @@ pub enum NetworkFilterError {
     NegatedRedirection,
     NegatedTag,
     NegatedGenericHide,
+    NegatedRemoveParam,
+    NegatedDenyallow,
+    DenyallowWithoutDomain,
+    NegatedMethod,
+    InvalidMethod,
+    NegatedHeader,
+    EmptyHeader,
     GenericHideWithoutException,
     EmptyRedirection,
+    InvalidRedirectPriority,
     UnrecognisedOption,
     NoRegex,
     FullRegexUnsupported,
     RegexParsingError(regex::Error),
     PunycodeError,
+    NegatedElemHide,
+    NegatedSpecificHide,
+    ElemHideWithoutException,
+    SpecificHideWithoutException,
+    NegatedGenericBlock,
+    GenericBlockWithoutException,
 }
 
 bitflags::bitflags! {
     #[derive(Serialize, Deserialize)]
-    pub struct NetworkFilterMask: u32 {
+    pub struct NetworkFilterMask: u64 {
         const FROM_IMAGE = 1; // 1 << 0;
         const FROM_MEDIA = 1 << 1;
         const FROM_OBJECT = 1 << 2;
@@ -48,12 +62,17 @@ bitflags::bitflags! {
         const FROM_HTTPS = 1 << 12;
         const IS_IMPORTANT = 1 << 13;
         const MATCH_CASE = 1 << 14;
-        const _FUZZY_MATCH = 1 << 15;    // Unused
+        const ELEM_HIDE = 1 << 15;
         const THIRD_PARTY = 1 << 16;
         const FIRST_PARTY = 1 << 17;
-        const _EXPLICIT_CANCEL = 1 << 26;   // Unused
+        const IS_REDIRECT_RULE = 1 << 26;
         const BAD_FILTER = 1 << 27;
         const GENERIC_HIDE = 1 << 30;
+        const IS_REMOVEPARAM = 1 << 31;
+        const SPECIFIC_HIDE = 1 << 32;
+        const GENERIC_BLOCK = 1 << 33;
+        // Exceptions without a resource, like `@@||example.com^$redirect-rule`
+        const ALL_REDIRECTS = 1 << 34;
 
         // full document rules tend to be handled differently
         const FROM_DOCUMENT = 1 << 29;
@@ -198,6 +217,35 @@ pub struct NetworkFilter {
     pub opt_domains_union: Option<Hash>,
     pub opt_not_domains_union: Option<Hash>,
 
+    #[serde(default)]
+    pub removeparam: Option<String>,
+    #[serde(default)]
+    pub redirect_priority: i32,
+
+    // Constraints on the request's own hostname, from `$to=` and `$denyallow=`
+    #[serde(default)]
+    pub opt_to_domains: Option<Vec<Hash>>,
+    #[serde(default)]
+    pub opt_not_to_domains: Option<Vec<Hash>>,
+    #[serde(default)]
+    pub opt_to_domains_union: Option<Hash>,
+    #[serde(default)]
+    pub opt_not_to_domains_union: Option<Hash>,
+
+    // HTTP methods from `$method=`
+    #[serde(default)]
+    pub opt_methods: Option<Vec<request::RequestMethod>>,
+    #[serde(default)]
+    pub opt_not_methods: Option<Vec<request::RequestMethod>>,
+
+    // Response header specification from `$header=name:value`
+    #[serde(default)]
+    pub header: Option<String>,
+
+    // Ids of the original filters that were fused into this one by the optimizer
+    #[serde(default)]
+    pub fused_ids: Option<Vec<Hash>>,
+
     // Regex compild lazily, using "Interior Mutability"
     // Arc (Atomic Reference Counter) allows for cloned NetworkFilters
     // to point to the same RwLock and what is inside.
@@ -206,7 +254,15 @@ pub struct NetworkFilter {
     // When the Regex hasn't been compiled, <None> is stored, afterwards Arc to Some<CompiledRegex>
     // to avoid expensive cloning of the Regex itself.
     #[serde(skip_serializing, skip_deserializing)]
-    regex: Arc<RwLock<Option<Arc<CompiledRegex>>>>
+    regex: Arc<RwLock<Option<Arc<CompiledRegex>>>>,
+
+    // The `/regex/` value of `$removeparam`, compiled while parsing or lazily after deserialization
+    #[serde(skip_serializing, skip_deserializing)]
+    removeparam_regex: Arc<RwLock<Option<Arc<CompiledRegex>>>>,
+
+    // The `/regex/` value of `$header`, compiled while parsing or lazily after deserialization
+    #[serde(skip_serializing, skip_deserializing)]
+    header_regex: Arc<RwLock<Option<Arc<CompiledRegex>>>>,
 }
 
 impl NetworkFilter {
@@ -227,11 +283,19 @@ impl NetworkFilter {
 
         let mut opt_domains: Option<Vec<Hash>> = None;
         let mut opt_not_domains: Option<Vec<Hash>> = None;
-        let mut opt_domains_union: Option<Hash> = None;
-        let mut opt_not_domains_union: Option<Hash> = None;
+        let mut opt_to_domains: Option<Vec<Hash>> = None;
+        let mut opt_not_to_domains: Option<Vec<Hash>> = None;
+        let mut has_denyallow = false;
+        let mut opt_methods: Option<Vec<request::RequestMethod>> = None;
+        let mut opt_not_methods: Option<Vec<request::RequestMethod>> = None;
+        let mut header: Option<String> = None;
 
         let mut redirect: Option<String> = None;
+        let mut redirect_priority: i32 = 0;
         let mut csp: Option<String> = None;
+        let mut removeparam: Option<String> = None;
+        let mut removeparam_regex: Option<Regex> = None;
+        let mut header_regex: Option<Regex> = None;
         let mut bug: Option<u32> = None;
         let mut tag: Option<String> = None;
 
@@ -250,58 +314,81 @@ impl NetworkFilter {
         // |     |
         // |     optionsIndex
         // filterIndexStart
-        let maybe_options_index: Option<usize> = twoway::rfind_str(&line, "$");
-
-        if let Some(options_index) = maybe_options_index {
+        if let Some(raw_options) = raw_options(line) {
             // Parse options and set flags
-            filter_index_end = options_index;
+            filter_index_end = line.len() - raw_options.len() - 1;
 
             // Parse Options
-            let raw_options = &line[filter_index_end + 1..];    // safe, first character after '$' will be char boundary
-            let options = raw_options.split(',');
-            for raw_option in options {
-                // Check for negation: ~option
-                let negation = raw_option.starts_with('~');
-                let maybe_negated_option = raw_option.trim_start_matches('~');
-
-                // Check for options: option=value1|value2
-                let mut option_and_values = maybe_negated_option.splitn(2, '=');
-                let (option, value) = (
-                    option_and_values.next().unwrap(),
-                    option_and_values.next().unwrap_or_default(),
-                );
-
+            for (option, negation, value) in split_options(raw_options) {
                 match (option, negation) {
-                    ("domain", _) => {
-                        let mut option_values: Vec<&str> = value.split('|').collect();
-                        // Some rules have duplicate domain options - avoid including duplicates
-                        // Benchmarking doesn't indicate signficant performance degradation across the entire easylist
-                        option_values.sort_unstable();
-                        option_values.dedup();
-                        let mut opt_domains_array: Vec<Hash> = vec![];
-                        let mut opt_not_domains_array: Vec<Hash> = vec![];
-
-                        for option_value in option_values {
-                            if option_value.starts_with('~') {
-                                let domain = &option_value[1..];
-                                let domain_hash = utils::fast_hash(&domain);
-                                opt_not_domains_array.push(domain_hash);
+                    // `$from=` is uBO's alias for `$domain=`
+                    ("domain", _) | ("from", _) => {
+                        let (domains, not_domains) = parse_domains_option(value);
+                        opt_domains = domains;
+                        opt_not_domains = not_domains;
+                    }
+                    ("to", _) => {
+                        let (domains, not_domains) = parse_domains_option(value);
+                        if let Some(mut domains) = domains {
+                            opt_to_domains.get_or_insert_with(Vec::new).append(&mut domains);
+                        }
+                        if let Some(mut not_domains) = not_domains {
+                            opt_not_to_domains.get_or_insert_with(Vec::new).append(&mut not_domains);
+                        }
+                    }
+                    ("denyallow", true) => return Err(NetworkFilterError::NegatedDenyallow),
+                    // `$denyallow=a.com` is equivalent to `$to=~a.com`
+                    ("denyallow", false) => {
+                        let (domains, not_domains) = parse_domains_option(value);
+                        if not_domains.is_some() {
+                            return Err(NetworkFilterError::NegatedDenyallow);
+                        }
+                        if let Some(mut domains) = domains {
+                            opt_not_to_domains.get_or_insert_with(Vec::new).append(&mut domains);
+                        }
+                        has_denyallow = true;
+                    }
+                    ("method", true) => return Err(NetworkFilterError::NegatedMethod),
+                    ("method", false) => {
+                        let mut methods = vec![];
+                        let mut not_methods = vec![];
+                        for method in value.split('|') {
+                            let (negated, name) = if let Some(name) = method.strip_prefix('~') {
+                                (true, name)
+                            } else {
+                                (false, method)
+                            };
+                            let method = request::RequestMethod::from_name(name).ok_or(NetworkFilterError::InvalidMethod)?;
+                            if negated {
+                                not_methods.push(method);
                             } else {
-                                let domain_hash = utils::fast_hash(&option_value);
-                                opt_domains_array.push(domain_hash);
+                                methods.push(method);
                             }
                         }
-
-                        if !opt_domains_array.is_empty() {
-                            opt_domains_array.sort_unstable();
-                            opt_domains_union = Some(opt_domains_array.iter().fold(0, |acc, x| acc | x));
-                            opt_domains = Some(opt_domains_array);
+                        opt_methods = if methods.is_empty() { None } else { Some(methods) };
+                        opt_not_methods = if not_methods.is_empty() { None } else { Some(not_methods) };
+                    }
+                    ("header", true) => return Err(NetworkFilterError::NegatedHeader),
+                    ("header", false) => {
+                        let (name, header_value) = match value.find(':') {
+                            Some(colon) => (&value[..colon], Some(&value[colon + 1..])),
+                            None => (value, None),
+                        };
+                        if name.is_empty() {
+                            return Err(NetworkFilterError::EmptyHeader);
                         }
-                        if !opt_not_domains_array.is_empty() {
-                            opt_not_domains_array.sort_unstable();
-                            opt_not_domains_union = Some(opt_not_domains_array.iter().fold(0, |acc, x| acc | x));
-                            opt_not_domains = Some(opt_not_domains_array);
+                        // Compile `/regex/` values ahead of time, which also makes sure they're valid
+                        if let Some(pattern) = header_value.and_then(|v| regex_option_pattern(v.trim_start_matches('~'))) {
+                            match Regex::new(pattern) {
+                                Ok(regex) => header_regex = Some(regex),
+                                Err(e) => return Err(NetworkFilterError::RegexParsingError(e)),
+                            }
                         }
+                        // Header names are case-insensitive
+                        header = Some(match header_value {
+                            Some(header_value) => format!("{}:{}", name.to_ascii_lowercase(), header_value),
+                            None => name.to_ascii_lowercase(),
+                        });
                     }
                     ("badfilter", false) => mask.set(NetworkFilterMask::BAD_FILTER, true),
                     ("badfilter", true) => return Err(NetworkFilterError::NegatedBadFilter),
@@ -327,14 +414,34 @@ impl NetworkFilter {
                     ("tag", false) => tag = Some(String::from(value)),
                     ("tag", true) => return Err(NetworkFilterError::NegatedTag),
                     // Negation of redirection doesn't make sense
-                    ("redirect", true) => return Err(NetworkFilterError::NegatedRedirection),
-                    ("redirect", false) => {
-                        // Ignore this filter if no redirection resource is specified
-                        if value.is_empty() {
-                            return Err(NetworkFilterError::EmptyRedirection);
+                    ("redirect", true) | ("redirect-rule", true) => return Err(NetworkFilterError::NegatedRedirection),
+                    ("redirect", false) | ("redirect-rule", false) => {
+                        // `$redirect-rule` filters only redirect requests blocked by other filters
+                        if option == "redirect-rule" {
+                            mask.set(NetworkFilterMask::IS_REDIRECT_RULE, true);
                         }
 
-                        redirect = Some(String::from(value));
+                        // Resources may be suffixed with a priority: `$redirect=resource:priority`
+                        let resource = if let Some(priority_index) = value.rfind(':') {
+                            redirect_priority = value[priority_index + 1..]
+                                .parse::<i32>()
+                                .map_err(|_| NetworkFilterError::InvalidRedirectPriority)?;
+                            &value[..priority_index]
+                        } else {
+                            value
+                        };
+
+                        // Ignore this filter if no redirection resource is specified. Exceptions
+                        // without a resource, like `@@||example.com^$redirect-rule`, are kept
+                        // and disable every redirection instead.
+                        if resource.is_empty() {
+                            if !mask.contains(NetworkFilterMask::IS_EXCEPTION) {
+                                return Err(NetworkFilterError::EmptyRedirection);
+                            }
+                            mask.set(NetworkFilterMask::ALL_REDIRECTS, true);
+                        } else {
+                            redirect = Some(String::from(resource));
+                        }
                     }
                     ("csp", _) => {
                         mask.set(NetworkFilterMask::IS_CSP, true);
@@ -342,10 +449,34 @@ impl NetworkFilter {
                             csp = Some(String::from(value));
                         }
                     }
+                    ("removeparam", true) => return Err(NetworkFilterError::NegatedRemoveParam),
+                    ("removeparam", false) => {
+                        mask.set(NetworkFilterMask::IS_REMOVEPARAM, true);
+                        if !value.is_empty() {
+                            // Compile `/regex/` values ahead of time, which also makes sure they're valid
+                            if let Some(pattern) = regex_option_pattern(value.trim_start_matches('~')) {
+                                match Regex::new(pattern) {
+                                    Ok(regex) => removeparam_regex = Some(regex),
+                                    Err(e) => return Err(NetworkFilterError::RegexParsingError(e)),
+                                }
+                            }
+                            removeparam = Some(String::from(value));
+                        }
+                    }
                     ("generichide", true) => return Err(NetworkFilterError::NegatedGenericHide),
                     ("generichide", false) => mask.set(NetworkFilterMask::GENERIC_HIDE, true),
                     ("ghide", true) => return Err(NetworkFilterError::NegatedGenericHide),
                     ("ghide", false) => mask.set(NetworkFilterMask::GENERIC_HIDE, true),
+                    ("elemhide", true) => return Err(NetworkFilterError::NegatedElemHide),
+                    ("elemhide", false) => mask.set(NetworkFilterMask::ELEM_HIDE, true),
+                    ("ehide", true) => return Err(NetworkFilterError::NegatedElemHide),
+                    ("ehide", false) => mask.set(NetworkFilterMask::ELEM_HIDE, true),
+                    ("specifichide", true) => return Err(NetworkFilterError::NegatedSpecificHide),
+                    ("specifichide", false) => mask.set(NetworkFilterMask::SPECIFIC_HIDE, true),
+                    ("shide", true) => return Err(NetworkFilterError::NegatedSpecificHide),
+                    ("shide", false) => mask.set(NetworkFilterMask::SPECIFIC_HIDE, true),
+                    ("genericblock", true) => return Err(NetworkFilterError::NegatedGenericBlock),
+                    ("genericblock", false) => mask.set(NetworkFilterMask::GENERIC_BLOCK, true),
                     (_, negation) => {
                         // Handle content type options separatly
                         let mut option_mask = NetworkFilterMask::NONE;
@@ -560,6 +691,31 @@ impl NetworkFilter {
         if mask.contains(NetworkFilterMask::GENERIC_HIDE) && !mask.contains(NetworkFilterMask::IS_EXCEPTION) {
             return Err(NetworkFilterError::GenericHideWithoutException);
         }
+        if mask.contains(NetworkFilterMask::ELEM_HIDE) && !mask.contains(NetworkFilterMask::IS_EXCEPTION) {
+            return Err(NetworkFilterError::ElemHideWithoutException);
+        }
+        if mask.contains(NetworkFilterMask::SPECIFIC_HIDE) && !mask.contains(NetworkFilterMask::IS_EXCEPTION) {
+            return Err(NetworkFilterError::SpecificHideWithoutException);
+        }
+        if mask.contains(NetworkFilterMask::GENERIC_BLOCK) && !mask.contains(NetworkFilterMask::IS_EXCEPTION) {
+            return Err(NetworkFilterError::GenericBlockWithoutException);
+        }
+
+        // `$denyallow` is only allowed in combination with `$domain`, to avoid overly broad rules
+        if has_denyallow && opt_domains.is_none() {
+            return Err(NetworkFilterError::DenyallowWithoutDomain);
+        }
+
+        // Several `$to` and `$denyallow` options may contribute domains
+        for to_domains in opt_to_domains.iter_mut().chain(opt_not_to_domains.iter_mut()) {
+            to_domains.sort_unstable();
+            to_domains.dedup();
+        }
+
+        let opt_domains_union = domains_union(opt_domains.as_ref());
+        let opt_not_domains_union = domains_union(opt_not_domains.as_ref());
+        let opt_to_domains_union = domains_union(opt_to_domains.as_ref());
+        let opt_not_to_domains_union = domains_union(opt_not_to_domains.as_ref());
 
         Ok(NetworkFilter {
             bug,
@@ -584,7 +740,19 @@ impl NetworkFilter {
             _fuzzy_signature: None,
             opt_domains_union,
             opt_not_domains_union,
-            regex: Arc::new(RwLock::new(None))
+            removeparam,
+            redirect_priority,
+            opt_to_domains,
+            opt_not_to_domains,
+            opt_to_domains_union,
+            opt_not_to_domains_union,
+            opt_methods,
+            opt_not_methods,
+            header,
+            fused_ids: None,
+            regex: Arc::new(RwLock::new(None)),
+            removeparam_regex: Arc::new(RwLock::new(removeparam_regex.map(|regex| Arc::new(CompiledRegex::Compiled(regex))))),
+            header_regex: Arc::new(RwLock::new(header_regex.map(|regex| Arc::new(CompiledRegex::Compiled(regex))))),
         })
     }
 
@@ -625,22 +793,48 @@ impl NetworkFilter {
         mask.set(NetworkFilterMask::BAD_FILTER, false);
         compute_filter_id(
             self.csp.as_deref(),
+            self.removeparam.as_deref(),
             mask,
             self.filter.string_view().as_deref(),
             self.hostname.as_deref(),
             self.opt_domains.as_ref(),
             self.opt_not_domains.as_ref(),
+            self.opt_to_domains.as_ref(),
+            self.opt_not_to_domains.as_ref(),
+            self.opt_methods.as_ref(),
+            self.opt_not_methods.as_ref(),
+            self.header.as_deref(),
+            self.redirect_priority,
         )
     }
 
+    /// Discards the compiled regex, which must be done whenever the pattern of the filter changes.
+    pub(crate) fn reset_regex(&mut self) {
+        self.regex = Arc::new(RwLock::new(None));
+    }
+
+    /// Returns the compiled regex of a `$removeparam=/regex/` filter, ignoring any leading `~`, or
+    /// `None` if the value of the option isn't a regex.
+    pub(crate) fn get_removeparam_regex(&self) -> Option<Arc<CompiledRegex>> {
+        let pattern = regex_option_pattern(self.removeparam.as_deref()?.trim_start_matches('~'))?;
+        Some(get_cached_option_regex(&self.removeparam_regex, pattern))
+    }
+
     pub fn get_id(&self) -> Hash {
         compute_filter_id(
             self.csp.as_deref(),
+            self.removeparam.as_deref(),
             self.mask,
             self.filter.string_view().as_deref(),
             self.hostname.as_deref(),
             self.opt_domains.as_ref(),
             self.opt_not_domains.as_ref(),
+            self.opt_to_domains.as_ref(),
+            self.opt_not_to_domains.as_ref(),
+            self.opt_methods.as_ref(),
+            self.opt_not_methods.as_ref(),
+            self.header.as_deref(),
+            self.redirect_priority,
         )
     }
 
@@ -737,7 +931,16 @@ impl NetworkFilter {
     }
 
     pub fn is_redirect(&self) -> bool {
-        self.redirect.is_some()
+        self.redirect.is_some() || self.excepts_all_redirects()
+    }
+
+    /// Whether this is a redirect exception without a resource, which disables every redirection.
+    pub fn excepts_all_redirects(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::ALL_REDIRECTS)
+    }
+
+    pub fn is_redirect_rule(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::IS_REDIRECT_RULE)
     }
 
     pub fn is_badfilter(&self) -> bool {
@@ -748,6 +951,75 @@ impl NetworkFilter {
         self.mask.contains(NetworkFilterMask::GENERIC_HIDE)
     }
 
+    pub fn is_elem_hide(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::ELEM_HIDE)
+    }
+
+    pub fn is_specific_hide(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::SPECIFIC_HIDE)
+    }
+
+    /// Whether this filter explicitly applies to document requests, with `$document`.
+    pub fn is_document(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::FROM_DOCUMENT)
+    }
+
+    pub fn is_generic_block(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::GENERIC_BLOCK)
+    }
+
+    /// Whether this filter applies on any page, i.e. it isn't restricted to specific sites with
+    /// `$domain`. Such filters are disabled by `$genericblock` exceptions.
+    pub fn is_generic(&self) -> bool {
+        self.opt_domains.is_none()
+    }
+
+    /// Whether this filter matches the top-level document of a page, knowing only the hashes of
+    /// the page's hostname and its parent domains. Used for page-level exceptions like
+    /// `$genericblock`. The path of the page is not known, so only filters without a path, like
+    /// `@@||example.com^` or `@@$domain=example.com`, can match.
+    pub(crate) fn matches_document(&self, hostname_hashes: Option<&Vec<Hash>>) -> bool {
+        let hashes = match hostname_hashes {
+            Some(hashes) => hashes,
+            None => return false,
+        };
+        // A top-level document is first-party to itself
+        if self.is_badfilter() || !self.first_party() {
+            return false;
+        }
+
+        let pattern_matches = match (&self.hostname, &self.filter) {
+            (None, FilterPart::Empty) => !self.is_hostname_anchor(),
+            (Some(hostname), FilterPart::Empty) => !self.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)
+                && hashes.contains(&utils::fast_hash(hostname)),
+            (Some(hostname), FilterPart::Simple(path)) if path == "/" => !self.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX)
+                && hashes.contains(&utils::fast_hash(hostname)),
+            _ => false,
+        };
+
+        pattern_matches
+            && check_domains(
+                Some(hashes),
+                self.opt_domains.as_ref(),
+                self.opt_domains_union,
+                self.opt_not_domains.as_ref(),
+                self.opt_not_domains_union,
+            )
+            && check_domains(
+                Some(hashes),
+                self.opt_to_domains.as_ref(),
+                self.opt_to_domains_union,
+                self.opt_not_to_domains.as_ref(),
+                self.opt_not_to_domains_union,
+            )
+    }
+
+    /// Whether this filter disables some or all cosmetic filtering on matching pages, rather than
+    /// blocking or allowing requests.
+    pub fn is_cosmetic_hide_exception(&self) -> bool {
+        self.mask.intersects(NetworkFilterMask::GENERIC_HIDE | NetworkFilterMask::ELEM_HIDE | NetworkFilterMask::SPECIFIC_HIDE)
+    }
+
     pub fn is_regex(&self) -> bool {
         self.mask.contains(NetworkFilterMask::IS_REGEX)
     }
@@ -764,6 +1036,44 @@ impl NetworkFilter {
         self.mask.contains(NetworkFilterMask::IS_CSP)
     }
 
+    pub fn is_removeparam(&self) -> bool {
+        self.mask.contains(NetworkFilterMask::IS_REMOVEPARAM)
+    }
+
+    pub fn is_header(&self) -> bool {
+        self.header.is_some()
+    }
+
+    /// Checks the `$header=name:value` option of this filter against a set of response headers.
+    /// Without a value, the header only needs to be present. The value can be a literal or a
+    /// `/regex/`, and a leading `~` inverts the value test. Filters without a `$header` option
+    /// never match.
+    pub fn matches_headers(&self, headers: &[(&str, &str)]) -> bool {
+        let header = match self.header.as_deref() {
+            Some(header) => header,
+            None => return false,
+        };
+        let (name, value) = match header.find(':') {
+            Some(colon) => (&header[..colon], Some(&header[colon + 1..])),
+            None => (header, None),
+        };
+
+        headers.iter()
+            .filter(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .any(|(_, header_value)| match value {
+                None => true,
+                Some(value) => {
+                    let negated = value.starts_with('~');
+                    let value = value.trim_start_matches('~');
+                    let matches = match regex_option_pattern(value) {
+                        Some(pattern) => get_cached_option_regex(&self.header_regex, pattern).is_match(header_value),
+                        None => value == *header_value,
+                    };
+                    matches != negated
+                }
+            })
+    }
+
     pub fn has_bug(&self) -> bool {
         self.bug.is_some()
     }
@@ -838,16 +1148,113 @@ impl NetworkMatchable for NetworkFilter {
 // Filter parsing
 // ---------------------------------------------------------------------------
 
+/// Splits the raw options of a network filter, i.e. what follows its last `$`, into the name,
+/// negation and value of each option: `~option=value1|value2`.
+pub(crate) fn split_options(raw_options: &str) -> impl Iterator<Item = (&str, bool, &str)> {
+    raw_options.split(',').map(|raw_option| {
+        // Check for negation: ~option
+        let negation = raw_option.starts_with('~');
+        let maybe_negated_option = raw_option.trim_start_matches('~');
+
+        // Check for options: option=value1|value2
+        let mut option_and_values = maybe_negated_option.splitn(2, '=');
+        let option = option_and_values.next().unwrap_or_default();
+        let value = option_and_values.next().unwrap_or_default();
+        (option, negation, value)
+    })
+}
+
+/// Returns the options of a network filter line as parsed by `NetworkFilter::parse`, or `None` if
+/// it has none.
+pub(crate) fn raw_options(line: &str) -> Option<&str> {
+    twoway::rfind_str(line, "$").map(|options_index| &line[options_index + 1..])
+}
+
+/// Returns the compiled `pattern` of a `/regex/` option value from `cache`, compiling it on first
+/// use.
+fn get_cached_option_regex(cache: &RwLock<Option<Arc<CompiledRegex>>>, pattern: &str) -> Arc<CompiledRegex> {
+    {
+        let cache = cache.read().unwrap();
+        if let Some(regex) = cache.as_ref() {
+            return regex.clone();
+        }
+    }
+    let mut cache = cache.write().unwrap();
+    let regex = Arc::new(match Regex::new(pattern) {
+        Ok(regex) => CompiledRegex::Compiled(regex),
+        Err(e) => CompiledRegex::RegexParsingError(e),
+    });
+    *cache = Some(regex.clone());
+    regex
+}
+
+/// Returns the regex inside a `/regex/` value of `$removeparam` or `$header`, or `None` for a plain
+/// value.
+fn regex_option_pattern(value: &str) -> Option<&str> {
+    if value.len() > 1 && value.starts_with('/') && value.ends_with('/') {
+        Some(&value[1..value.len() - 1])
+    } else {
+        None
+    }
+}
+
+/// Splits the value of a `domain=`-like option into sorted lists of included and excluded domain
+/// hashes.
+fn parse_domains_option(value: &str) -> (Option<Vec<Hash>>, Option<Vec<Hash>>) {
+    let mut option_values: Vec<&str> = value.split('|').collect();
+    // Some rules have duplicate domain options - avoid including duplicates
+    // Benchmarking doesn't indicate signficant performance degradation across the entire easylist
+    option_values.sort_unstable();
+    option_values.dedup();
+    let mut domains: Vec<Hash> = vec![];
+    let mut not_domains: Vec<Hash> = vec![];
+
+    for option_value in option_values {
+        if option_value.starts_with('~') {
+            let domain = &option_value[1..];
+            not_domains.push(utils::fast_hash(&domain));
+        } else {
+            domains.push(utils::fast_hash(&option_value));
+        }
+    }
+
+    domains.sort_unstable();
+    not_domains.sort_unstable();
+
+    (
+        if domains.is_empty() { None } else { Some(domains) },
+        if not_domains.is_empty() { None } else { Some(not_domains) },
+    )
+}
+
+/// All domain hashes OR'ed together, used to quickly dismiss mis-matches.
+fn domains_union(domains: Option<&Vec<Hash>>) -> Option<Hash> {
+    domains.map(|domains| domains.iter().fold(0, |acc, x| acc | x))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn compute_filter_id(
     csp: Option<&str>,
+    removeparam: Option<&str>,
     mask: NetworkFilterMask,
     filter: Option<&str>,
     hostname: Option<&str>,
     opt_domains: Option<&Vec<Hash>>,
     opt_not_domains: Option<&Vec<Hash>>,
+    opt_to_domains: Option<&Vec<Hash>>,
+    opt_not_to_domains: Option<&Vec<Hash>>,
+    opt_methods: Option<&Vec<request::RequestMethod>>,
+    opt_not_methods: Option<&Vec<request::RequestMethod>>,
+    header: Option<&str>,
+    redirect_priority: i32,
 ) -> Hash {
     let mut hash: Hash = (5408 * 33) ^ Hash::from(mask.bits);
 
+    // Only hashed when set, so that ids of other filters are unchanged
+    if redirect_priority != 0 {
+        hash = hash.wrapping_mul(33) ^ (redirect_priority as Hash);
+    }
+
     if let Some(s) = csp {
         let chars = s.chars();
         for c in chars {
@@ -855,6 +1262,20 @@ fn compute_filter_id(
         }
     };
 
+    if let Some(s) = removeparam {
+        let chars = s.chars();
+        for c in chars {
+            hash = hash.wrapping_mul(33) ^ (c as Hash);
+        }
+    };
+
+    if let Some(s) = header {
+        let chars = s.chars();
+        for c in chars {
+            hash = hash.wrapping_mul(33) ^ (c as Hash);
+        }
+    };
+
     if let Some(domains) = opt_domains {
         for d in domains {
             hash = hash.wrapping_mul(33) ^ d;
@@ -867,6 +1288,30 @@ fn compute_filter_id(
         }
     }
 
+    if let Some(domains) = opt_to_domains {
+        for d in domains {
+            hash = hash.wrapping_mul(33) ^ d;
+        }
+    }
+
+    if let Some(domains) = opt_not_to_domains {
+        for d in domains {
+            hash = hash.wrapping_mul(33) ^ d;
+        }
+    }
+
+    if let Some(methods) = opt_methods {
+        for m in methods {
+            hash = hash.wrapping_mul(33) ^ (*m as Hash);
+        }
+    }
+
+    if let Some(methods) = opt_not_methods {
+        for m in methods {
+            hash = hash.wrapping_mul(33) ^ (*m as Hash);
+        }
+    }
+
     if let Some(s) = filter {
         let chars = s.chars();
         for c in chars {
@@ -1294,7 +1739,7 @@ fn check_pattern(filter: &NetworkFilter, request: &request::Request) -> bool {
 
 pub fn check_cpt_allowed(filter: &NetworkFilter, cpt: &request::RequestType) -> bool {
     match NetworkFilterMask::from(cpt) {
-        NetworkFilterMask::FROM_DOCUMENT => filter.get_cpt_mask().contains(NetworkFilterMask::FROM_DOCUMENT) || filter.is_exception(),
+        NetworkFilterMask::FROM_DOCUMENT => filter.get_cpt_mask().contains(NetworkFilterMask::FROM_DOCUMENT) || filter.is_exception() || filter.is_csp() || filter.is_removeparam(),
         mask => filter.mask.contains(mask),
     }
 }
@@ -1321,33 +1766,87 @@ fn check_options(filter: &NetworkFilter, request: &request::Request) -> bool {
         return false;
     }
 
+    // Request method must be among these methods to match
+    if let Some(methods) = filter.opt_methods.as_ref() {
+        if !request.method.map(|m| methods.contains(&m)).unwrap_or(false) {
+            return false;
+        }
+    }
+    if let Some(methods) = filter.opt_not_methods.as_ref() {
+        if request.method.map(|m| methods.contains(&m)).unwrap_or(false) {
+            return false;
+        }
+    }
+
     // Source URL must be among these domains to match
-    if let Some(included_domains) = filter.opt_domains.as_ref() {
-        if let Some(source_hashes) = request.source_hostname_hashes.as_ref() {
-            // If the union of included domains is recorded
-            if let Some(included_domains_union) = filter.opt_domains_union {
-                // If there isn't any source hash that matches the union, there's no match at all
-                if source_hashes.iter().all(|h| h & included_domains_union != *h) {
-                    return false
-                }
+    if !check_domains(
+        request.source_hostname_hashes.as_ref(),
+        filter.opt_domains.as_ref(),
+        filter.opt_domains_union,
+        filter.opt_not_domains.as_ref(),
+        filter.opt_not_domains_union,
+    ) {
+        return false;
+    }
+
+    // Request URL must be among these domains to match
+    if filter.opt_to_domains.is_some() || filter.opt_not_to_domains.is_some() {
+        match request.hostname_hashes() {
+            // A request without a hostname can't be among the included domains
+            None if filter.opt_to_domains.is_some() => return false,
+            None => (),
+            hashes => if !check_domains(
+                hashes,
+                filter.opt_to_domains.as_ref(),
+                filter.opt_to_domains_union,
+                filter.opt_not_to_domains.as_ref(),
+                filter.opt_not_to_domains_union,
+            ) {
+                return false;
             }
-            if source_hashes.iter().all(|h| !utils::bin_lookup(&included_domains, *h)) {
+        }
+    }
+
+    true
+}
+
+/// Checks the hashes of a hostname and its parent domains against a filter's included and
+/// excluded domains. The unions of each domain list are used to quickly dismiss mis-matches.
+#[inline]
+fn check_domains(
+    hashes: Option<&Vec<Hash>>,
+    included_domains: Option<&Vec<Hash>>,
+    included_domains_union: Option<Hash>,
+    excluded_domains: Option<&Vec<Hash>>,
+    excluded_domains_union: Option<Hash>,
+) -> bool {
+    let hashes = match hashes {
+        Some(hashes) => hashes,
+        None => return true,
+    };
+
+    if let Some(included_domains) = included_domains {
+        // If the union of included domains is recorded
+        if let Some(included_domains_union) = included_domains_union {
+            // If there isn't any hash that matches the union, there's no match at all
+            if hashes.iter().all(|h| h & included_domains_union != *h) {
                 return false
             }
         }
+        if hashes.iter().all(|h| !utils::bin_lookup(&included_domains, *h)) {
+            return false
+        }
     }
 
-    if let Some(excluded_domains) = filter.opt_not_domains.as_ref() {
-        if let Some(source_hashes) = request.source_hostname_hashes.as_ref() {
-            // If the union of excluded domains is recorded
-            if let Some(excluded_domains_union) = filter.opt_not_domains_union {
-                // If there's any source hash that matches the union, check the actual values
-                if source_hashes.iter().any(|h| (h & excluded_domains_union == *h) && utils::bin_lookup(&excluded_domains, *h)) {
-                    return false
-                }
-            } else if source_hashes.iter().any(|h| utils::bin_lookup(&excluded_domains, *h)) {
+    if let Some(excluded_domains) = excluded_domains {
+        // If the union of excluded domains is recorded
+        if let Some(excluded_domains_union) = excluded_domains_union {
+            // If there's any hash that matches the union, check the actual values
+            if hashes.iter().any(|h| (h & excluded_domains_union == *h) && utils::bin_lookup(&excluded_domains, *h)) {
                 return false
             }
+        } else if hashes.iter().any(|h| utils::bin_lookup(&excluded_domains, *h)) {
+            return false
         }
     }
 
@@ -1944,6 +2443,134 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn parses_removeparam() {
+        {
+            let filter = NetworkFilter::parse("||foo.com", true).unwrap();
+            assert_eq!(filter.is_removeparam(), false);
+            assert_eq!(filter.removeparam, None);
+        }
+        {
+            // parses a plain parameter name
+            let filter = NetworkFilter::parse("||foo.com^$removeparam=utm_source", true).unwrap();
+            assert_eq!(filter.is_removeparam(), true);
+            assert_eq!(filter.removeparam, Some(String::from("utm_source")));
+            assert!(filter.get_removeparam_regex().is_none());
+        }
+        {
+            // parses an empty value, which removes all parameters
+            let filter = NetworkFilter::parse("||foo.com^$removeparam", true).unwrap();
+            assert_eq!(filter.is_removeparam(), true);
+            assert_eq!(filter.removeparam, None);
+        }
+        {
+            // parses negated and regex values
+            let filter = NetworkFilter::parse("$removeparam=~id", true).unwrap();
+            assert_eq!(filter.removeparam, Some(String::from("~id")));
+            let filter = NetworkFilter::parse("$removeparam=/^utm_/,domain=foo.com", true).unwrap();
+            assert_eq!(filter.removeparam, Some(String::from("/^utm_/")));
+            assert!(filter.get_removeparam_regex().unwrap().is_match("utm_source=1"));
+            // the regex is compiled once while parsing and shared by clones of the filter
+            assert!(Arc::ptr_eq(&filter.get_removeparam_regex().unwrap(), &filter.clone().get_removeparam_regex().unwrap()));
+            assert!(filter.opt_domains.is_some());
+        }
+        {
+            // exceptions
+            let filter = NetworkFilter::parse("@@||foo.com^$removeparam=utm_source", true).unwrap();
+            assert_eq!(filter.is_removeparam(), true);
+            assert_eq!(filter.is_exception(), true);
+        }
+        // different values produce different ids
+        assert_ne!(
+            NetworkFilter::parse("||foo.com^$removeparam=a", true).unwrap().get_id(),
+            NetworkFilter::parse("||foo.com^$removeparam=b", true).unwrap().get_id(),
+        );
+        assert_eq!(NetworkFilter::parse("||foo.com^$~removeparam", true).err(), Some(NetworkFilterError::NegatedRemoveParam));
+        assert!(matches!(NetworkFilter::parse("||foo.com^$removeparam=/(/", true), Err(NetworkFilterError::RegexParsingError(_))));
+    }
+
+    #[test]
+    fn parses_to_and_denyallow() {
+        {
+            // `from` is an alias of `domain`
+            let filter = NetworkFilter::parse("||foo.com$from=bar.com|~baz.com", true).unwrap();
+            assert_eq!(filter.opt_domains, Some(vec![utils::fast_hash("bar.com")]));
+            assert_eq!(filter.opt_not_domains, Some(vec![utils::fast_hash("baz.com")]));
+        }
+        {
+            let filter = NetworkFilter::parse("*$script,to=foo.com|~bar.foo.com", true).unwrap();
+            assert_eq!(filter.opt_to_domains, Some(vec![utils::fast_hash("foo.com")]));
+            assert_eq!(filter.opt_to_domains_union, Some(utils::fast_hash("foo.com")));
+            assert_eq!(filter.opt_not_to_domains, Some(vec![utils::fast_hash("bar.foo.com")]));
+            assert_eq!(filter.opt_domains, None);
+        }
+        {
+            // `denyallow` and negated `to` domains are combined
+            let filter = NetworkFilter::parse("*$script,domain=a.com,denyallow=b.com|c.com,to=~d.com", true).unwrap();
+            let mut domains = vec![utils::fast_hash("b.com"), utils::fast_hash("c.com"), utils::fast_hash("d.com")];
+            domains.sort_unstable();
+            assert_eq!(filter.opt_not_to_domains, Some(domains));
+            assert_eq!(filter.opt_to_domains, None);
+        }
+        {
+            // repeated `to` options are merged
+            let filter = NetworkFilter::parse("*$script,to=b.com,to=a.com|b.com", true).unwrap();
+            let mut domains = vec![utils::fast_hash("a.com"), utils::fast_hash("b.com")];
+            domains.sort_unstable();
+            assert_eq!(filter.opt_to_domains, Some(domains));
+        }
+        // `to` domains contribute to the id
+        assert_ne!(
+            NetworkFilter::parse("*$script,to=a.com", true).unwrap().get_id(),
+            NetworkFilter::parse("*$script,to=b.com", true).unwrap().get_id(),
+        );
+        assert_eq!(NetworkFilter::parse("*$script,denyallow=a.com", true).err(), Some(NetworkFilterError::DenyallowWithoutDomain));
+        assert_eq!(NetworkFilter::parse("*$script,domain=a.com,denyallow=~b.com", true).err(), Some(NetworkFilterError::NegatedDenyallow));
+        assert_eq!(NetworkFilter::parse("*$script,domain=a.com,~denyallow=b.com", true).err(), Some(NetworkFilterError::NegatedDenyallow));
+    }
+
+    #[test]
+    fn parses_header() {
+        let filter = NetworkFilter::parse("||foo.com^$header=Via:1.1 google", true).unwrap();
+        assert_eq!(filter.header, Some(String::from("via:1.1 google")));
+        assert!(filter.is_header());
+        let filter = NetworkFilter::parse("||foo.com^$header=x-ad", true).unwrap();
+        assert_eq!(filter.header, Some(String::from("x-ad")));
+        let filter = NetworkFilter::parse("||foo.com^", true).unwrap();
+        assert!(!filter.is_header());
+        assert!(!filter.matches_headers(&[("x-ad", "1")]));
+        // `/regex/` values are compiled once while parsing
+        let filter = NetworkFilter::parse("||foo.com^$header=x-ad:~/^1/", true).unwrap();
+        assert!(filter.header_regex.read().unwrap().is_some());
+        assert!(filter.matches_headers(&[("X-Ad", "2")]));
+        assert!(!filter.matches_headers(&[("x-ad", "10")]));
+        assert_ne!(
+            NetworkFilter::parse("||foo.com^$header=a", true).unwrap().get_id(),
+            NetworkFilter::parse("||foo.com^$header=b", true).unwrap().get_id(),
+        );
+        assert_eq!(NetworkFilter::parse("||foo.com^$header=:value", true).err(), Some(NetworkFilterError::EmptyHeader));
+        assert_eq!(NetworkFilter::parse("||foo.com^$~header=x-ad", true).err(), Some(NetworkFilterError::NegatedHeader));
+        assert!(matches!(NetworkFilter::parse("||foo.com^$header=x-ad:/(/", true), Err(NetworkFilterError::RegexParsingError(_))));
+    }
+
+    #[test]
+    fn parses_method() {
+        use request::RequestMethod;
+
+        let filter = NetworkFilter::parse("||foo.com^$method=post|PUT", true).unwrap();
+        assert_eq!(filter.opt_methods, Some(vec![RequestMethod::Post, RequestMethod::Put]));
+        assert_eq!(filter.opt_not_methods, None);
+        let filter = NetworkFilter::parse("||foo.com^$method=~get|~head", true).unwrap();
+        assert_eq!(filter.opt_methods, None);
+        assert_eq!(filter.opt_not_methods, Some(vec![RequestMethod::Get, RequestMethod::Head]));
+        assert_ne!(
+            NetworkFilter::parse("||foo.com^$method=post", true).unwrap().get_id(),
+            NetworkFilter::parse("||foo.com^$method=get", true).unwrap().get_id(),
+        );
+        assert_eq!(NetworkFilter::parse("||foo.com^$method=brew", true).err(), Some(NetworkFilterError::InvalidMethod));
+        assert_eq!(NetworkFilter::parse("||foo.com^$~method=get", true).err(), Some(NetworkFilterError::NegatedMethod));
+    }
+
     #[test]
     fn parses_domain() {
         // parses domain
@@ -2035,6 +2662,46 @@ mod parse_tests {
             let filter = NetworkFilter::parse("||foo.com$redirect=", true);
             assert_eq!(filter.err(), Some(NetworkFilterError::EmptyRedirection))
         }
+        // parses redirect-rule
+        {
+            let filter = NetworkFilter::parse("||foo.com$redirect-rule=bar.js", true).unwrap();
+            assert_eq!(filter.redirect, Some(String::from("bar.js")));
+            assert_eq!(filter.is_redirect_rule(), true);
+            let filter = NetworkFilter::parse("||foo.com$redirect=bar.js", true).unwrap();
+            assert_eq!(filter.is_redirect_rule(), false);
+            let filter = NetworkFilter::parse("||foo.com$~redirect-rule=bar.js", true);
+            assert_eq!(filter.err(), Some(NetworkFilterError::NegatedRedirection));
+            let filter = NetworkFilter::parse("||foo.com$redirect-rule", true);
+            assert_eq!(filter.err(), Some(NetworkFilterError::EmptyRedirection));
+        }
+        // parses exceptions without a value, which disable all redirections
+        {
+            let filter = NetworkFilter::parse("@@||foo.com$redirect-rule", true).unwrap();
+            assert_eq!(filter.redirect, None);
+            assert!(filter.is_redirect());
+            assert!(filter.excepts_all_redirects());
+            assert_eq!(filter.is_redirect_rule(), true);
+            let filter = NetworkFilter::parse("@@||foo.com$redirect", true).unwrap();
+            assert_eq!(filter.redirect, None);
+            assert!(filter.excepts_all_redirects());
+            let filter = NetworkFilter::parse("@@||foo.com$redirect=bar.js", true).unwrap();
+            assert!(!filter.excepts_all_redirects());
+        }
+        // parses redirect priorities
+        {
+            let filter = NetworkFilter::parse("||foo.com$redirect=bar.js:10", true).unwrap();
+            assert_eq!(filter.redirect, Some(String::from("bar.js")));
+            assert_eq!(filter.redirect_priority, 10);
+            let filter = NetworkFilter::parse("||foo.com$redirect-rule=bar.js:-1", true).unwrap();
+            assert_eq!(filter.redirect, Some(String::from("bar.js")));
+            assert_eq!(filter.redirect_priority, -1);
+            let filter = NetworkFilter::parse("||foo.com$redirect=bar.js", true).unwrap();
+            assert_eq!(filter.redirect_priority, 0);
+            let filter = NetworkFilter::parse("||foo.com$redirect=bar.js:high", true);
+            assert_eq!(filter.err(), Some(NetworkFilterError::InvalidRedirectPriority));
+            let filter = NetworkFilter::parse("||foo.com$redirect=:10", true);
+            assert_eq!(filter.err(), Some(NetworkFilterError::EmptyRedirection));
+        }
         // defaults to false
         {
             let filter = NetworkFilter::parse("||foo.com", true).unwrap();
@@ -2227,6 +2894,62 @@ mod parse_tests {
         }
     }
 
+    #[test]
+    fn parses_generic_block() {
+        assert!(matches!(NetworkFilter::parse("||foo.com$genericblock", true), Err(NetworkFilterError::GenericBlockWithoutException)));
+        assert!(matches!(NetworkFilter::parse("@@||foo.com$~genericblock", true), Err(NetworkFilterError::NegatedGenericBlock)));
+        {
+            let filter = NetworkFilter::parse("@@||foo.com^$genericblock", true).unwrap();
+            assert!(filter.is_generic_block());
+            assert!(!filter.is_cosmetic_hide_exception());
+        }
+        {
+            let filter = NetworkFilter::parse("@@||foo.com^$genericblock,generichide", true).unwrap();
+            assert!(filter.is_generic_block());
+            assert!(filter.is_generic_hide());
+        }
+        assert!(NetworkFilter::parse("||foo.com/ads", true).unwrap().is_generic());
+        assert!(NetworkFilter::parse("/ads$domain=~foo.com", true).unwrap().is_generic());
+        assert!(!NetworkFilter::parse("/ads$domain=foo.com", true).unwrap().is_generic());
+
+        let hashes = Some(vec![utils::fast_hash("sub.foo.com"), utils::fast_hash("foo.com"), utils::fast_hash("com")]);
+        let matches_document = |filter: &str| NetworkFilter::parse(filter, true).unwrap().matches_document(hashes.as_ref());
+        assert!(matches_document("@@||foo.com^$genericblock"));
+        assert!(matches_document("@@||sub.foo.com/$genericblock"));
+        assert!(matches_document("@@$genericblock,domain=foo.com"));
+        assert!(!matches_document("@@||bar.com^$genericblock"));
+        assert!(!matches_document("@@||foo.com/path$genericblock"));
+        assert!(!matches_document("@@||foo.com^$genericblock,3p"));
+        assert!(!matches_document("@@$genericblock,domain=~foo.com"));
+    }
+
+    #[test]
+    fn parses_elem_hide_specific_hide() {
+        assert!(matches!(NetworkFilter::parse("||foo.com$elemhide", true), Err(NetworkFilterError::ElemHideWithoutException)));
+        assert!(matches!(NetworkFilter::parse("||foo.com$shide", true), Err(NetworkFilterError::SpecificHideWithoutException)));
+        assert!(matches!(NetworkFilter::parse("@@||foo.com$~ehide", true), Err(NetworkFilterError::NegatedElemHide)));
+        assert!(matches!(NetworkFilter::parse("@@||foo.com$~specifichide", true), Err(NetworkFilterError::NegatedSpecificHide)));
+        {
+            let filter = NetworkFilter::parse("@@||foo.com$elemhide", true).unwrap();
+            assert!(filter.is_elem_hide());
+            assert!(!filter.is_generic_hide());
+            assert!(!filter.is_specific_hide());
+            assert!(filter.is_cosmetic_hide_exception());
+            assert_eq!(filter.get_id(), NetworkFilter::parse("@@||foo.com$ehide", true).unwrap().get_id());
+        }
+        {
+            let filter = NetworkFilter::parse("@@||foo.com$specifichide", true).unwrap();
+            assert!(filter.is_specific_hide());
+            assert!(!filter.is_elem_hide());
+            assert!(filter.is_cosmetic_hide_exception());
+            assert_eq!(filter.get_id(), NetworkFilter::parse("@@||foo.com$shide", true).unwrap().get_id());
+        }
+        {
+            let filter = NetworkFilter::parse("@@||foo.com", true).unwrap();
+            assert!(!filter.is_cosmetic_hide_exception());
+        }
+    }
+
     #[test]
     fn parses_hosts_style() {
         {
@@ -2914,6 +3637,35 @@ mod match_tests {
         }
     }
 
+    #[test]
+    fn check_to_and_denyallow_works() {
+        {
+            let network_filter = NetworkFilter::parse("/adv$to=example.net|~foo.example.net", true).unwrap();
+            assert!(network_filter.matches(&request::Request::from_urls("http://example.net/adv", "http://example.com", "").unwrap()) == true);
+            assert!(network_filter.matches(&request::Request::from_urls("http://bar.example.net/adv", "http://example.com", "").unwrap()) == true);
+            assert!(network_filter.matches(&request::Request::from_urls("http://foo.example.net/adv", "http://example.com", "").unwrap()) == false);
+            assert!(network_filter.matches(&request::Request::from_urls("http://example.org/adv", "http://example.com", "").unwrap()) == false);
+            // requests without a hostname can't match included domains, but aren't excluded either
+            let request = request::Request::new("", "/adv", "", "", "", "example.com", "example.com");
+            assert!(network_filter.matches(&request) == false);
+            let network_filter = NetworkFilter::parse("/adv$to=~example.net", true).unwrap();
+            assert!(network_filter.matches(&request) == true);
+        }
+        {
+            let network_filter = NetworkFilter::parse("/adv$to=example.net,to=example.org", true).unwrap();
+            assert!(network_filter.matches(&request::Request::from_urls("http://example.net/adv", "http://example.com", "").unwrap()) == true);
+            assert!(network_filter.matches(&request::Request::from_urls("http://example.org/adv", "http://example.com", "").unwrap()) == true);
+            assert!(network_filter.matches(&request::Request::from_urls("http://example.com/adv", "http://example.com", "").unwrap()) == false);
+        }
+        {
+            let network_filter = NetworkFilter::parse("*$script,3p,domain=example.com,denyallow=cdn.net|static.org", true).unwrap();
+            assert!(network_filter.matches(&request::Request::from_urls("http://tracker.io/a.js", "http://example.com", "script").unwrap()) == true);
+            assert!(network_filter.matches(&request::Request::from_urls("http://cdn.net/a.js", "http://example.com", "script").unwrap()) == false);
+            assert!(network_filter.matches(&request::Request::from_urls("http://a.static.org/a.js", "http://example.com", "script").unwrap()) == false);
+            assert!(network_filter.matches(&request::Request::from_urls("http://tracker.io/a.js", "http://example.org", "script").unwrap()) == false);
+        }
+    }
+
     #[test]
     fn check_unicode_handled() {
         filter_match_url(
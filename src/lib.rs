@@ -11,8 +11,14 @@ pub mod blocker;
 pub mod optimizer;
 pub mod url_parser;
 pub mod engine;
+pub mod allowlist;
+pub mod engine_group;
 pub mod resources;
 pub mod cosmetic_filter_cache;
 pub mod data_format;
+#[cfg(feature = "metrics")]
+pub mod statistics;
 #[cfg(feature = "content-blocking")]
 pub mod content_blocking;
+#[cfg(feature = "dnr")]
+pub mod dnr;
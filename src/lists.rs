@@ -6,6 +6,9 @@ use crate::filters::cosmetic::{CosmeticFilter, CosmeticFilterError};
 use itertools::{Either, Itertools};
 use serde::{Deserialize, Serialize};
 
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+
 /// iOS and macOS limit the number of content blocking rules that can be loaded. To better
 /// fine-tune content-blocking behavior, the types of rules converted can be restricted using this
 /// type.
@@ -83,9 +86,74 @@ impl FilterSet {
 
     /// Adds the contents of an entire filter list to this `FilterSet`. Filters that cannot be
     /// parsed successfully are ignored.
-    pub fn add_filter_list(&mut self, filter_list: &str, format: FilterFormat) {
-        let rules = filter_list.lines().map(str::to_string).collect::<Vec<_>>();
+    ///
+    /// `!#if` blocks are evaluated with no flags set, and `!#include` directives are ignored. Use
+    /// `add_filter_list_with_preprocessor` to choose the flags and to follow includes.
+    ///
+    /// Returns the metadata found in the list's header.
+    pub fn add_filter_list(&mut self, filter_list: &str, format: FilterFormat) -> FilterListMetadata {
+        let rules = preprocess_filter_list(filter_list, &PreprocessorOptions::default());
+        self.add_filters(&rules, format);
+        parse_filter_list_metadata(filter_list)
+    }
+
+    /// Adds the contents of an entire filter list to this `FilterSet`, after evaluating any
+    /// `!#if`/`!#else`/`!#endif` and `!#include` preprocessor directives it contains according to
+    /// `options`. Filters that cannot be parsed successfully are ignored.
+    pub fn add_filter_list_with_preprocessor(&mut self, filter_list: &str, format: FilterFormat, options: &PreprocessorOptions) -> FilterListMetadata {
+        let rules = preprocess_filter_list(filter_list, options);
         self.add_filters(&rules, format);
+        parse_filter_list_metadata(filter_list)
+    }
+
+    /// Adds the contents of an entire filter list to this `FilterSet`, like `add_filter_list`.
+    /// Additionally returns a diagnostic for every line that could not be parsed, and for every
+    /// rule that was parsed but will never have any effect.
+    pub fn add_filter_list_with_diagnostics(&mut self, filter_list: &str, format: FilterFormat) -> (FilterListMetadata, Vec<FilterDiagnostic>) {
+        let mut diagnostics = vec![];
+        let mut badfilters = vec![];
+
+        for (i, line) in filter_list.lines().enumerate() {
+            let diagnostic = |kind| FilterDiagnostic {
+                line_number: i + 1,
+                line: line.to_string(),
+                kind,
+            };
+            match parse_filter(line, self.debug, format) {
+                Ok(ParsedFilter::Network(filter)) => {
+                    diagnostics.extend(check_network_filter(line.trim(), &filter)
+                        .into_iter()
+                        .map(|warning| diagnostic(FilterDiagnosticKind::Warning(warning))));
+                    if filter.is_badfilter() {
+                        badfilters.push((i, filter.get_id_without_badfilter()));
+                    }
+                    self.network_filters.push(filter);
+                }
+                Ok(ParsedFilter::Cosmetic(filter)) => self.cosmetic_filters.push(filter),
+                Err(FilterParseError::Empty) => (),
+                // Comments are not worth reporting
+                Err(FilterParseError::Unsupported) if !matches!(format, FilterFormat::Standard) || is_comment(line.trim()) => (),
+                Err(e) => diagnostics.push(diagnostic(FilterDiagnosticKind::Error(e))),
+            }
+        }
+
+        if !badfilters.is_empty() {
+            let filter_ids: HashSet<_> = self.network_filters.iter()
+                .filter(|filter| !filter.is_badfilter())
+                .map(|filter| filter.get_id())
+                .collect();
+            let lines: Vec<_> = filter_list.lines().collect();
+            diagnostics.extend(badfilters.into_iter()
+                .filter(|(_, id)| !filter_ids.contains(id))
+                .map(|(i, _)| FilterDiagnostic {
+                    line_number: i + 1,
+                    line: lines[i].to_string(),
+                    kind: FilterDiagnosticKind::Warning(FilterParseWarning::UnmatchedBadFilter),
+                }));
+            diagnostics.sort_by_key(|diagnostic| diagnostic.line_number);
+        }
+
+        (parse_filter_list_metadata(filter_list), diagnostics)
     }
 
     /// Adds a collection of filter rules to this `FilterSet`. Filters that cannot be parsed
@@ -106,6 +174,105 @@ impl FilterSet {
         Ok(())
     }
 
+    /// Adds filters equivalent to a list of content blocking rules, e.g. a WebKit JSON block list
+    /// deserialized with `serde_json`. Returns the index of each rule that could not be converted,
+    /// along with the reason.
+    ///
+    /// Content blocking rules apply in order, so `ignore-previous-rules` rules only override the
+    /// rules before them, whereas exceptions override every filter. This is approximated by
+    /// dropping `ignore-previous-rules` rules that have no earlier rule to override, by importing
+    /// those that only follow `css-display-none` rules as cosmetic exceptions, and by making
+    /// blocking rules `$important` when an earlier `ignore-previous-rules` rule could match the
+    /// same requests but no later one could. Blocking rules that could be matched by both an
+    /// earlier and a later `ignore-previous-rules` rule are kept as they are, so the earlier one
+    /// can still override them. The rule from `content_blocking::ignore_previous_fp_documents` is
+    /// also dropped, since filters never block top-level documents.
+    #[cfg(feature = "content-blocking")]
+    pub fn add_content_blocking_rules(&mut self, rules: &[crate::content_blocking::CbRule]) -> Vec<(usize, crate::content_blocking::CbRuleImportFailure)> {
+        use crate::content_blocking::{self, CbRuleImportFailure, CbType};
+
+        let fp_documents = content_blocking::ignore_previous_fp_documents();
+        let first_block = rules.iter().position(|rule| rule.action.typ == CbType::Block);
+        let overrides_rules = |index: usize| {
+            let rule = &rules[index];
+            rule.action.typ == CbType::IgnorePreviousRules
+                && *rule != fp_documents
+                && matches!(first_block, Some(first_block) if first_block < index)
+        };
+        let debug = self.debug;
+        let convert = |rule: &content_blocking::CbRule, important: bool| rule.to_filter_rule(important)
+            .and_then(|filter| parse_filter(&filter, debug, FilterFormat::Standard).map_err(CbRuleImportFailure::FilterParseError));
+
+        let mut failures = vec![];
+        let mut converted = vec![];
+        for (index, rule) in rules.iter().enumerate() {
+            if rule.action.typ == CbType::IgnorePreviousRules && !overrides_rules(index) {
+                // Without an earlier blocking rule, it can still override earlier cosmetic rules
+                let selectors: Vec<&str> = rules[..index].iter()
+                    .filter(|rule| rule.action.typ == CbType::CssDisplayNone)
+                    .filter_map(|rule| rule.action.selector.as_deref())
+                    .unique()
+                    .collect();
+                if *rule == fp_documents || selectors.is_empty() {
+                    continue;
+                }
+                let exceptions = rule.to_cosmetic_exceptions(&selectors)
+                    .and_then(|filters| filters.iter()
+                        .map(|filter| parse_filter(filter, debug, FilterFormat::Standard).map_err(CbRuleImportFailure::FilterParseError))
+                        .collect::<Result<Vec<_>, _>>());
+                match exceptions {
+                    Ok(parsed) => converted.extend(parsed.into_iter().map(|parsed| (index, parsed))),
+                    Err(e) => failures.push((index, e)),
+                }
+                continue;
+            }
+            match convert(rule, false) {
+                Ok(parsed) => converted.push((index, parsed)),
+                Err(e) => failures.push((index, e)),
+            }
+        }
+
+        let overrides: Vec<(usize, &NetworkFilter)> = converted.iter()
+            .filter_map(|(index, parsed)| match parsed {
+                ParsedFilter::Network(filter) if filter.is_exception() && !filter.is_cosmetic_hide_exception() => Some((*index, filter)),
+                _ => None,
+            })
+            .collect();
+        let important: HashSet<usize> = converted.iter()
+            .filter_map(|(index, parsed)| match parsed {
+                ParsedFilter::Network(filter) if !filter.is_exception() && !filter.is_important() => Some((*index, filter)),
+                _ => None,
+            })
+            .filter(|(index, filter)| {
+                let mut matching = overrides.iter()
+                    .filter(|(_, exception)| may_match_same_request(filter, exception));
+                matching.clone().any(|(override_index, _)| override_index < index)
+                    && !matching.any(|(override_index, _)| override_index > index)
+            })
+            .map(|(index, _)| index)
+            .collect();
+
+        for (index, parsed) in converted {
+            let parsed = if important.contains(&index) {
+                match convert(&rules[index], true) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        failures.push((index, e));
+                        continue;
+                    }
+                }
+            } else {
+                parsed
+            };
+            match parsed {
+                ParsedFilter::Network(filter) => self.network_filters.push(filter),
+                ParsedFilter::Cosmetic(filter) => self.cosmetic_filters.push(filter),
+            }
+        }
+        failures.sort_by_key(|(index, _)| *index);
+        failures
+    }
+
     /// Consumes this `FilterSet`, returning an equivalent list of content blocking rules and a
     /// corresponding new list containing the `String` representation of all filters that were
     /// successfully converted (as `FilterFormat::Standard` rules).
@@ -116,11 +283,153 @@ impl FilterSet {
     /// This function will fail if the `FilterSet` was not created in debug mode.
     #[cfg(feature = "content-blocking")]
     pub fn into_content_blocking(self, rule_types: RuleTypes) -> Result<(Vec<crate::content_blocking::CbRule>, Vec<String>), ()> {
+        let (rules, filters_used, _) = self.into_content_blocking_with_entities(rule_types, &Default::default())
+            .map_err(|_| ())?;
+        Ok((rules, filters_used))
+    }
+
+    /// Like `into_content_blocking`, but cosmetic rules for entities (e.g. `google.*##.ad`) are
+    /// converted by expanding each entity into its known domains from `entity_domains`, rather
+    /// than being dropped. Rules with any entity that has no known domain are still dropped.
+    ///
+    /// Also returns how many entities were expanded, and into how many domains.
+    ///
+    /// This function will fail with `CbRuleCreationFailure::NeedsDebugMode` if the `FilterSet`
+    /// was not created in debug mode.
+    #[cfg(feature = "content-blocking")]
+    pub fn into_content_blocking_with_entities(
+        self,
+        rule_types: RuleTypes,
+        entity_domains: &crate::content_blocking::CbEntityDomains,
+    ) -> Result<(Vec<crate::content_blocking::CbRule>, Vec<String>, crate::content_blocking::CbEntityExpansion), crate::content_blocking::CbRuleCreationFailure> {
+        let mut expansion = Default::default();
+        let ConvertedCbRules { other_rules, ignore_previous_rules, filters_used } = self.convert_to_content_blocking(rule_types, entity_domains, &mut expansion)?;
+
+        let mut rules: Vec<_> = other_rules.into_iter()
+            .chain(ignore_previous_rules)
+            .map(|(rule, _)| rule)
+            .collect();
+
+        if rule_types.loads_network_rules() {
+            rules.push(crate::content_blocking::ignore_previous_fp_documents());
+        }
+
+        Ok((rules, filters_used, expansion))
+    }
+
+    /// Consumes this `FilterSet`, returning equivalent content blocking rules split into several
+    /// lists of at most `max_rules_per_chunk` rules each, to stay under the limits of iOS and
+    /// macOS. At most `max_chunks` lists are returned. Each list comes with the `String`
+    /// representation of the filters it was converted from. Cosmetic rules for entities are
+    /// expanded using `entity_domains`, as in `into_content_blocking_with_entities`.
+    ///
+    /// `ignore-previous-rules` rules only apply to earlier rules of the same list, so every list
+    /// includes all of them, ordered after the other rules. They are never dropped, since that
+    /// would block more than intended.
+    ///
+    /// If the other rules do not fit in the remaining space, the ones converted from the filters
+    /// with the highest `rank` are kept. The filters that could not be converted entirely
+    /// because of this are returned separately.
+    ///
+    /// If `max_selector_len` is set, `css-display-none` rules are first merged as in
+    /// `content_blocking::coalesce_cosmetic_rules`, so that the limits apply to the merged rules.
+    /// A merged rule is ranked by the highest ranked of its filters. Only selectors that pass
+    /// validation are merged, so nothing is merged unless the `css-validation` feature is enabled.
+    ///
+    /// This function will fail with `CbRuleCreationFailure::NeedsDebugMode` if the `FilterSet`
+    /// was not created in debug mode, and with `CbRuleCreationFailure::TooManyIgnorePreviousRules`
+    /// if the `ignore-previous-rules` rules alone take up `max_rules_per_chunk` or more.
+    #[cfg(feature = "content-blocking")]
+    pub fn into_content_blocking_chunks<R: Ord, F: FnMut(&str) -> R>(
+        self,
+        rule_types: RuleTypes,
+        entity_domains: &crate::content_blocking::CbEntityDomains,
+        max_rules_per_chunk: usize,
+        max_chunks: usize,
+        max_selector_len: Option<usize>,
+        mut rank: F,
+    ) -> Result<(Vec<crate::content_blocking::CbRuleChunk>, Vec<String>, crate::content_blocking::CbEntityExpansion), crate::content_blocking::CbRuleCreationFailure> {
+        use crate::content_blocking::CbRuleChunk;
+
+        let mut expansion = Default::default();
+        let ConvertedCbRules { other_rules, ignore_previous_rules, filters_used } = self.convert_to_content_blocking(rule_types, entity_domains, &mut expansion)?;
+
+        // The filters that each rule was converted from
+        let mut other_rules: Vec<(_, Vec<usize>)> = other_rules.into_iter()
+            .map(|(rule, filter)| (rule, vec![filter]))
+            .collect();
+        if let Some(max_selector_len) = max_selector_len {
+            // Without validation, no selector is known to be valid
+            let mergeable = |selector: &str| cfg!(feature = "css-validation") && crate::filters::cosmetic::is_valid_css_selector(selector);
+            other_rules = crate::content_blocking::coalesce_cosmetic_rules_with(other_rules, max_selector_len, mergeable, |filters, merged| filters.extend(merged));
+        }
+
+        let mut ignore_previous_rules: Vec<_> = ignore_previous_rules.into_iter()
+            .map(|(rule, filter)| (rule, vec![filter]))
+            .collect();
+        if rule_types.loads_network_rules() {
+            ignore_previous_rules.push((crate::content_blocking::ignore_previous_fp_documents(), vec![]));
+        }
+
+        let capacity = max_rules_per_chunk.saturating_sub(ignore_previous_rules.len());
+        if capacity == 0 {
+            return Err(crate::content_blocking::CbRuleCreationFailure::TooManyIgnorePreviousRules);
+        }
+        let budget = capacity.saturating_mul(max_chunks);
+
+        // Keep the highest ranked rules, then restore their original order
+        let mut order: Vec<usize> = (0..other_rules.len()).collect();
+        if order.len() > budget {
+            order.sort_by_cached_key(|&index| std::cmp::Reverse(other_rules[index].1.iter().map(|&filter| rank(&filters_used[filter])).max()));
+        }
+        let dropped = order.split_off(budget.min(order.len()));
+        order.sort_unstable();
+
+        let mut dropped_filters: Vec<usize> = dropped.into_iter().flat_map(|index| other_rules[index].1.iter().copied()).collect();
+        dropped_filters.sort_unstable();
+        dropped_filters.dedup();
+
+        let mut other_rules: Vec<_> = other_rules.into_iter().map(Some).collect();
+        let chunks = order.chunks(capacity)
+            .map(|indices| {
+                let mut rules = Vec::with_capacity(indices.len() + ignore_previous_rules.len());
+                let mut filters = vec![];
+                indices.iter()
+                    .map(|&index| other_rules[index].take().expect("Each rule is only used once"))
+                    .chain(ignore_previous_rules.iter().cloned())
+                    .for_each(|(rule, rule_filters)| {
+                        rules.push(rule);
+                        // Rules converted from the same filter are always adjacent
+                        for filter in rule_filters {
+                            if filters.last() != Some(&filter) {
+                                filters.push(filter);
+                            }
+                        }
+                    });
+                CbRuleChunk {
+                    rules,
+                    filters_used: filters.into_iter().map(|filter| filters_used[filter].clone()).collect(),
+                }
+            })
+            .collect();
+
+        Ok((chunks, dropped_filters.into_iter().map(|filter| filters_used[filter].clone()).collect(), expansion))
+    }
+
+    /// Converts every filter of the requested types, keeping track of the filter each content
+    /// blocking rule was converted from.
+    #[cfg(feature = "content-blocking")]
+    fn convert_to_content_blocking(
+        self,
+        rule_types: RuleTypes,
+        entity_domains: &crate::content_blocking::CbEntityDomains,
+        expansion: &mut crate::content_blocking::CbEntityExpansion,
+    ) -> Result<ConvertedCbRules, crate::content_blocking::CbRuleCreationFailure> {
         use std::convert::TryInto;
         use crate::content_blocking;
 
         if !self.debug {
-            return Err(())
+            return Err(content_blocking::CbRuleCreationFailure::NeedsDebugMode)
         }
 
         let mut ignore_previous_rules = vec![];
@@ -132,11 +441,12 @@ impl FilterSet {
             self.network_filters.into_iter().for_each(|filter| {
                 let original_rule = filter.raw_line.clone().expect("All rules should be in debug mode");
                 if let Ok(equivalent) = TryInto::<content_blocking::CbRuleEquivalent>::try_into(filter) {
+                    let filter_index = filters_used.len();
                     filters_used.push(original_rule);
                     equivalent.into_iter().for_each(|cb_rule| {
                         match &cb_rule.action.typ {
-                            content_blocking::CbType::IgnorePreviousRules => ignore_previous_rules.push(cb_rule),
-                            _ => other_rules.push(cb_rule),
+                            content_blocking::CbType::IgnorePreviousRules => ignore_previous_rules.push((cb_rule, filter_index)),
+                            _ => other_rules.push((cb_rule, filter_index)),
                         }
                     });
                 }
@@ -146,26 +456,124 @@ impl FilterSet {
         if rule_types.loads_cosmetic_rules() {
             self.cosmetic_filters.into_iter().for_each(|filter| {
                 let original_rule = filter.raw_line.clone().expect("All rules should be in debug mode");
-                if let Ok(cb_rule) = TryInto::<content_blocking::CbRule>::try_into(filter) {
+                if let Ok(cb_rule) = content_blocking::CbRule::from_cosmetic_filter(filter, entity_domains, expansion) {
+                    let filter_index = filters_used.len();
                     filters_used.push(original_rule);
                     match &cb_rule.action.typ {
-                        content_blocking::CbType::IgnorePreviousRules => ignore_previous_rules.push(cb_rule),
-                        _ => other_rules.push(cb_rule),
+                        content_blocking::CbType::IgnorePreviousRules => ignore_previous_rules.push((cb_rule, filter_index)),
+                        _ => other_rules.push((cb_rule, filter_index)),
                     }
                 }
             });
         }
 
-        other_rules.append(&mut ignore_previous_rules);
+        Ok(ConvertedCbRules { other_rules, ignore_previous_rules, filters_used })
+    }
+
+    /// Consumes this `FilterSet`, returning an equivalent list of Manifest V3
+    /// `declarativeNetRequest` rules and a corresponding new list containing the `String`
+    /// representation of all filters that were successfully converted (as `FilterFormat::Standard`
+    /// rules). Only network filters are converted, and rules are numbered from 1. Every network
+    /// filter that could not be converted is returned as well, along with the reason. Filters
+    /// disabled by a `$badfilter` rule, and the `$badfilter` rules themselves, are skipped.
+    ///
+    /// `$redirect` resources are referenced by name under `resources_path`, e.g.
+    /// `/web_accessible_resources`, which should contain a file for each resource used.
+    ///
+    /// This function will fail with `DnrRuleCreationFailure::NeedsDebugMode` if the `FilterSet`
+    /// was not created in debug mode.
+    #[cfg(feature = "dnr")]
+    pub fn into_declarative_net_request(self, resources_path: &str) -> Result<(Vec<crate::dnr::DnrRule>, Vec<String>, crate::dnr::DnrRuleFailures), crate::dnr::DnrRuleCreationFailure> {
+        use std::convert::TryInto;
+        use crate::dnr;
 
-        if rule_types.loads_network_rules() {
-            other_rules.push(content_blocking::ignore_previous_fp_documents());
+        if !self.debug {
+            return Err(dnr::DnrRuleCreationFailure::NeedsDebugMode)
         }
 
-        Ok((other_rules, filters_used))
+        let resources_path = resources_path.trim_end_matches('/');
+        let resources_path = if resources_path.starts_with('/') {
+            resources_path.to_string()
+        } else {
+            format!("/{}", resources_path)
+        };
+
+        let badfilter_ids: HashSet<_> = self.network_filters.iter()
+            .filter(|filter| filter.is_badfilter())
+            .map(|filter| filter.get_id_without_badfilter())
+            .collect();
+
+        let mut rules = vec![];
+        let mut filters_used = vec![];
+        let mut failures = vec![];
+
+        self.network_filters.into_iter()
+            .filter(|filter| !filter.is_badfilter() && !badfilter_ids.contains(&filter.get_id()))
+            .for_each(|filter| {
+                let original_rule = filter.raw_line.clone().expect("All rules should be in debug mode");
+                match TryInto::<dnr::DnrRule>::try_into(filter) {
+                    Ok(mut rule) => {
+                        filters_used.push(original_rule);
+                        rule.id = rules.len() as u32 + 1;
+                        if let Some(path) = rule.action.redirect.as_mut().and_then(|redirect| redirect.extension_path.as_mut()) {
+                            *path = format!("{}{}", resources_path, path);
+                        }
+                        rules.push(rule);
+                    }
+                    Err(failure) => failures.push((original_rule, failure)),
+                }
+            });
+
+        Ok((rules, filters_used, failures))
     }
 }
 
+/// Whether a blocking `filter` and an `exception` could both match some request. Only returns
+/// `false` if their resource types, parties or hostnames are known not to overlap.
+#[cfg(feature = "content-blocking")]
+fn may_match_same_request(filter: &NetworkFilter, exception: &NetworkFilter) -> bool {
+    use crate::filters::network::NetworkFilterMask;
+
+    let common = filter.mask & exception.mask;
+    if !common.intersects(NetworkFilterMask::FROM_ANY | NetworkFilterMask::FROM_DOCUMENT)
+        || !common.intersects(NetworkFilterMask::FIRST_PARTY | NetworkFilterMask::THIRD_PARTY)
+    {
+        return false;
+    }
+
+    // Both hostnames must be a suffix of the request's hostname
+    match (complete_hostname(filter), complete_hostname(exception)) {
+        (Some(a), Some(b)) => a == b || a.ends_with(&format!(".{}", b)) || b.ends_with(&format!(".{}", a)),
+        _ => true,
+    }
+}
+
+/// The hostname of a hostname-anchored filter, e.g. `example.com` in `||example.com^` or
+/// `||example.com/ads`, if the rest of the pattern can only match after the end of the hostname.
+#[cfg(feature = "content-blocking")]
+fn complete_hostname(filter: &NetworkFilter) -> Option<&str> {
+    use crate::filters::network::{FilterPart, NetworkFilterMask};
+
+    if filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX) {
+        return None;
+    }
+    let hostname = filter.hostname.as_deref()?;
+    match &filter.filter {
+        FilterPart::Empty if filter.is_right_anchor() => Some(hostname),
+        FilterPart::Simple(rest) if rest.starts_with('/') || rest.starts_with('^') => Some(hostname),
+        _ => None,
+    }
+}
+
+/// Content blocking rules converted from a `FilterSet`, along with the index in `filters_used` of
+/// the filter that each rule was converted from.
+#[cfg(feature = "content-blocking")]
+struct ConvertedCbRules {
+    other_rules: Vec<(crate::content_blocking::CbRule, usize)>,
+    ignore_previous_rules: Vec<(crate::content_blocking::CbRule, usize)>,
+    filters_used: Vec<String>,
+}
+
 /// Denotes the format of a particular list resource, which affects how its rules should be parsed.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum FilterFormat {
@@ -192,6 +600,7 @@ pub enum FilterType {
 }
 
 /// Successful result of parsing a single filter rule
+#[allow(clippy::large_enum_variant)]
 pub enum ParsedFilter {
     Network(NetworkFilter),
     Cosmetic(CosmeticFilter),
@@ -230,6 +639,80 @@ impl From<CosmeticFilterError> for FilterParseError {
     }
 }
 
+/// A rule that was parsed successfully, but is likely to be a mistake.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterParseWarning {
+    /// The named option is specified more than once, either directly or through an alias. Only
+    /// the last value is kept for options that take a value.
+    RedundantOption(String),
+    /// The rule's options rule out every request, e.g. `$first-party,third-party`.
+    NeverMatches,
+    /// The `$badfilter` rule does not disable any rule in the `FilterSet`.
+    UnmatchedBadFilter,
+}
+
+/// Whether a `FilterDiagnostic` describes a rule that was rejected or a suspicious rule that was
+/// still added.
+#[derive(Debug)]
+pub enum FilterDiagnosticKind {
+    Error(FilterParseError),
+    Warning(FilterParseWarning),
+}
+
+/// A problem with a single line of a filter list, as reported by
+/// `FilterSet::add_filter_list_with_diagnostics`.
+#[derive(Debug)]
+pub struct FilterDiagnostic {
+    /// 1-based index of the line within the list.
+    pub line_number: usize,
+    /// The raw contents of the line.
+    pub line: String,
+    pub kind: FilterDiagnosticKind,
+}
+
+/// Finds problems with a successfully parsed network filter that would make it ineffective.
+fn check_network_filter(line: &str, filter: &NetworkFilter) -> Vec<FilterParseWarning> {
+    use crate::filters::network::{raw_options, split_options, NetworkFilterMask};
+
+    let mut warnings = vec![];
+
+    // Options are split the same way as by the parser
+    if let Some(raw_options) = raw_options(line) {
+        let mut seen = HashSet::new();
+        for (name, _, _) in split_options(raw_options) {
+            let name = match name {
+                "from" => "domain",
+                "1p" => "first-party",
+                "3p" => "third-party",
+                "css" => "stylesheet",
+                "frame" => "subdocument",
+                "main_frame" => "document",
+                "xhr" => "xmlhttprequest",
+                "beacon" => "ping",
+                "object-subrequest" => "object",
+                "ghide" => "generichide",
+                "ehide" => "elemhide",
+                "shide" => "specifichide",
+                name => name,
+            };
+            if !seen.insert(name) {
+                let warning = FilterParseWarning::RedundantOption(name.to_string());
+                if !warnings.contains(&warning) {
+                    warnings.push(warning);
+                }
+            }
+        }
+    }
+
+    if !filter.mask.intersects(NetworkFilterMask::FIRST_PARTY | NetworkFilterMask::THIRD_PARTY)
+        || !filter.mask.intersects(NetworkFilterMask::FROM_ANY | NetworkFilterMask::FROM_DOCUMENT)
+    {
+        warnings.push(FilterParseWarning::NeverMatches);
+    }
+
+    warnings
+}
+
 /// Parse a single filter rule
 pub fn parse_filter(
     line: &str,
@@ -317,17 +800,569 @@ pub fn parse_filters(
     (network_filters, cosmetic_filters)
 }
 
+/// Information about a filter list, parsed from the comments in its header.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FilterListMetadata {
+    /// `! Title: ...`
+    pub title: Option<String>,
+    /// `! Version: ...`
+    pub version: Option<String>,
+    /// `! Expires: ...`, the recommended interval between updates of the list.
+    pub expires: Option<ExpiresInterval>,
+    /// `! Homepage: ...`
+    pub homepage: Option<String>,
+    /// `! License: ...` or `! Licence: ...`
+    pub license: Option<String>,
+    /// `! Redirect: ...`, a new URL that the list should be downloaded from in the future.
+    pub redirect_url: Option<String>,
+    /// The minimum version of Adblock Plus required by the list, from a `[Adblock Plus x.y]`
+    /// first line.
+    pub abp_version: Option<String>,
+    /// The result of verifying the list against its `! Checksum: ...` line, if it has one.
+    pub checksum: Option<Checksum>,
+}
+
+/// Update interval of a filter list, as specified by `! Expires: ...`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExpiresInterval {
+    Days(u16),
+    Hours(u16),
+}
+
+impl ExpiresInterval {
+    /// Parses values like `4 days (update frequency)`, `12 hours`, or the shorter `4d` and `12h`.
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let digits_end = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+        let amount = value[..digits_end].parse::<u16>().ok()?;
+        let unit = value[digits_end..].trim_start();
+        let unit = unit.split(|c: char| !c.is_ascii_alphabetic()).next().unwrap_or_default();
+        match unit.to_ascii_lowercase().as_str() {
+            "d" | "day" | "days" => Some(ExpiresInterval::Days(amount)),
+            "h" | "hour" | "hours" => Some(ExpiresInterval::Hours(amount)),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of verifying a filter list's `! Checksum: ...` line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Checksum {
+    /// The list's contents match its checksum.
+    Valid,
+    /// The list's contents do not match its checksum. The list may be corrupted or incomplete.
+    Invalid,
+}
+
+/// Parses the metadata from the header of a filter list, i.e. the first line and the block of
+/// comments that follows it. If the list has a `! Checksum: ...` line anywhere, it is also
+/// verified.
+pub fn parse_filter_list_metadata(filter_list: &str) -> FilterListMetadata {
+    let mut metadata = FilterListMetadata::default();
+    let mut expected_checksum = None;
+    let mut in_header = true;
+
+    for (i, line) in filter_list.lines().enumerate() {
+        let line = line.trim();
+        if i == 0 && line.starts_with('[') && line.ends_with(']') {
+            metadata.abp_version = line[1..line.len() - 1]
+                .strip_prefix("Adblock Plus")
+                .map(|version| version.trim().to_string())
+                .filter(|version| !version.is_empty());
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let comment = match line.strip_prefix('!') {
+            Some(comment) => comment,
+            // The header ends at the first rule, although the checksum can come later
+            None => {
+                in_header = false;
+                continue;
+            }
+        };
+        let (key, value) = match comment.find(':') {
+            Some(colon) => (comment[..colon].trim(), comment[colon + 1..].trim()),
+            None => continue,
+        };
+        if value.is_empty() {
+            continue;
+        }
+        let key = key.to_ascii_lowercase();
+        if !in_header && key != "checksum" {
+            continue;
+        }
+
+        match key.as_str() {
+            "title" => metadata.title = Some(value.to_string()),
+            "version" => metadata.version = Some(value.to_string()),
+            "expires" => metadata.expires = ExpiresInterval::parse(value),
+            "homepage" => metadata.homepage = Some(value.to_string()),
+            "license" | "licence" => metadata.license = Some(value.to_string()),
+            "redirect" => metadata.redirect_url = Some(value.to_string()),
+            "checksum" => expected_checksum = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    metadata.checksum = expected_checksum.map(|expected| {
+        if expected == compute_checksum(filter_list) {
+            Checksum::Valid
+        } else {
+            Checksum::Invalid
+        }
+    });
+
+    metadata
+}
+
+/// Computes a checksum following the Adblock Plus convention: the base64-encoded MD5 digest of
+/// the list, without carriage returns, empty lines, or the checksum line itself. A checksum on
+/// the last line is removed along with the preceding line break.
+fn compute_checksum(filter_list: &str) -> String {
+    use regex::Regex;
+    use once_cell::sync::Lazy;
+
+    static NEWLINES: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n+").unwrap());
+    static CHECKSUM_LINE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?mi)^\s*!\s*checksum[\s\-:]+[\w+/=]+.*\n|\n\s*!\s*checksum[\s\-:]+[\w+/=]+.*\z").unwrap());
+
+    let normalized = filter_list.replace('\r', "");
+    let normalized = NEWLINES.replace_all(&normalized, "\n");
+    let normalized = CHECKSUM_LINE.replace_all(&normalized, "");
+
+    let digest = md5::compute(normalized.as_bytes());
+    base64::encode(digest.0).trim_end_matches('=').to_string()
+}
+
+/// Rules added to and removed from a filter list between two of its versions.
+///
+/// The field names match the JSON diff format used by Adblock Plus for diff updates, so it can be
+/// deserialized directly from a diff in that format.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FilterListDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// For each added rule, the rule it follows in the new version of the list, or `None` if it
+    /// is the first one. Empty for diffs without positions, whose added rules are appended.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added_after: Vec<Option<String>>,
+}
+
+impl FilterListDiff {
+    /// Compares two versions of a filter list, line by line. Comments and empty lines are ignored.
+    pub fn between(old_list: &str, new_list: &str) -> Self {
+        let rules = |list: &str| -> Vec<String> {
+            list.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !is_comment(line))
+                .map(str::to_string)
+                .unique()
+                .collect()
+        };
+        let old_rules = rules(old_list);
+        let new_rules = rules(new_list);
+
+        let old_set: HashSet<&String> = old_rules.iter().collect();
+        let new_set: HashSet<&String> = new_rules.iter().collect();
+
+        let (added, added_after) = new_rules.iter()
+            .enumerate()
+            .filter(|(_, rule)| !old_set.contains(rule))
+            .map(|(i, rule)| (rule.clone(), i.checked_sub(1).map(|prev| new_rules[prev].clone())))
+            .unzip();
+
+        FilterListDiff {
+            added,
+            removed: old_rules.iter().filter(|rule| !new_set.contains(rule)).cloned().collect(),
+            added_after,
+        }
+    }
+
+    /// Produces the new version of `list`, with every removed rule dropped and every added rule
+    /// inserted after the rule it follows in the new version. Added rules without a known
+    /// position are appended at the end.
+    pub fn apply(&self, list: &str) -> String {
+        fn push_following<'a>(rule: Option<&str>, following: &HashMap<Option<&str>, Vec<&'a str>>, lines: &mut Vec<&'a str>, placed: &mut HashSet<&'a str>) {
+            for &added in following.get(&rule).into_iter().flatten() {
+                if placed.insert(added) {
+                    lines.push(added);
+                    push_following(Some(added), following, lines, placed);
+                }
+            }
+        }
+
+        let removed: HashSet<&str> = self.removed.iter().map(|rule| rule.trim()).collect();
+        let mut following: HashMap<Option<&str>, Vec<&str>> = HashMap::new();
+        for (i, rule) in self.added.iter().enumerate() {
+            if let Some(after) = self.added_after.get(i) {
+                following.entry(after.as_deref()).or_default().push(rule);
+            }
+        }
+
+        let mut lines = Vec::with_capacity(list.len() / 16 + self.added.len());
+        let mut placed = HashSet::with_capacity(self.added.len());
+        let mut first_rule = true;
+        for line in list.lines() {
+            let rule = line.trim();
+            if removed.contains(rule) {
+                continue;
+            }
+            let is_rule = !rule.is_empty() && !is_comment(rule);
+            if is_rule && first_rule {
+                first_rule = false;
+                push_following(None, &following, &mut lines, &mut placed);
+            }
+            lines.push(line);
+            if is_rule {
+                push_following(Some(rule), &following, &mut lines, &mut placed);
+            }
+        }
+        if first_rule {
+            push_following(None, &following, &mut lines, &mut placed);
+        }
+
+        // Rules whose position wasn't found in `list`
+        lines.extend(self.added.iter().map(String::as_str).filter(|rule| !placed.contains(rule)));
+        lines.join("\n")
+    }
+}
+
+/// Unsuccessful result of applying an RCS-style diff with `apply_rcs_diff`.
+#[derive(Debug, PartialEq)]
+pub enum DiffUpdateError {
+    /// A line of the diff is neither an `a` nor a `d` command.
+    InvalidCommand,
+    /// A command refers to lines that are not in the list, or are not in increasing order.
+    LineOutOfRange,
+    /// An `a` command is followed by fewer lines than it adds.
+    UnexpectedEnd,
+}
+
+/// Applies a diff in RCS format, as produced by `diff -n` and used by uBlock Origin for diff
+/// updates, to `list` and returns the new version of the list.
+///
+/// The diff consists of `dN M` commands, which delete `M` lines starting at line `N`, and `aN M`
+/// commands followed by `M` lines to insert after line `N`. Line numbers refer to the original
+/// list. Patch files that bundle diffs of several lists must be split beforehand.
+pub fn apply_rcs_diff(list: &str, diff: &str) -> Result<String, DiffUpdateError> {
+    let old_lines: Vec<&str> = list.lines().collect();
+    let mut new_lines: Vec<&str> = Vec::with_capacity(old_lines.len());
+    // Index of the next line of the original list that hasn't been copied or deleted yet
+    let mut next = 0;
+
+    let mut diff_lines = diff.lines();
+    while let Some(command) = diff_lines.next() {
+        if command.is_empty() {
+            continue;
+        }
+        let (operation, arguments) = command.split_at(command.find(|c: char| c.is_ascii_digit()).unwrap_or(0));
+        let mut arguments = arguments.split(' ').map(str::parse::<usize>);
+        let (line, count) = match (arguments.next(), arguments.next(), arguments.next()) {
+            (Some(Ok(line)), Some(Ok(count)), None) => (line, count),
+            _ => return Err(DiffUpdateError::InvalidCommand),
+        };
+
+        match operation {
+            "d" => {
+                if line == 0 || line - 1 < next || line - 1 + count > old_lines.len() {
+                    return Err(DiffUpdateError::LineOutOfRange);
+                }
+                new_lines.extend_from_slice(&old_lines[next..line - 1]);
+                next = line - 1 + count;
+            }
+            "a" => {
+                if line < next || line > old_lines.len() {
+                    return Err(DiffUpdateError::LineOutOfRange);
+                }
+                new_lines.extend_from_slice(&old_lines[next..line]);
+                next = line;
+                for _ in 0..count {
+                    new_lines.push(diff_lines.next().ok_or(DiffUpdateError::UnexpectedEnd)?);
+                }
+            }
+            _ => return Err(DiffUpdateError::InvalidCommand),
+        }
+    }
+    new_lines.extend_from_slice(&old_lines[next..]);
+
+    let mut new_list = new_lines.join("\n");
+    if list.ends_with('\n') {
+        new_list.push('\n');
+    }
+    Ok(new_list)
+}
+
+/// Parses every rule of a single list that would be active in an `Engine`, i.e. excluding
+/// `$badfilter` rules and the rules they disable. Each filter is returned alongside its trimmed
+/// line.
+pub(crate) fn parse_active_filters(list: &str, format: FilterFormat) -> Vec<(String, ParsedFilter)> {
+    let filters: Vec<(String, ParsedFilter)> = list.lines()
+        .filter_map(|line| parse_filter(line, false, format).ok().map(|filter| (line.trim().to_string(), filter)))
+        .collect();
+
+    let badfilter_ids: HashSet<_> = filters.iter()
+        .filter_map(|(_, filter)| match filter {
+            ParsedFilter::Network(f) if f.is_badfilter() => Some(f.get_id_without_badfilter()),
+            _ => None,
+        })
+        .collect();
+
+    filters.into_iter()
+        .filter(|(_, filter)| match filter {
+            ParsedFilter::Network(f) => !f.is_badfilter() && !badfilter_ids.contains(&f.get_id()),
+            ParsedFilter::Cosmetic(_) => true,
+        })
+        .collect()
+}
+
+/// Resolves the targets of `!#include` directives into the contents of other filter lists.
+///
+/// This is implemented for any `Fn(&str) -> Option<String>`, and by `DirectoryIncludeLoader` for
+/// lists stored in a local directory.
+pub trait IncludeLoader {
+    /// Returns the contents of the filter list at `path`, or `None` if it cannot be loaded. Paths
+    /// of nested includes are resolved against the directory of the including list, so `path` is
+    /// always relative to the directory of the top-level list.
+    fn load(&self, path: &str) -> Option<String>;
+}
+
+impl<F: Fn(&str) -> Option<String>> IncludeLoader for F {
+    fn load(&self, path: &str) -> Option<String> {
+        self(path)
+    }
+}
+
+/// Loads included filter lists from files in a local directory. Paths that are absolute or that
+/// would escape the directory are rejected.
+pub struct DirectoryIncludeLoader {
+    root: PathBuf,
+}
+
+impl DirectoryIncludeLoader {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl IncludeLoader for DirectoryIncludeLoader {
+    fn load(&self, path: &str) -> Option<String> {
+        let path = Path::new(path);
+        if !path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return None;
+        }
+        std::fs::read_to_string(self.root.join(path)).ok()
+    }
+}
+
+/// Controls the evaluation of preprocessor directives in
+/// `FilterSet::add_filter_list_with_preprocessor`.
+#[derive(Default)]
+pub struct PreprocessorOptions<'a> {
+    /// Flags considered to be true in `!#if` conditions, like `env_chromium` or
+    /// `cap_html_filtering`. Any other flag is false.
+    pub env: HashSet<String>,
+    /// Used to resolve `!#include` directives. If `None`, they are ignored.
+    pub loader: Option<&'a dyn IncludeLoader>,
+}
+
+/// `!#include` directives are followed at most this many levels deep.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Evaluates the preprocessor directives of a filter list, as described in uBlock Origin's
+/// [static filter syntax][1], and returns the lines that should be parsed as rules.
+///
+/// `!#if` blocks can be nested, and are closed by `!#endif`. `!#include` directives are only
+/// followed in active blocks, and the included lists are preprocessed in turn. As in uBlock Origin,
+/// included paths are relative to the list that includes them. Each path is only included once,
+/// which also guards against cycles.
+///
+/// [1]: https://github.com/gorhill/uBlock/wiki/Static-filter-syntax#if-condition
+pub fn preprocess_filter_list(filter_list: &str, options: &PreprocessorOptions) -> Vec<String> {
+    let mut lines = vec![];
+    let mut included = HashSet::new();
+    preprocess_into(filter_list, None, options, 0, &mut included, &mut lines);
+    lines
+}
+
+/// Preprocesses `filter_list`, which was included from `list_path`, or is the top-level list if
+/// `list_path` is `None`.
+fn preprocess_into(filter_list: &str, list_path: Option<&str>, options: &PreprocessorOptions, depth: usize, included: &mut HashSet<String>, lines: &mut Vec<String>) {
+    // For each enclosing `!#if` block: whether the block was active when it was opened, and
+    // whether its condition holds in the current branch
+    let mut blocks: Vec<(bool, bool)> = vec![];
+    let mut active = true;
+
+    for line in filter_list.lines() {
+        let trimmed = line.trim();
+        if let Some(condition) = trimmed.strip_prefix("!#if ") {
+            blocks.push((active, evaluate_condition(condition, &options.env)));
+        } else if trimmed == "!#else" {
+            if let Some(block) = blocks.last_mut() {
+                block.1 = !block.1;
+            }
+        } else if trimmed == "!#endif" {
+            blocks.pop();
+        } else if let Some(path) = trimmed.strip_prefix("!#include ") {
+            let path = resolve_include_path(list_path, path.trim());
+            if active && depth < MAX_INCLUDE_DEPTH && !included.contains(&path) {
+                if let Some(list) = options.loader.and_then(|loader| loader.load(&path)) {
+                    included.insert(path.clone());
+                    preprocess_into(&list, Some(&path), options, depth + 1, included, lines);
+                }
+            }
+            continue;
+        } else {
+            if active {
+                lines.push(line.to_string());
+            }
+            continue;
+        }
+
+        active = blocks.last().map(|(outer, condition)| *outer && *condition).unwrap_or(true);
+    }
+}
+
+/// Resolves the `path` of an `!#include` directive found in the list at `list_path`, relative to
+/// the directory of that list. `.` and `..` segments are removed where possible, so that the same
+/// list always gets the same path.
+fn resolve_include_path(list_path: Option<&str>, path: &str) -> String {
+    let directory = match list_path.and_then(|list_path| list_path.rfind('/').map(|index| &list_path[..index])) {
+        Some(directory) if !path.starts_with('/') && !path.contains("://") => directory,
+        _ => return path.to_string(),
+    };
+
+    let mut segments: Vec<&str> = vec![];
+    for segment in directory.split('/').chain(path.split('/')) {
+        match segment {
+            "." => (),
+            ".." if matches!(segments.last(), Some(&last) if last != "..") => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+/// Evaluates a `!#if` condition consisting of flags combined with `!`, `&&`, `||` and
+/// parentheses. Malformed conditions are considered false.
+fn evaluate_condition(condition: &str, env: &HashSet<String>) -> bool {
+    #[derive(PartialEq)]
+    enum Token<'a> {
+        Flag(&'a str),
+        Not,
+        And,
+        Or,
+        Open,
+        Close,
+    }
+
+    fn tokenize(condition: &str) -> Option<Vec<Token<'_>>> {
+        let mut tokens = vec![];
+        let mut rest = condition.trim_start();
+        while !rest.is_empty() {
+            let (token, len) = if rest.starts_with("&&") {
+                (Token::And, 2)
+            } else if rest.starts_with("||") {
+                (Token::Or, 2)
+            } else if rest.starts_with('!') {
+                (Token::Not, 1)
+            } else if rest.starts_with('(') {
+                (Token::Open, 1)
+            } else if rest.starts_with(')') {
+                (Token::Close, 1)
+            } else {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                if len == 0 {
+                    return None;
+                }
+                (Token::Flag(&rest[..len]), len)
+            };
+            tokens.push(token);
+            rest = rest[len..].trim_start();
+        }
+        Some(tokens)
+    }
+
+    // Recursive descent, with `!` binding tightest and `&&` binding tighter than `||`
+    struct Parser<'a, 'b> {
+        tokens: &'b [Token<'a>],
+        position: usize,
+        env: &'b HashSet<String>,
+    }
+
+    impl<'a, 'b> Parser<'a, 'b> {
+        fn eat(&mut self, token: &Token) -> bool {
+            if self.tokens.get(self.position) == Some(token) {
+                self.position += 1;
+                true
+            } else {
+                false
+            }
+        }
+
+        fn or(&mut self) -> Option<bool> {
+            let mut value = self.and()?;
+            while self.eat(&Token::Or) {
+                value |= self.and()?;
+            }
+            Some(value)
+        }
+
+        fn and(&mut self) -> Option<bool> {
+            let mut value = self.unary()?;
+            while self.eat(&Token::And) {
+                value &= self.unary()?;
+            }
+            Some(value)
+        }
+
+        fn unary(&mut self) -> Option<bool> {
+            if self.eat(&Token::Not) {
+                return self.unary().map(|value| !value);
+            }
+            if self.eat(&Token::Open) {
+                let value = self.or()?;
+                return if self.eat(&Token::Close) { Some(value) } else { None };
+            }
+            match self.tokens.get(self.position) {
+                Some(Token::Flag(flag)) => {
+                    self.position += 1;
+                    Some(self.env.contains(*flag))
+                }
+                _ => None,
+            }
+        }
+    }
+
+    let tokens = match tokenize(condition) {
+        Some(tokens) => tokens,
+        None => return false,
+    };
+    let mut parser = Parser { tokens: &tokens, position: 0, env };
+    match parser.or() {
+        Some(value) if parser.position == tokens.len() => value,
+        _ => false,
+    }
+}
+
+/// Checks if a single trimmed line is a comment or list header rather than a rule.
+fn is_comment(filter: &str) -> bool {
+    filter.starts_with('!')
+        || (filter.starts_with('#') && filter[1..].starts_with(char::is_whitespace))
+        || filter.starts_with("[Adblock")
+}
+
 /// Given a single line, checks if this would likely be a cosmetic filter, a
 /// network filter or something that is not supported. This check is performed
 /// before calling a more specific parser to create an instance of
 /// `NetworkFilter` or `CosmeticFilter`.
 fn detect_filter_type(filter: &str) -> FilterType {
     // Ignore comments
-    if filter.len() == 1
-        || filter.starts_with('!')
-        || (filter.starts_with('#') && filter[1..].starts_with(char::is_whitespace))
-        || filter.starts_with("[Adblock")
-    {
+    if filter.len() == 1 || is_comment(filter) {
         return FilterType::NotSupported;
     }
 
@@ -466,4 +1501,292 @@ mod tests {
             FilterFormat::Standard,
         ).is_ok());
     }
+
+    #[test]
+    fn filter_list_metadata() {
+        let list = [
+            "[Adblock Plus 2.0]",
+            "! Title: Test List",
+            "! Version: 202010011200",
+            "! Expires: 4 days (update frequency)",
+            "! Homepage: https://example.com/",
+            "! License: https://example.com/license",
+            "! Redirect: https://example.com/list.txt",
+            "",
+            "||ads.example.com^",
+            "##.banner",
+            "",
+        ].join("\n");
+
+        let mut filter_set = FilterSet::new(true);
+        let metadata = filter_set.add_filter_list(&list, FilterFormat::Standard);
+        assert_eq!(metadata, FilterListMetadata {
+            title: Some("Test List".to_string()),
+            version: Some("202010011200".to_string()),
+            expires: Some(ExpiresInterval::Days(4)),
+            homepage: Some("https://example.com/".to_string()),
+            license: Some("https://example.com/license".to_string()),
+            redirect_url: Some("https://example.com/list.txt".to_string()),
+            abp_version: Some("2.0".to_string()),
+            checksum: None,
+        });
+        assert_eq!(filter_set.network_filters.len(), 1);
+        assert_eq!(filter_set.cosmetic_filters.len(), 1);
+
+        // Comments after the first rule are not part of the header
+        let metadata = parse_filter_list_metadata("! Title: A\n||a.com^\n! Version: 1\n! Expires: 12h");
+        assert_eq!(metadata.title, Some("A".to_string()));
+        assert_eq!(metadata.version, None);
+        assert_eq!(metadata.expires, None);
+        assert_eq!(metadata.checksum, None);
+        assert_eq!(metadata.abp_version, None);
+
+        // A published list, checksummed by Adblock Plus' own tooling
+        let published = std::fs::read_to_string("data/easylist.to/easylistgermany/easylistgermany.txt").unwrap();
+        let metadata = parse_filter_list_metadata(&published);
+        assert_eq!(metadata.title, Some("EasyList Germany".to_string()));
+        assert_eq!(metadata.checksum, Some(Checksum::Valid));
+        let tampered = format!("{}\n||tampered.com^", published);
+        assert_eq!(parse_filter_list_metadata(&tampered).checksum, Some(Checksum::Invalid));
+        // Carriage returns and empty lines don't affect the checksum
+        let crlf_list = published.replace("\n", "\r\n\r\n");
+        assert_eq!(parse_filter_list_metadata(&crlf_list).checksum, Some(Checksum::Valid));
+        // The checksum line can also be the last line, without a trailing newline
+        let checksum_line = published.lines().find(|line| line.starts_with("! Checksum")).unwrap();
+        let checksum_last = format!("{}\n{}", published.replacen(&format!("{}\n", checksum_line), "", 1), checksum_line);
+        assert_eq!(compute_checksum(&checksum_last), compute_checksum(&published));
+        let metadata = parse_filter_list_metadata(&checksum_last);
+        assert_eq!(metadata.title, Some("EasyList Germany".to_string()));
+        assert_eq!(metadata.checksum, Some(Checksum::Valid));
+        let tampered = checksum_last.replacen("||", "||tampered.", 1);
+        assert_eq!(parse_filter_list_metadata(&tampered).checksum, Some(Checksum::Invalid));
+
+        assert_eq!(ExpiresInterval::parse("12 hours"), Some(ExpiresInterval::Hours(12)));
+        assert_eq!(ExpiresInterval::parse("1 day"), Some(ExpiresInterval::Days(1)));
+        assert_eq!(ExpiresInterval::parse("5d"), Some(ExpiresInterval::Days(5)));
+        assert_eq!(ExpiresInterval::parse("soon"), None);
+        assert_eq!(ExpiresInterval::parse("3 weeks"), None);
+    }
+
+    #[test]
+    fn filter_list_diagnostics() {
+        use crate::filters::network::NetworkFilterError;
+
+        let list = [
+            "[Adblock Plus 2.0]",
+            "! Title: Diagnostics",
+            "||good.com^",
+            "",
+            "||bad.com^$unknown-option",
+            "example.com##",
+            "example.com#$#body { color: red; }",
+            "||redundant.com^$script,third-party,script,3p",
+            "||never.com^$first-party,third-party",
+            "||nothing.com^$badfilter",
+            "||good.com^$badfilter",
+        ].join("\n");
+
+        let mut filter_set = FilterSet::new(true);
+        let (metadata, diagnostics) = filter_set.add_filter_list_with_diagnostics(&list, FilterFormat::Standard);
+        assert_eq!(metadata.title, Some("Diagnostics".to_string()));
+        // Everything that parsed is still added
+        assert_eq!(filter_set.network_filters.len(), 5);
+
+        let summary: Vec<_> = diagnostics.iter().map(|d| (d.line_number, d.line.as_str())).collect();
+        assert_eq!(summary, vec![
+            (5, "||bad.com^$unknown-option"),
+            (6, "example.com##"),
+            (7, "example.com#$#body { color: red; }"),
+            (8, "||redundant.com^$script,third-party,script,3p"),
+            (8, "||redundant.com^$script,third-party,script,3p"),
+            (9, "||never.com^$first-party,third-party"),
+            (10, "||nothing.com^$badfilter"),
+        ]);
+
+        assert!(matches!(diagnostics[0].kind, FilterDiagnosticKind::Error(FilterParseError::Network(NetworkFilterError::UnrecognisedOption))));
+        assert!(matches!(diagnostics[1].kind, FilterDiagnosticKind::Error(FilterParseError::Cosmetic(_))));
+        assert!(matches!(diagnostics[2].kind, FilterDiagnosticKind::Error(FilterParseError::Unsupported)));
+        assert!(matches!(&diagnostics[3].kind, FilterDiagnosticKind::Warning(FilterParseWarning::RedundantOption(o)) if o == "script"));
+        assert!(matches!(&diagnostics[4].kind, FilterDiagnosticKind::Warning(FilterParseWarning::RedundantOption(o)) if o == "third-party"));
+        assert!(matches!(diagnostics[5].kind, FilterDiagnosticKind::Warning(FilterParseWarning::NeverMatches)));
+        assert!(matches!(diagnostics[6].kind, FilterDiagnosticKind::Warning(FilterParseWarning::UnmatchedBadFilter)));
+
+        // `$document` rules match documents, which are not part of the other resource types
+        let mut filter_set = FilterSet::new(false);
+        let (_, diagnostics) = filter_set.add_filter_list_with_diagnostics("@@||site.com^$document\n||x.com^$document", FilterFormat::Standard);
+        assert!(diagnostics.is_empty());
+
+        // Hosts-style comments and `localhost` entries are not reported
+        let mut filter_set = FilterSet::new(false);
+        let (_, diagnostics) = filter_set.add_filter_list_with_diagnostics("# comment\n127.0.0.1 localhost\n0.0.0.0 ads.com", FilterFormat::Hosts);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn filter_list_diff() {
+        let old_list = "! Version: 1\n||a.com^\n||b.com^\n##.ad\n";
+        let new_list = "! Version: 2\n||c.com^\n||a.com^\n##.banner\n##.popup\n##.ad\n||a.com^\n";
+
+        let diff = FilterListDiff::between(old_list, new_list);
+        assert_eq!(diff, FilterListDiff {
+            added: vec!["||c.com^".to_string(), "##.banner".to_string(), "##.popup".to_string()],
+            removed: vec!["||b.com^".to_string()],
+            added_after: vec![None, Some("||a.com^".to_string()), Some("##.banner".to_string())],
+        });
+
+        assert_eq!(diff.apply(old_list), "! Version: 1\n||c.com^\n||a.com^\n##.banner\n##.popup\n##.ad");
+
+        // Without positions, as in Adblock Plus diffs, added rules are appended
+        let diff = FilterListDiff { added_after: vec![], ..diff };
+        assert_eq!(diff.apply(old_list), "! Version: 1\n||a.com^\n##.ad\n||c.com^\n##.banner\n##.popup");
+    }
+
+    #[test]
+    fn rcs_diff() {
+        let list = "! Version: 1\n||a.com^\n||b.com^\n||c.com^\n";
+        // Replace the version line, delete `||b.com^` and add two rules at the end
+        let diff = "d1 1\na1 1\n! Version: 2\nd3 1\na4 2\n||d.com^\n||e.com^\n";
+        assert_eq!(apply_rcs_diff(list, diff), Ok("! Version: 2\n||a.com^\n||c.com^\n||d.com^\n||e.com^\n".to_string()));
+
+        assert_eq!(apply_rcs_diff(list, "a0 1\n! Title: A"), Ok("! Title: A\n! Version: 1\n||a.com^\n||b.com^\n||c.com^\n".to_string()));
+        assert_eq!(apply_rcs_diff(list, "x1 1"), Err(DiffUpdateError::InvalidCommand));
+        assert_eq!(apply_rcs_diff(list, "d1"), Err(DiffUpdateError::InvalidCommand));
+        assert_eq!(apply_rcs_diff(list, "d4 2"), Err(DiffUpdateError::LineOutOfRange));
+        assert_eq!(apply_rcs_diff(list, "d3 1\nd2 1"), Err(DiffUpdateError::LineOutOfRange));
+        assert_eq!(apply_rcs_diff(list, "a2 2\n||d.com^"), Err(DiffUpdateError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn active_filters() {
+        let list = "||a.com^\n||b.com^\n||b.com^$badfilter\n##.ad\n! comment";
+        let rules: Vec<_> = parse_active_filters(list, FilterFormat::Standard).into_iter().map(|(rule, _)| rule).collect();
+        assert_eq!(rules, vec!["||a.com^", "##.ad"]);
+    }
+
+    #[test]
+    fn preprocessor_conditions() {
+        let env: HashSet<String> = ["env_chromium", "cap_html_filtering"].iter().map(|s| s.to_string()).collect();
+        assert!(evaluate_condition("env_chromium", &env));
+        assert!(!evaluate_condition("env_firefox", &env));
+        assert!(evaluate_condition("!env_firefox", &env));
+        assert!(evaluate_condition("env_chromium && !env_mobile", &env));
+        assert!(!evaluate_condition("env_chromium && env_mobile", &env));
+        assert!(evaluate_condition("env_firefox || cap_html_filtering", &env));
+        assert!(evaluate_condition("!(env_firefox || env_safari) && env_chromium", &env));
+        assert!(!evaluate_condition("!!env_firefox", &env));
+        // malformed conditions are false
+        assert!(!evaluate_condition("(env_chromium", &env));
+        assert!(!evaluate_condition("env_chromium &&", &env));
+        assert!(!evaluate_condition("env_chromium env_firefox", &env));
+        assert!(!evaluate_condition("", &env));
+    }
+
+    #[test]
+    fn preprocessor_blocks() {
+        let list = [
+            "||always.com^",
+            "!#if env_chromium",
+            "||chromium.com^",
+            "!#if env_mobile",
+            "||chromium-mobile.com^",
+            "!#else",
+            "||chromium-desktop.com^",
+            "!#endif",
+            "!#else",
+            "||not-chromium.com^",
+            "!#endif",
+            "!#if !env_chromium",
+            "||no-chromium.com^",
+            "!#if env_mobile",
+            "||no-chromium-mobile.com^",
+            "!#else",
+            "||no-chromium-desktop.com^",
+            "!#endif",
+            "!#endif",
+            "||always-too.com^",
+        ].join("\n");
+
+        let options = PreprocessorOptions {
+            env: vec!["env_chromium".to_string()].into_iter().collect(),
+            loader: None,
+        };
+        assert_eq!(
+            preprocess_filter_list(&list, &options),
+            vec!["||always.com^", "||chromium.com^", "||chromium-desktop.com^", "||always-too.com^"],
+        );
+
+        assert_eq!(
+            preprocess_filter_list(&list, &PreprocessorOptions::default()),
+            vec!["||always.com^", "||not-chromium.com^", "||no-chromium.com^", "||no-chromium-desktop.com^", "||always-too.com^"],
+        );
+
+        // `add_filter_list` evaluates blocks without any flag
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter_list(&list, FilterFormat::Standard);
+        assert_eq!(filter_set.network_filters.len(), 5);
+        assert!(filter_set.network_filters.iter().all(|filter| filter.raw_line.as_deref() != Some("||chromium.com^")));
+    }
+
+    #[test]
+    fn preprocessor_includes() {
+        let loader = |path: &str| match path {
+            "sub.txt" => Some("||sub.com^\n!#include nested.txt".to_string()),
+            "nested.txt" => Some("||nested.com^".to_string()),
+            "cycle.txt" => Some("||cycle.com^\n!#include cycle.txt\n!#include cycle.txt".to_string()),
+            "deep.txt" => Some("||deep.com^\n!#include deeper.txt".to_string()),
+            "deeper.txt" => Some("||deeper.com^\n!#include nested.txt\n!#include nested.txt".to_string()),
+            _ => None,
+        };
+        let options = PreprocessorOptions {
+            env: HashSet::new(),
+            loader: Some(&loader),
+        };
+
+        let list = "||a.com^\n!#include sub.txt\n!#include missing.txt\n!#if env_firefox\n!#include nested.txt\n!#endif";
+        assert_eq!(preprocess_filter_list(list, &options), vec!["||a.com^", "||sub.com^", "||nested.com^"]);
+
+        // includes are ignored without a loader
+        assert_eq!(preprocess_filter_list(list, &PreprocessorOptions::default()), vec!["||a.com^"]);
+
+        // each list is only included once, which also stops cycles
+        assert_eq!(preprocess_filter_list("!#include cycle.txt\n!#include cycle.txt", &options), vec!["||cycle.com^"]);
+        assert_eq!(
+            preprocess_filter_list("!#include deep.txt\n!#include nested.txt", &options),
+            vec!["||deep.com^", "||deeper.com^", "||nested.com^"],
+        );
+
+        let mut filter_set = FilterSet::new(true);
+        filter_set.add_filter_list_with_preprocessor(list, FilterFormat::Standard, &options);
+        assert_eq!(filter_set.network_filters.len(), 3);
+    }
+
+    #[test]
+    fn directory_include_loader() {
+        let loader = DirectoryIncludeLoader::new("data/test/includes");
+        assert_eq!(loader.load("included.txt").as_deref(), Some("||included.com^\n!#include sub/nested.txt\n"));
+        assert!(loader.load("./included.txt").is_some());
+        assert!(loader.load("sub/nested.txt").is_some());
+        assert!(loader.load("missing.txt").is_none());
+        assert!(loader.load("../includes/included.txt").is_none());
+        assert!(loader.load("/etc/hostname").is_none());
+
+        let options = PreprocessorOptions {
+            env: HashSet::new(),
+            loader: Some(&loader),
+        };
+        // `sub/nested.txt` includes `deeper.txt` from its own directory
+        assert_eq!(preprocess_filter_list("!#include included.txt", &options), vec!["||included.com^", "||nested.com^", "||deeper.com^"]);
+    }
+
+    #[test]
+    fn include_paths() {
+        assert_eq!(resolve_include_path(None, "a.txt"), "a.txt");
+        assert_eq!(resolve_include_path(Some("list.txt"), "a.txt"), "a.txt");
+        assert_eq!(resolve_include_path(Some("sub/list.txt"), "a.txt"), "sub/a.txt");
+        assert_eq!(resolve_include_path(Some("sub/list.txt"), "./other/a.txt"), "sub/other/a.txt");
+        assert_eq!(resolve_include_path(Some("sub/deeper/list.txt"), "../a.txt"), "sub/a.txt");
+        assert_eq!(resolve_include_path(Some("sub/list.txt"), "../../a.txt"), "../a.txt");
+        assert_eq!(resolve_include_path(Some("https://example.com/lists/list.txt"), "a.txt"), "https://example.com/lists/a.txt");
+        assert_eq!(resolve_include_path(Some("sub/list.txt"), "https://example.com/a.txt"), "https://example.com/a.txt");
+    }
 }
@@ -1,4 +1,5 @@
 use crate::filters::network::{NetworkFilter, NetworkFilterMask, FilterPart};
+use crate::utils::{fast_hash, Hash};
 use itertools::*;
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
@@ -26,6 +27,56 @@ pub fn optimize(filters: Vec<NetworkFilter>) -> Vec<NetworkFilter> {
     optimized
 }
 
+/// Removes one of the original filters from a filter produced by `optimize`. Returns `None` if the
+/// remaining filters can't be recovered, which is the case if any of the fused filters had an
+/// empty pattern.
+pub fn unfuse(fused: &NetworkFilter, removed: &NetworkFilter) -> Option<NetworkFilter> {
+    let mut patterns = match &fused.filter {
+        FilterPart::Empty => return None,
+        FilterPart::Simple(s) => vec![s.clone()],
+        FilterPart::AnyOf(s) => s.clone(),
+    };
+    let removed_patterns = match &removed.filter {
+        FilterPart::Empty => return None,
+        FilterPart::Simple(s) => std::slice::from_ref(s),
+        FilterPart::AnyOf(s) => s.as_slice(),
+    };
+    for pattern in removed_patterns {
+        let index = patterns.iter().position(|p| p == pattern)?;
+        patterns.remove(index);
+    }
+
+    let mut ids: Vec<Hash> = fused.fused_ids.clone().unwrap_or_default();
+    let index = ids.iter().position(|id| *id == removed.id)?;
+    ids.remove(index);
+
+    let mut filter = fused.clone();
+    filter.filter = match patterns.len() {
+        0 => return None,
+        1 => FilterPart::Simple(patterns.remove(0)),
+        _ => FilterPart::AnyOf(patterns),
+    };
+    if filter.id == removed.id {
+        filter.id = *ids.first()?;
+    }
+    filter.fused_ids = Some(ids);
+    // Raw lines of the original filters are joined together, and their ids are hashes of them
+    if let Some(raw_line) = &fused.raw_line {
+        let mut raw_lines: Vec<&str> = raw_line.split(" <+> ").collect();
+        let is_removed = |r: &&str| match &removed.raw_line {
+            Some(line) => r == line,
+            None => fast_hash(r) == removed.id,
+        };
+        if let Some(index) = raw_lines.iter().position(is_removed) {
+            raw_lines.remove(index);
+        }
+        filter.raw_line = Some(raw_lines.join(" <+> "));
+    }
+    filter.reset_regex();
+
+    Some(filter)
+}
+
 fn apply_optimisation<T: Optimization>(
     optimization: &T,
     filters: Vec<NetworkFilter>,
@@ -112,6 +163,14 @@ impl Optimization for SimplePatternGroup {
             )
         }
 
+        // Keep track of the original filters, so that they can be removed individually
+        filter.fused_ids = Some(
+            filters
+                .iter()
+                .flat_map(|f| f.fused_ids.clone().unwrap_or_else(|| vec![f.id]))
+                .collect(),
+        );
+
         filter
     }
 
@@ -124,7 +183,13 @@ impl Optimization for SimplePatternGroup {
             && !filter.is_hostname_anchor()
             && !filter.is_redirect()
             && !filter.is_csp()
+            && !filter.is_removeparam()
             && !filter.has_bug()
+            && filter.opt_to_domains.is_none()
+            && filter.opt_not_to_domains.is_none()
+            && filter.opt_methods.is_none()
+            && filter.opt_not_methods.is_none()
+            && !filter.is_header()
     }
 }
 
@@ -185,7 +250,13 @@ impl Optimization for UnionDomainGroup {
 
     fn select(&self, filter: &NetworkFilter) -> bool {
         !filter.is_csp()
+            && !filter.is_removeparam()
             && !filter.has_bug()
+            && filter.opt_to_domains.is_none()
+            && filter.opt_not_to_domains.is_none()
+            && filter.opt_methods.is_none()
+            && filter.opt_not_methods.is_none()
+            && !filter.is_header()
             && (filter.opt_domains.is_some() || filter.opt_not_domains.is_some())
     }
 }
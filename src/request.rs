@@ -3,6 +3,9 @@
 use crate::url_parser;
 use crate::utils;
 
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum RequestType {
     Beacon,
@@ -24,6 +27,36 @@ pub enum RequestType {
     Xmlhttprequest,
 }
 
+/// HTTP method of a request, as matched by the `$method` network filter option.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum RequestMethod {
+    Connect,
+    Delete,
+    Get,
+    Head,
+    Options,
+    Patch,
+    Post,
+    Put,
+}
+
+impl RequestMethod {
+    /// Parses a method name, case-insensitively. Returns `None` for unknown methods.
+    pub fn from_name(method: &str) -> Option<Self> {
+        match method.to_ascii_lowercase().as_str() {
+            "connect" => Some(RequestMethod::Connect),
+            "delete" => Some(RequestMethod::Delete),
+            "get" => Some(RequestMethod::Get),
+            "head" => Some(RequestMethod::Head),
+            "options" => Some(RequestMethod::Options),
+            "patch" => Some(RequestMethod::Patch),
+            "post" => Some(RequestMethod::Post),
+            "put" => Some(RequestMethod::Put),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum RequestError {
     HostnameParseError,
@@ -79,13 +112,63 @@ pub struct Request {
     pub is_third_party: Option<bool>,
     pub url: String,
     pub hostname: String,
+    /// Hostname of the document that made this request, or empty if unknown.
+    pub source_hostname: String,
     pub source_hostname_hashes: Option<Vec<utils::Hash>>,
+    // Only needed by filters with `$to=` or `$denyallow=`, so computed on first use
+    hostname_hashes: OnceCell<Option<Vec<utils::Hash>>>,
 
     // mutable fields, set later
     pub bug: Option<u32>,
+    /// `None` if the method was not provided or is not recognized. Filters restricted to specific
+    /// methods will not match such requests, but filters excluding methods will.
+    pub method: Option<RequestMethod>,
     hostname_end: usize
 }
 
+/// Hashes of the hostname and each of its parent domains, for matching against domain options.
+fn get_hostname_hashes(hostname: &str) -> Option<Vec<utils::Hash>> {
+    if hostname.is_empty() {
+        return None;
+    }
+    let mut hashes = Vec::with_capacity(4);
+    hashes.push(utils::fast_hash(&hostname));
+    for (i, c) in hostname.char_indices() {
+        if c == '.' && i + 1 < hostname.len() {
+            hashes.push(utils::fast_hash(&hostname[i + 1..]));
+        }
+    }
+    Some(hashes)
+}
+
+/// The document that makes requests, parsed once so that it can be shared by many requests with
+/// `Request::from_url_with_source`.
+#[derive(Clone, Debug, Default)]
+pub struct RequestSource {
+    hostname: String,
+    domain: String,
+    hostname_hashes: Option<Vec<utils::Hash>>,
+}
+
+impl RequestSource {
+    /// Parses the URL of the source document. As with `Request::from_urls`, an unparseable URL is
+    /// treated as an unknown source.
+    pub fn parse(source_url: &str) -> Self {
+        match url_parser::parse_url(source_url) {
+            Some(parsed_source) => Self {
+                hostname: parsed_source.hostname().to_owned(),
+                domain: parsed_source.domain().to_owned(),
+                hostname_hashes: get_hostname_hashes(parsed_source.hostname()),
+            },
+            None => Self::default(),
+        }
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+}
+
 impl<'a> Request {
     pub fn get_tokens(&self, mut token_buffer: &mut Vec<utils::Hash>) {
         token_buffer.clear();
@@ -94,6 +177,12 @@ impl<'a> Request {
         token_buffer.push(0);
     }
 
+    /// Hashes of the request's hostname and each of its parent domains, or `None` if the hostname
+    /// is empty.
+    pub fn hostname_hashes(&self) -> Option<&Vec<utils::Hash>> {
+        self.hostname_hashes.get_or_init(|| get_hostname_hashes(&self.hostname)).as_ref()
+    }
+
     pub fn url_after_hostname(&self) -> &str {
         &self.url[self.hostname_end..]
     }
@@ -121,6 +210,7 @@ impl<'a> Request {
             schema,
             hostname,
             source_hostname,
+            get_hostname_hashes(source_hostname),
             third_party,
             hostname_end
         )
@@ -133,6 +223,7 @@ impl<'a> Request {
         schema: &str,
         hostname: &str,
         source_hostname: &str,
+        source_hostname_hashes: Option<Vec<utils::Hash>>,
         third_party: Option<bool>,
         hostname_end: usize
     ) -> Request {
@@ -162,32 +253,20 @@ impl<'a> Request {
             }
         }
 
-        let source_hostname_hashes = if !source_hostname.is_empty() {
-            let mut hashes = Vec::with_capacity(4);
-            hashes.push(utils::fast_hash(&source_hostname));
-            for (i, c) in
-                source_hostname.char_indices()
-            {
-                if c == '.' && i + 1 < source_hostname.len() {
-                    hashes.push(utils::fast_hash(&source_hostname[i + 1..]));
-                }
-            }
-            Some(hashes)
-        } else {
-            None
-        };
-
         Request {
             request_type,
             url: url.to_owned(),
             hostname: hostname.to_owned(),
+            source_hostname: source_hostname.to_owned(),
             source_hostname_hashes,
+            hostname_hashes: OnceCell::new(),
             is_first_party: first_party,
             is_third_party: third_party,
             is_http,
             is_https,
             is_supported,
             bug: None,
+            method: None,
             hostname_end
         }
     }
@@ -213,6 +292,7 @@ impl<'a> Request {
                     parsed_url.schema(),
                     parsed_url.hostname(),
                     parsed_source.hostname(),
+                    get_hostname_hashes(parsed_source.hostname()),
                     third_party,
                     parsed_url.hostname_pos.1
                 ))
@@ -224,6 +304,7 @@ impl<'a> Request {
                     parsed_url.hostname(),
                     "",
                     None,
+                    None,
                     parsed_url.hostname_pos.1
                 ))
             }
@@ -265,11 +346,45 @@ impl<'a> Request {
             &schema,
             &hostname,
             &source_hostname,
+            get_hostname_hashes(source_hostname),
             third_party,
             splitter + 2 + hostname.len()
         )
     }
 
+    /// Same as `from_urls`, with a source document that was already parsed.
+    pub fn from_url_with_source(
+        url: &str,
+        source: &RequestSource,
+        request_type: &str,
+    ) -> Result<Request, RequestError> {
+        let parsed_url = url_parser::parse_url(url).ok_or(RequestError::HostnameParseError)?;
+
+        let third_party = if source.domain.is_empty() {
+            None
+        } else {
+            Some(source.domain != parsed_url.domain())
+        };
+
+        Ok(Request::from_detailed_parameters(
+            request_type,
+            &parsed_url.url,
+            parsed_url.schema(),
+            parsed_url.hostname(),
+            &source.hostname,
+            source.hostname_hashes.clone(),
+            third_party,
+            parsed_url.hostname_pos.1
+        ))
+    }
+
+    /// Sets the HTTP method of this request, e.g. `"POST"`. Can be chained after any of the
+    /// constructors.
+    pub fn with_method(mut self, method: &str) -> Self {
+        self.method = RequestMethod::from_name(method);
+        self
+    }
+
     pub fn from_url(url: &str) -> Result<Request, RequestError> {
         // Used in testing - assume empty source_url and default request type
         Self::from_urls(url, "", "")
@@ -435,7 +550,7 @@ mod tests {
             parsed.source_hostname_hashes,
             Some(vec![utils::fast_hash("example.com"), utils::fast_hash("com")]),
         );
-        // assert_eq!(parsed.source_hostname, "example.com");
+        assert_eq!(parsed.source_hostname, "example.com");
 
         let bad_url = Request::from_urls(
             "subdomain.example.com/ad",
@@ -445,6 +560,30 @@ mod tests {
         assert_eq!(bad_url.err(), Some(RequestError::HostnameParseError));
     }
 
+    #[test]
+    fn from_url_with_source() {
+        let source = RequestSource::parse("https://sub.example.com/page");
+        assert_eq!(source.hostname(), "sub.example.com");
+        for (url, request_type) in &[("https://cdn.example.net/ad.js", "script"), ("https://example.com/img.png", "image")] {
+            let with_source = Request::from_url_with_source(url, &source, request_type).unwrap();
+            let from_urls = Request::from_urls(url, "https://sub.example.com/page", request_type).unwrap();
+            assert_eq!(with_source.url, from_urls.url);
+            assert_eq!(with_source.hostname, from_urls.hostname);
+            assert_eq!(with_source.source_hostname, from_urls.source_hostname);
+            assert_eq!(with_source.source_hostname_hashes, from_urls.source_hostname_hashes);
+            assert_eq!(with_source.hostname_hashes(), from_urls.hostname_hashes());
+            assert_eq!(with_source.is_third_party, from_urls.is_third_party);
+            assert_eq!(with_source.request_type, from_urls.request_type);
+        }
+
+        let unknown_source = RequestSource::parse("");
+        let request = Request::from_url_with_source("https://cdn.example.net/ad.js", &unknown_source, "script").unwrap();
+        assert_eq!(request.is_third_party, None);
+        assert_eq!(request.source_hostname_hashes, None);
+
+        assert_eq!(Request::from_url_with_source("example.com/ad", &source, "script").err(), Some(RequestError::HostnameParseError));
+    }
+
     #[test]
     fn handles_explicit_third_party_param() {
         {
@@ -474,6 +613,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parses_methods() {
+        let parsed = Request::from_url("https://example.com/ad").unwrap();
+        assert_eq!(parsed.method, None);
+        let parsed = Request::from_url("https://example.com/ad").unwrap().with_method("POST");
+        assert_eq!(parsed.method, Some(RequestMethod::Post));
+        let parsed = Request::from_url("https://example.com/ad").unwrap().with_method("get");
+        assert_eq!(parsed.method, Some(RequestMethod::Get));
+        let parsed = Request::from_url("https://example.com/ad").unwrap().with_method("brew");
+        assert_eq!(parsed.method, None);
+        let parsed = Request::from_urls("https://example.com/ad", "https://example.com", "xhr").unwrap().with_method("PUT");
+        assert_eq!(parsed.method, Some(RequestMethod::Put));
+        let parsed = Request::from_urls_with_hostname("https://example.com/ad", "example.com", "example.com", "xhr", None).with_method("delete");
+        assert_eq!(parsed.method, Some(RequestMethod::Delete));
+    }
+
     #[test]
     fn fuzzing_errors() {
         {
@@ -0,0 +1,210 @@
+//! Opt-in statistics about how rules are matched, enabled by the `metrics` feature. They can be
+//! used to find rules that never match and buckets that are visited too often in production
+//! lists.
+
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use crate::blocker::FilterListKind;
+use crate::filters::network::NetworkFilter;
+use crate::utils::Hash;
+
+/// Number of bins in visit histograms.
+pub const HISTOGRAM_BINS: usize = 16;
+
+/// Counts occurrences of each key. Counting only needs a shared reference, so it can be done
+/// while matching.
+pub(crate) struct Counters<K> {
+    counts: RwLock<HashMap<K, AtomicU64>>,
+}
+
+impl<K> Default for Counters<K> {
+    fn default() -> Self {
+        Self {
+            counts: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + std::hash::Hash + Clone> Counters<K> {
+    pub fn increment<Q>(&self, key: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + std::hash::Hash + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(count) = self.counts.read().unwrap().get(key) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.counts.write().unwrap()
+            .entry(key.to_owned())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HashMap<K, u64> {
+        self.counts.read().unwrap()
+            .iter()
+            .map(|(key, count)| (key.clone(), count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub fn reset(&self) {
+        self.counts.write().unwrap().clear();
+    }
+}
+
+/// Histogram with power-of-two bins: bin 0 counts the value 0, and bin `i` counts values from
+/// `2^(i-1)` to `2^i - 1`. The last bin also counts every larger value.
+#[derive(Default)]
+pub(crate) struct Histogram {
+    bins: [AtomicU64; HISTOGRAM_BINS],
+}
+
+impl Histogram {
+    pub fn record(&self, value: usize) {
+        let bin = (usize::BITS - value.leading_zeros()) as usize;
+        self.bins[bin.min(HISTOGRAM_BINS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Vec<u64> {
+        self.bins.iter().map(|bin| bin.load(Ordering::Relaxed)).collect()
+    }
+
+    pub fn reset(&self) {
+        self.bins.iter().for_each(|bin| bin.store(0, Ordering::Relaxed));
+    }
+}
+
+/// Statistics collected by a single `NetworkFilterList`.
+#[derive(Default)]
+pub(crate) struct ListStatistics {
+    checks: AtomicU64,
+    filter_hits: Counters<Hash>,
+    bucket_visits: Counters<Hash>,
+    buckets_visited: Histogram,
+    filters_checked: Histogram,
+}
+
+impl ListStatistics {
+    pub fn record_bucket_visit(&self, token: Hash) {
+        self.bucket_visits.increment(&token);
+    }
+
+    /// Records a single check of the list, given the number of buckets and filters visited and
+    /// every filter that matched.
+    pub fn record_check<'a, I: IntoIterator<Item = &'a NetworkFilter>>(&self, buckets: usize, filters: usize, matches: I) {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        self.buckets_visited.record(buckets);
+        self.filters_checked.record(filters);
+        self.record_hits(matches);
+    }
+
+    /// Records hits for filters that matched during an already recorded check.
+    pub fn record_hits<'a, I: IntoIterator<Item = &'a NetworkFilter>>(&self, matches: I) {
+        for filter in matches {
+            // A hit of a fused filter counts for each of the original filters
+            match &filter.fused_ids {
+                Some(ids) => ids.iter().for_each(|id| self.filter_hits.increment(id)),
+                None => self.filter_hits.increment(&filter.id),
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> NetworkListStatistics {
+        NetworkListStatistics {
+            checks: self.checks.load(Ordering::Relaxed),
+            filter_hits: self.filter_hits.snapshot(),
+            bucket_visits: self.bucket_visits.snapshot(),
+            buckets_visited: self.buckets_visited.snapshot(),
+            filters_checked: self.filters_checked.snapshot(),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.checks.store(0, Ordering::Relaxed);
+        self.filter_hits.reset();
+        self.bucket_visits.reset();
+        self.buckets_visited.reset();
+        self.filters_checked.reset();
+    }
+}
+
+/// Snapshot of the statistics of a single list of network filters.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NetworkListStatistics {
+    /// Number of times the list was checked against a request.
+    pub checks: u64,
+    /// Number of times each filter matched a request, by filter id. When filters were fused
+    /// together by the optimizer, a match of the fused filter is counted for each of them, since
+    /// the one that matched is not known; disable optimizations to get exact counts.
+    pub filter_hits: HashMap<Hash, u64>,
+    /// Number of times each bucket of filters was visited, by token.
+    pub bucket_visits: HashMap<Hash, u64>,
+    /// Histogram of the number of buckets visited per check, with `HISTOGRAM_BINS` power-of-two
+    /// bins: bin 0 counts checks that visited no bucket, and bin `i` counts checks that visited
+    /// from `2^(i-1)` to `2^i - 1` buckets.
+    pub buckets_visited: Vec<u64>,
+    /// Histogram of the number of filters tried per check, with the same bins as
+    /// `buckets_visited`.
+    pub filters_checked: Vec<u64>,
+}
+
+/// Snapshot of all statistics collected by an `Engine`, as returned by `Engine::statistics`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct EngineStatistics {
+    pub network: HashMap<FilterListKind, NetworkListStatistics>,
+    /// Number of times each hostname-specific cosmetic rule or class/id-based generic rule was
+    /// returned for a page, keyed like the rule, e.g. `##.ad` or `example.com##+js(arguments)`.
+    /// Rules for several hostnames or entities are counted separately for each of them.
+    pub cosmetic_hits: HashMap<String, u64>,
+}
+
+impl EngineStatistics {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Statistics are always serializable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let histogram = Histogram::default();
+        for value in &[0, 1, 2, 3, 4, 7, 8, 100_000] {
+            histogram.record(*value);
+        }
+        let mut expected = vec![0; HISTOGRAM_BINS];
+        expected[0] = 1;
+        expected[1] = 1;
+        expected[2] = 2;
+        expected[3] = 2;
+        expected[4] = 1;
+        expected[HISTOGRAM_BINS - 1] = 1;
+        assert_eq!(histogram.snapshot(), expected);
+
+        histogram.reset();
+        assert_eq!(histogram.snapshot(), vec![0; HISTOGRAM_BINS]);
+    }
+
+    #[test]
+    fn counters() {
+        let counters: Counters<String> = Counters::default();
+        counters.increment("a");
+        counters.increment("b");
+        counters.increment("a");
+
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot["a"], 2);
+        assert_eq!(snapshot["b"], 1);
+
+        counters.reset();
+        assert!(counters.snapshot().is_empty());
+    }
+}